@@ -0,0 +1,134 @@
+//! Creates the disk images that the integration tests attach to QEMU with `-drive`, and
+//! packs `initrd/` into the ustar archive that `src/initrd.rs` links into the kernel.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const SECTOR_SIZE: usize = 512;
+/// The number of sectors of every test disk.
+const TEST_DISK_SECTORS: u64 = 2048;
+
+const TEST_DISKS: [&str; 3] = ["ata-test.img", "virtio-legacy.img", "virtio-modern.img"];
+
+/// The blank disks the tables are saved to, by `cargo xtest` and `cargo xrun`.
+const STORE_DISKS: [&str; 2] = ["persist-test.img", "hive.img"];
+const STORE_DISK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The partitioned disk with FAT volumes that `scripts/fat-images.sh` creates.
+const FAT_DISK: &str = "fat-test.img";
+
+/// Every sector starts with its number as a little-endian u64, the rest of it repeats the
+/// low byte of that number.
+fn test_disk_image() -> Vec<u8> {
+    let mut image = Vec::with_capacity(TEST_DISK_SECTORS as usize * SECTOR_SIZE);
+    for sector in 0..TEST_DISK_SECTORS {
+        let mut data = [sector as u8; SECTOR_SIZE];
+        data[..8].copy_from_slice(&sector.to_le_bytes());
+        image.extend_from_slice(&data);
+    }
+    image
+}
+
+const TAR_BLOCK: usize = 512;
+/// The length of the name field of a ustar header; longer paths are not supported.
+const TAR_NAME_LENGTH: usize = 100;
+
+/// Writes `value` as a NUL-terminated octal number filling `field`.
+fn put_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    assert!(digits.len() < field.len(), "{} does not fit a tar header field", value);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+fn tar_header(path: &str, size: u64, directory: bool) -> [u8; TAR_BLOCK] {
+    assert!(path.len() <= TAR_NAME_LENGTH, "initrd path too long: {}", path);
+    let mut header = [0; TAR_BLOCK];
+    header[..path.len()].copy_from_slice(path.as_bytes());
+    put_octal(&mut header[100..108], if directory { 0o755 } else { 0o644 });
+    put_octal(&mut header[108..116], 0);
+    put_octal(&mut header[116..124], 0);
+    put_octal(&mut header[124..136], size);
+    put_octal(&mut header[136..148], 0);
+    header[156] = if directory { b'5' } else { b'0' };
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // the checksum is computed with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = header.iter().map(|&byte| u64::from(byte)).sum();
+    put_octal(&mut header[148..155], checksum);
+    header
+}
+
+/// Appends the contents of `directory` to the archive, in name order so the archive does
+/// not depend on the order of the host file system.
+fn tar_directory(archive: &mut Vec<u8>, root: &Path, directory: &Path) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        let name = path.strip_prefix(root).unwrap().to_str().expect("initrd path is not UTF-8");
+        let name = name.replace('\\', "/");
+        if path.is_dir() {
+            archive.extend_from_slice(&tar_header(&format!("{}/", name), 0, true));
+            tar_directory(archive, root, &path);
+        } else {
+            let data = fs::read(&path).unwrap();
+            archive.extend_from_slice(&tar_header(&name, data.len() as u64, false));
+            archive.extend_from_slice(&data);
+            let padding = (TAR_BLOCK - data.len() % TAR_BLOCK) % TAR_BLOCK;
+            archive.extend(std::iter::repeat(0).take(padding));
+        }
+    }
+}
+
+/// Packs `initrd/` into `initrd.tar` in the build output directory.
+fn build_initrd(manifest_dir: &Path) {
+    let root = manifest_dir.join("initrd");
+    let mut archive = Vec::new();
+    tar_directory(&mut archive, &root, &root);
+    // the archive ends with two zero blocks
+    archive.extend_from_slice(&[0; 2 * TAR_BLOCK]);
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("initrd.tar"), &archive).unwrap();
+    println!("cargo:rerun-if-changed={}", root.display());
+}
+
+/// Recreates the FAT test disk with the host tools. Without them the previous disk is
+/// kept, or a blank one is created so QEMU still starts and the FAT tests fail.
+fn build_fat_disk(manifest_dir: &Path, target: &Path) {
+    let script = manifest_dir.join("scripts").join("fat-images.sh");
+    let path = target.join(FAT_DISK);
+    let status = Command::new("sh").arg(&script).arg(&path).status();
+    if !status.map(|status| status.success()).unwrap_or(false) {
+        println!("cargo:warning=creating {} failed; are sfdisk, mkfs.fat and mtools installed?", FAT_DISK);
+        if !path.exists() {
+            fs::File::create(&path).unwrap().set_len(STORE_DISK_SIZE).unwrap();
+        }
+    }
+    println!("cargo:rerun-if-changed={}", script.display());
+}
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    build_initrd(&manifest_dir);
+    let target = manifest_dir.join("target");
+    fs::create_dir_all(&target).unwrap();
+    let image = test_disk_image();
+    for name in TEST_DISKS.iter() {
+        fs::write(target.join(name), &image).unwrap();
+    }
+    // existing stores are kept, so the saved tables survive a rebuild
+    for name in STORE_DISKS.iter() {
+        let path = target.join(name);
+        if !path.exists() {
+            fs::File::create(&path).unwrap().set_len(STORE_DISK_SIZE).unwrap();
+        }
+    }
+    build_fat_disk(&manifest_dir, &target);
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,43 @@
+#!/bin/sh
+# Creates the disk that tests/fat.rs reads: an MBR with a FAT32 partition and a FAT16
+# partition, each holding the same files.
+#
+# Usage: scripts/fat-images.sh <image>
+# Needs sfdisk (util-linux), mkfs.fat (dosfstools) and mtools.
+set -eu
+
+image=${1:?usage: $0 <image>}
+work=$(mktemp -d)
+trap 'rm -rf "$work"' EXIT
+
+# partition 1: 40 MiB FAT32 with one sector per cluster
+# partition 2: 16 MiB FAT16 with four sectors per cluster
+fat32_start=2048
+fat32_sectors=81920
+fat16_start=$((fat32_start + fat32_sectors))
+fat16_sectors=32768
+total=$((fat16_start + fat16_sectors))
+
+rm -f "$image"
+truncate -s $((total * 512)) "$image"
+sfdisk --quiet "$image" <<TABLE
+label: dos
+start=$fat32_start, size=$fat32_sectors, type=c
+start=$fat16_start, size=$fat16_sectors, type=6
+TABLE
+
+mkfs.fat -F 32 -s 1 -n HIVEFAT32 --offset $fat32_start "$image" $((fat32_sectors / 2)) >/dev/null
+mkfs.fat -F 16 -s 4 -n HIVEFAT16 --offset $fat16_start "$image" $((fat16_sectors / 2)) >/dev/null
+
+printf 'Hello from a FAT volume.\n' > "$work/README.TXT"
+printf 'This file has a long name.\n' > "$work/A long file name.txt"
+printf 'Three levels down.\n' > "$work/deep.txt"
+# spans many clusters on both volumes
+yes 0123456789abcdef | head -c 20000 > "$work/big.bin"
+
+for offset in $fat32_start $fat16_start; do
+    fat="$image@@$((offset * 512))"
+    mcopy -i "$fat" "$work/README.TXT" "$work/A long file name.txt" "$work/big.bin" ::/
+    mmd -i "$fat" ::/docs ::/docs/nested
+    mcopy -i "$fat" "$work/deep.txt" ::/docs/nested/
+done
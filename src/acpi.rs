@@ -0,0 +1,331 @@
+//! Just enough ACPI table parsing to find the interrupt controllers.
+//!
+//! All tables are read through the physical memory window, so `memory::remap_physical_memory`
+//! must have run before any function here is called.
+
+use crate::memory;
+use alloc::vec::Vec;
+use core::ptr;
+use core::slice;
+use x86_64::PhysAddr;
+
+/// Where the BIOS data area stores the real-mode segment of the extended BIOS data area.
+const EBDA_SEGMENT_POINTER: u64 = 0x40e;
+/// The read-only BIOS area that may hold the RSDP.
+const BIOS_AREA: (u64, u64) = (0xe_0000, 0x10_0000);
+/// The size of the header that starts every system description table.
+const SDT_HEADER_SIZE: usize = 36;
+/// The polarity bits of the MPS INTI flags, and their value for active-low.
+const INTI_POLARITY: u16 = 0b11;
+const INTI_ACTIVE_LOW: u16 = 0b11;
+/// The trigger mode bits of the MPS INTI flags, and their value for level-triggered.
+const INTI_TRIGGER_MODE: u16 = 0b1100;
+const INTI_LEVEL_TRIGGERED: u16 = 0b1100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// No root system description pointer was found in the BIOS areas.
+    RsdpNotFound,
+    /// The table with the given signature has a wrong checksum.
+    InvalidChecksum([u8; 4]),
+    /// No table with the given signature exists.
+    TableNotFound([u8; 4]),
+}
+
+/// A processor's local APIC, from the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicEntry {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// Whether the processor can be started.
+    pub enabled: bool,
+}
+
+/// An I/O APIC, from the MADT.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt this I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// A legacy ISA interrupt that is not identity mapped to a global system interrupt.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// The MPS INTI flags: polarity in bits 0-1 and trigger mode in bits 2-3, each 0 if
+    /// the interrupt conforms to the specification of its bus.
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    /// Whether the interrupt is active-low. Conforming ISA interrupts are active-high.
+    pub fn active_low(&self) -> bool {
+        self.flags & INTI_POLARITY == INTI_ACTIVE_LOW
+    }
+
+    /// Whether the interrupt is level-triggered. Conforming ISA interrupts are
+    /// edge-triggered.
+    pub fn level_triggered(&self) -> bool {
+        self.flags & INTI_TRIGGER_MODE == INTI_LEVEL_TRIGGERED
+    }
+}
+
+/// The parts of the multiple APIC description table the kernel uses.
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// Whether the machine also has the legacy 8259 PICs.
+    pub has_legacy_pics: bool,
+    pub local_apics: Vec<LocalApicEntry>,
+    pub io_apics: Vec<IoApicEntry>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Returns the global system interrupt and its polarity and trigger mode for an ISA IRQ.
+    pub fn isa_irq(&self, irq: u8) -> InterruptOverride {
+        self.overrides
+            .iter()
+            .find(|o| o.irq == irq)
+            .cloned()
+            .unwrap_or(InterruptOverride {
+                irq,
+                gsi: u32::from(irq),
+                flags: 0,
+            })
+    }
+
+    /// Returns the global system interrupt and its polarity and trigger mode for the
+    /// legacy interrupt line of a PCI function.
+    ///
+    /// PCI interrupts are active-low and level-triggered, unless an override of the line
+    /// says otherwise, like QEMU's active-high overrides for its PCI lines. The flags of
+    /// the returned route spell out what conforming to PCI means.
+    pub fn pci_irq(&self, line: u8) -> InterruptOverride {
+        let mut route = self.isa_irq(line);
+        if route.flags & INTI_POLARITY == 0 {
+            route.flags |= INTI_ACTIVE_LOW;
+        }
+        if route.flags & INTI_TRIGGER_MODE == 0 {
+            route.flags |= INTI_LEVEL_TRIGGERED;
+        }
+        route
+    }
+}
+
+/// Returns the bytes at a physical address.
+unsafe fn physical_bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(memory::phys_to_virt(addr).as_ptr(), len)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    assert!(offset + 2 <= bytes.len());
+    unsafe { ptr::read_unaligned(bytes.as_ptr().add(offset) as *const u16) }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    assert!(offset + 4 <= bytes.len());
+    unsafe { ptr::read_unaligned(bytes.as_ptr().add(offset) as *const u32) }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    assert!(offset + 8 <= bytes.len());
+    unsafe { ptr::read_unaligned(bytes.as_ptr().add(offset) as *const u64) }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Searches the extended BIOS data area and the BIOS area for the RSDP.
+fn find_rsdp() -> Option<PhysAddr> {
+    let pointer = unsafe { physical_bytes(PhysAddr::new(EBDA_SEGMENT_POINTER), 2) };
+    let ebda_segment = read_u16(pointer, 0);
+    let ebda = u64::from(ebda_segment) << 4;
+    let areas = [(ebda, ebda + 1024), BIOS_AREA];
+    for &(start, end) in areas.iter().filter(|&&(start, _)| start != 0) {
+        // the RSDP is 16 byte aligned
+        for addr in (start..end).step_by(16) {
+            let bytes = unsafe { physical_bytes(PhysAddr::new(addr), 20) };
+            if &bytes[..8] == b"RSD PTR " && checksum_ok(bytes) {
+                return Some(PhysAddr::new(addr));
+            }
+        }
+    }
+    None
+}
+
+/// Returns the whole table at `addr`, after checking its checksum.
+fn table(addr: PhysAddr) -> Result<&'static [u8], AcpiError> {
+    let header = unsafe { physical_bytes(addr, SDT_HEADER_SIZE) };
+    let length = read_u32(header, 4) as usize;
+    let bytes = unsafe { physical_bytes(addr, length) };
+    if checksum_ok(bytes) {
+        Ok(bytes)
+    } else {
+        let mut signature = [0; 4];
+        signature.copy_from_slice(&header[..4]);
+        Err(AcpiError::InvalidChecksum(signature))
+    }
+}
+
+/// Returns the physical address of the table with the given signature.
+///
+/// Uses the XSDT if the firmware provides ACPI 2.0 or later and the RSDT otherwise.
+pub fn find_table(signature: &[u8; 4]) -> Result<PhysAddr, AcpiError> {
+    let rsdp_addr = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let rsdp = unsafe { physical_bytes(rsdp_addr, 36) };
+    let revision = rsdp[15];
+    let (root, entry_size) = if revision >= 2 && read_u64(rsdp, 24) != 0 {
+        (table(PhysAddr::new(read_u64(rsdp, 24)))?, 8)
+    } else {
+        (table(PhysAddr::new(u64::from(read_u32(rsdp, 16))))?, 4)
+    };
+
+    let entries = &root[SDT_HEADER_SIZE..];
+    for offset in (0..entries.len() / entry_size).map(|n| n * entry_size) {
+        let addr = if entry_size == 8 {
+            read_u64(entries, offset)
+        } else {
+            u64::from(read_u32(entries, offset))
+        };
+        let addr = PhysAddr::new(addr);
+        if unsafe { physical_bytes(addr, 4) } == signature {
+            table(addr)?;
+            return Ok(addr);
+        }
+    }
+    Err(AcpiError::TableNotFound(*signature))
+}
+
+/// Returns the checked bytes of the table with the given signature.
+pub fn find_table_bytes(signature: &[u8; 4]) -> Result<&'static [u8], AcpiError> {
+    table(find_table(signature)?)
+}
+
+/// Parses the MADT.
+pub fn madt() -> Result<Madt, AcpiError> {
+    let bytes = find_table_bytes(b"APIC")?;
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(read_u32(bytes, SDT_HEADER_SIZE))),
+        has_legacy_pics: read_u32(bytes, SDT_HEADER_SIZE + 4) & 1 != 0,
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= bytes.len() {
+        let entry_type = bytes[offset];
+        let length = bytes[offset + 1] as usize;
+        if length < 2 || offset + length > bytes.len() {
+            break;
+        }
+        let entry = &bytes[offset..offset + length];
+        match entry_type {
+            0 => madt.local_apics.push(LocalApicEntry {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                enabled: read_u32(entry, 4) & 1 != 0,
+            }),
+            1 => madt.io_apics.push(IoApicEntry {
+                id: entry[2],
+                address: PhysAddr::new(u64::from(read_u32(entry, 4))),
+                gsi_base: read_u32(entry, 8),
+            }),
+            2 => {
+                madt.overrides.push(InterruptOverride {
+                    irq: entry[3],
+                    gsi: read_u32(entry, 4),
+                    flags: read_u16(entry, 8),
+                });
+            }
+            5 => madt.local_apic_address = PhysAddr::new(read_u64(entry, 4)),
+            _ => {}
+        }
+        offset += length;
+    }
+    Ok(madt)
+}
+
+/// An address in one of the ACPI address spaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    /// 0 for memory, 1 for I/O ports, 2 for PCI configuration space.
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+impl GenericAddress {
+    fn parse(bytes: &[u8], offset: usize) -> GenericAddress {
+        GenericAddress {
+            address_space: bytes[offset],
+            bit_width: bytes[offset + 1],
+            bit_offset: bytes[offset + 2],
+            access_size: bytes[offset + 3],
+            address: read_u64(bytes, offset + 4),
+        }
+    }
+}
+
+/// The parts of the fixed ACPI description table the kernel uses.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub dsdt: PhysAddr,
+    /// The port that switches the firmware into ACPI mode, 0 if it always is.
+    pub smi_command: u16,
+    pub acpi_enable: u8,
+    pub pm1a_control: u16,
+    /// 0 if there is no second PM1 control block.
+    pub pm1b_control: u16,
+    /// The register to write `reset_value` to for a reboot, if the firmware supports it.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+/// Whether the reset register in the FADT is valid.
+const FADT_RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+/// Parses the FADT.
+pub fn fadt() -> Result<Fadt, AcpiError> {
+    let bytes = find_table_bytes(b"FACP")?;
+    let mut dsdt = u64::from(read_u32(bytes, 40));
+    // the 64-bit address of ACPI 2.0 takes precedence
+    if bytes.len() >= 148 && read_u64(bytes, 140) != 0 {
+        dsdt = read_u64(bytes, 140);
+    }
+    let flags = if bytes.len() >= 116 { read_u32(bytes, 112) } else { 0 };
+    let reset_register = if flags & FADT_RESET_REGISTER_SUPPORTED != 0 && bytes.len() >= 129 {
+        Some(GenericAddress::parse(bytes, 116))
+    } else {
+        None
+    };
+    Ok(Fadt {
+        dsdt: PhysAddr::new(dsdt),
+        smi_command: read_u32(bytes, 48) as u16,
+        acpi_enable: bytes[52],
+        pm1a_control: read_u32(bytes, 64) as u16,
+        pm1b_control: read_u32(bytes, 68) as u16,
+        reset_register,
+        reset_value: if bytes.len() >= 129 { bytes[128] } else { 0 },
+    })
+}
+
+/// Returns the checked bytes of the DSDT, including its header.
+pub fn dsdt(fadt: &Fadt) -> Result<&'static [u8], AcpiError> {
+    table(fadt.dsdt)
+}
+
+/// Returns the AML code of a definition block, without its header.
+pub fn aml(table: &[u8]) -> &[u8] {
+    &table[SDT_HEADER_SIZE.min(table.len())..]
+}
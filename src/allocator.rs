@@ -1,37 +1,33 @@
+use crate::cpu;
+use crate::memory::{self, FrameAllocatorAllSizes};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use linked_list_allocator::LockedHeap;
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, MapperAllSizes, PageTableFlags},
     VirtAddr,
 };
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 300 * 1024; // 100 KiB
+// aligned to 2 MiB, so that the heap is backed by a single huge page
+pub const HEAP_START: usize = 0x_4444_4440_0000;
+pub const HEAP_SIZE: usize = 2 * 1024 * 1024; // 2 MiB
 
 pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut impl FrameAllocatorAllSizes,
 ) -> Result<(), MapToError> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | cpu::no_execute();
+    memory::map_range(
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_SIZE as u64,
+        flags,
+        mapper,
+        frame_allocator,
+    )?;
 
     unsafe {
-        super::ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        super::ALLOCATOR.init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
@@ -48,3 +44,48 @@ unsafe impl GlobalAlloc for Dummy {
         panic!("dealloc should be never called")
     }
 }
+
+/// A `LockedHeap` that counts the bytes allocated from it.
+pub struct CountingHeap {
+    heap: LockedHeap,
+    used: AtomicUsize,
+}
+
+impl CountingHeap {
+    pub const fn empty() -> CountingHeap {
+        CountingHeap {
+            heap: LockedHeap::empty(),
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Initializes the heap with the given memory, like `Heap::init`.
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        self.heap.lock().init(start, size);
+    }
+
+    /// Returns the number of bytes allocated and not freed, as requested by the layouts.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+unsafe impl GlobalAlloc for CountingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.heap.alloc(layout);
+        if !ptr.is_null() {
+            self.used.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout);
+        self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of heap bytes currently allocated.
+pub fn heap_used() -> usize {
+    super::ALLOCATOR.used()
+}
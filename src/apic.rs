@@ -0,0 +1,351 @@
+//! Local APIC and I/O APIC interrupt delivery.
+//!
+//! `init` switches from the 8259 PICs to the APICs if the MADT describes them. Until then,
+//! and whenever `init` fails, interrupts keep arriving through `interrupts::PICS`.
+
+use crate::acpi::{self, AcpiError, InterruptOverride, Madt};
+use crate::interrupts::InterruptIndex;
+use crate::memory;
+use crate::percpu;
+use crate::sync::IrqMutex;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The vector of spurious local APIC interrupts. Its low four bits must be set.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_INTERRUPT_COMMAND_LOW: usize = 0x300;
+const LAPIC_INTERRUPT_COMMAND_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divides the bus clock by 16 before it drives the timer.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// The frequency of the programmable interval timer.
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// The reload value the firmware leaves in PIT channel 0, which drives the timer interrupt
+/// while the PICs are in use.
+const PIT_DEFAULT_RELOAD: u64 = 65536;
+/// The number of PIT cycles the local APIC timer is calibrated against, about 10ms.
+const CALIBRATION_CYCLES: u16 = 11932;
+
+const ISA_IRQ_TIMER: u8 = 0;
+const ISA_IRQ_KEYBOARD: u8 = 1;
+const ISA_IRQ_SERIAL: u8 = 4;
+
+/// The virtual address of the local APIC registers, 0 while the APICs are not in use.
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref IO_APICS: IrqMutex<Vec<IoApic>> = IrqMutex::new("IO_APICS", Vec::new());
+}
+
+struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn new(base: VirtAddr, gsi_base: u32) -> IoApic {
+        let mut io_apic = IoApic {
+            base,
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            (self.base.as_u64() as *mut u32).write_volatile(register);
+            ((self.base.as_u64() + 0x10) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            (self.base.as_u64() as *mut u32).write_volatile(register);
+            ((self.base.as_u64() + 0x10) as *mut u32).write_volatile(value);
+        }
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        // masked while the two halves disagree
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// Returns whether interrupts are delivered through the APICs.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::SeqCst) != 0
+}
+
+fn local_apic_read(register: usize) -> u32 {
+    let base = LOCAL_APIC.load(Ordering::SeqCst);
+    unsafe { ((base as usize + register) as *const u32).read_volatile() }
+}
+
+fn local_apic_write(register: usize, value: u32) {
+    let base = LOCAL_APIC.load(Ordering::SeqCst);
+    unsafe { ((base as usize + register) as *mut u32).write_volatile(value) };
+}
+
+/// Returns the APIC id of the running processor.
+pub fn local_apic_id() -> u8 {
+    (local_apic_read(LAPIC_ID) >> 24) as u8
+}
+
+/// Signals the end of an interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    local_apic_write(LAPIC_EOI, 0);
+}
+
+/// Sends an interrupt command and waits until the local APIC accepted it.
+///
+/// Interrupts are disabled meanwhile, so that no handler writes the command registers
+/// between the two halves.
+fn send_command(destination: u8, command: u32) {
+    interrupts::without_interrupts(|| {
+        local_apic_write(LAPIC_INTERRUPT_COMMAND_HIGH, u32::from(destination) << 24);
+        local_apic_write(LAPIC_INTERRUPT_COMMAND_LOW, command);
+        while local_apic_read(LAPIC_INTERRUPT_COMMAND_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+    });
+}
+
+/// Sends an inter-processor interrupt with the given vector to the processor `destination`.
+pub fn send_ipi(destination: u8, vector: u8) {
+    send_command(destination, ICR_LEVEL_ASSERT | u32::from(vector));
+}
+
+/// Resets the processor `destination` into its wait-for-startup state.
+pub(crate) fn send_init(destination: u8) {
+    send_command(destination, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Starts the processor `destination` in real mode at the start of the given 4KiB page.
+pub(crate) fn send_startup(destination: u8, page: u8) {
+    send_command(destination, ICR_DELIVERY_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+}
+
+/// Enables the local APIC of an application processor.
+///
+/// Interrupts from the I/O APIC all go to the boot processor, so only inter-processor
+/// interrupts arrive here.
+pub fn init_ap() {
+    enable_local_apic_registers();
+}
+
+/// Switches interrupt delivery from the legacy PICs to the APICs.
+///
+/// Routes the keyboard and serial IRQs through the I/O APIC to the boot processor and
+/// replaces the PIT with the local APIC timer at the same rate, so that ticks keep their
+/// length. If the MADT is missing, the PICs stay in use and the error is returned.
+///
+/// Requires the physical memory window, which covers the APIC registers, and the heap.
+pub fn init() -> Result<(), AcpiError> {
+    let madt = acpi::madt()?;
+    if madt.io_apics.is_empty() {
+        return Err(AcpiError::TableNotFound(*b"APIC"));
+    }
+
+    interrupts::without_interrupts(|| {
+        if madt.has_legacy_pics {
+            mask_legacy_pics();
+        }
+        enable_local_apic(madt.local_apic_address);
+        init_io_apics(&madt);
+        let destination = local_apic_id();
+        percpu::current().set_apic_id(destination);
+        route_isa_irq(&madt, ISA_IRQ_KEYBOARD, InterruptIndex::Keyboard.as_u8(), destination);
+        route_isa_irq(&madt, ISA_IRQ_SERIAL, InterruptIndex::Serial.as_u8(), destination);
+        // the PIT is replaced by the local APIC timer
+        set_isa_irq_masked(&madt, ISA_IRQ_TIMER);
+        start_timer(calibrate_timer());
+    });
+    Ok(())
+}
+
+/// Masks all lines of both PICs. They stay initialized, so spurious interrupts still use
+/// the remapped vectors.
+fn mask_legacy_pics() {
+    let mut master_data: Port<u8> = Port::new(0x21);
+    let mut slave_data: Port<u8> = Port::new(0xa1);
+    unsafe {
+        master_data.write(0xff);
+        slave_data.write(0xff);
+    }
+}
+
+fn enable_local_apic(address: PhysAddr) {
+    LOCAL_APIC.store(memory::phys_to_virt(address).as_u64(), Ordering::SeqCst);
+    enable_local_apic_registers();
+}
+
+/// Enables the local APIC of the running processor. All processors share the register
+/// address.
+fn enable_local_apic_registers() {
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    unsafe { apic_base.write(apic_base.read() | APIC_BASE_ENABLE) };
+    local_apic_write(LAPIC_TASK_PRIORITY, 0);
+    local_apic_write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
+
+/// Registers the I/O APICs and masks all of their inputs.
+fn init_io_apics(madt: &Madt) {
+    let mut io_apics = IO_APICS.lock();
+    for entry in madt.io_apics.iter() {
+        let io_apic = IoApic::new(memory::phys_to_virt(entry.address), entry.gsi_base);
+        for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.entries {
+            io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+        }
+        io_apics.push(io_apic);
+    }
+}
+
+fn redirection_entry(route: InterruptOverride, vector: u8, destination: u8) -> (u32, u64) {
+    let mut entry = u64::from(vector) | (u64::from(destination) << 56);
+    if route.active_low() {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if route.level_triggered() {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    (route.gsi, entry)
+}
+
+fn set_gsi(gsi: u32, entry: u64) -> bool {
+    match IO_APICS.lock().iter().find(|io_apic| io_apic.handles(gsi)) {
+        Some(io_apic) => {
+            io_apic.set_redirection(gsi, entry);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Delivers the ISA IRQ as `vector` to the processor with the given APIC id.
+///
+/// Returns `false` if no I/O APIC handles the IRQ.
+pub fn route_isa_irq(madt: &Madt, irq: u8, vector: u8, destination: u8) -> bool {
+    let (gsi, entry) = redirection_entry(madt.isa_irq(irq), vector, destination);
+    set_gsi(gsi, entry)
+}
+
+/// Delivers the legacy interrupt line of a PCI function as `vector` to the processor with
+/// the given APIC id, with the polarity and trigger mode of `Madt::pci_irq`.
+///
+/// The line is level-triggered and may be shared, so the handler must make its devices
+/// deassert it before the end of interrupt. Returns `false` if no I/O APIC handles the line.
+pub fn route_pci_irq(madt: &Madt, line: u8, vector: u8, destination: u8) -> bool {
+    let (gsi, entry) = redirection_entry(madt.pci_irq(line), vector, destination);
+    set_gsi(gsi, entry)
+}
+
+fn set_isa_irq_masked(madt: &Madt, irq: u8) -> bool {
+    set_gsi(madt.isa_irq(irq).gsi, REDIRECTION_MASKED)
+}
+
+/// Returns how many local APIC timer counts pass in one period of the PIT's default rate.
+///
+/// Uses PIT channel 2, whose output can be polled without an interrupt.
+fn calibrate_timer() -> u32 {
+    start_pit_countdown(CALIBRATION_CYCLES);
+    local_apic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    local_apic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    local_apic_write(LAPIC_TIMER_INITIAL_COUNT, u32::max_value());
+    while !pit_countdown_done() {}
+    let elapsed = u32::max_value() - local_apic_read(LAPIC_TIMER_CURRENT_COUNT);
+    local_apic_write(LAPIC_TIMER_INITIAL_COUNT, 0);
+
+    let per_tick = u64::from(elapsed) * PIT_DEFAULT_RELOAD / u64::from(CALIBRATION_CYCLES);
+    per_tick.max(1).min(u64::from(u32::max_value())) as u32
+}
+
+/// Starts counting down `cycles` on PIT channel 2.
+fn start_pit_countdown(cycles: u16) {
+    let mut control: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    unsafe {
+        // gate on, speaker off
+        let value = control.read();
+        control.write((value & !0b10) | 0b01);
+        // channel 2, low and high byte, mode 0
+        command.write(0b1011_0000);
+        channel_2.write(cycles as u8);
+        channel_2.write((cycles >> 8) as u8);
+        // a rising edge of the gate restarts the count
+        let value = control.read();
+        control.write(value & !0b01);
+        control.write(value | 0b01);
+    }
+}
+
+/// Returns whether the countdown on PIT channel 2 reached zero.
+fn pit_countdown_done() -> bool {
+    let mut control: Port<u8> = Port::new(0x61);
+    // bit 5 is the output of channel 2, which goes high once the count reached zero
+    unsafe { control.read() } & 0b10_0000 != 0
+}
+
+/// Busy-waits for at least the given number of microseconds, using PIT channel 2.
+///
+/// Works without interrupts, so it can be used before the timer runs.
+pub fn delay_us(microseconds: u64) {
+    let mut cycles = microseconds * PIT_FREQUENCY / 1_000_000 + 1;
+    while cycles > 0 {
+        let chunk = cycles.min(u64::from(u16::max_value()));
+        start_pit_countdown(chunk as u16);
+        while !pit_countdown_done() {}
+        cycles -= chunk;
+    }
+}
+
+/// Starts the local APIC timer in periodic mode on the timer vector.
+fn start_timer(initial_count: u32) {
+    local_apic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    local_apic_write(
+        LAPIC_LVT_TIMER,
+        LVT_TIMER_PERIODIC | u32::from(InterruptIndex::Timer.as_u8()),
+    );
+    local_apic_write(LAPIC_TIMER_INITIAL_COUNT, initial_count);
+}
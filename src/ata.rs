@@ -0,0 +1,405 @@
+//! ATA disks on the two legacy IDE channels, transferring data with programmed I/O.
+//!
+//! The driver binds to an IDE controller in compatibility mode, which decodes the fixed
+//! ISA ports of both channels. Commands complete through IRQ 14 and 15 when the I/O APIC
+//! delivers them, otherwise the status register is polled.
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::interrupts::InterruptIndex;
+use crate::pci::{self, Driver, Match, PciDevice};
+use crate::sync::{SleepMutex, WaitQueue};
+use crate::{acpi, apic, percpu};
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// offsets from the command block base port
+const REGISTER_DATA: u16 = 0;
+const REGISTER_ERROR: u16 = 1;
+const REGISTER_SECTOR_COUNT: u16 = 2;
+const REGISTER_LBA_LOW: u16 = 3;
+const REGISTER_LBA_MID: u16 = 4;
+const REGISTER_LBA_HIGH: u16 = 5;
+const REGISTER_DRIVE: u16 = 6;
+const REGISTER_STATUS: u16 = 7;
+const REGISTER_COMMAND: u16 = 7;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DEVICE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+/// Set in the device control register to keep the drives from raising interrupts.
+const CONTROL_NO_INTERRUPTS: u8 = 1 << 1;
+
+const DRIVE_LBA: u8 = 0xe0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_FLUSH_CACHE: u8 = 0xe7;
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// The number of sectors reachable with 28-bit addresses.
+const LBA28_SECTORS: u64 = 1 << 28;
+const LBA28_MAX_COUNT: u64 = 256;
+const LBA48_MAX_COUNT: u64 = 65536;
+
+/// Polls of the status register before a command is considered lost, 10µs apart.
+const POLL_LIMIT: usize = 100_000;
+
+/// Set in the programming interface of the IDE class when a channel uses PCI resources
+/// instead of the ISA ports.
+const PROG_IF_PRIMARY_NATIVE: u8 = 1 << 0;
+const PROG_IF_SECONDARY_NATIVE: u8 = 1 << 2;
+
+lazy_static! {
+    static ref CHANNELS: [Channel; 2] = [
+        Channel::new(0, 0x1f0, 0x3f6, 14, InterruptIndex::PrimaryAta),
+        Channel::new(1, 0x170, 0x376, 15, InterruptIndex::SecondaryAta),
+    ];
+}
+
+static IDE_DRIVER: Driver = Driver {
+    name: "ata",
+    matches: &[Match::Class { class: 0x01, subclass: 0x01 }],
+    probe: probe,
+};
+
+/// One IDE channel, whose two drives share the registers.
+struct Channel {
+    index: u8,
+    base: u16,
+    control: u16,
+    irq: u8,
+    vector: InterruptIndex,
+    uses_interrupts: AtomicBool,
+    /// Set by the interrupt handler, consumed by the thread waiting for the command.
+    interrupted: AtomicBool,
+    waiters: WaitQueue,
+    /// Held for the whole duration of a command.
+    lock: SleepMutex<()>,
+}
+
+/// The information returned by IDENTIFY DEVICE that the driver uses.
+struct Identity {
+    lba48: bool,
+    sectors: u64,
+    model: String,
+}
+
+impl Channel {
+    fn new(index: u8, base: u16, control: u16, irq: u8, vector: InterruptIndex) -> Channel {
+        Channel {
+            index,
+            base,
+            control,
+            irq,
+            vector,
+            uses_interrupts: AtomicBool::new(false),
+            interrupted: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            lock: SleepMutex::new("ata channel", ()),
+        }
+    }
+
+    fn read_register(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.base + register).read() }
+    }
+
+    fn write_register(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.base + register).write(value) }
+    }
+
+    /// Reads the status without acknowledging an interrupt.
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::new(self.control).read() }
+    }
+
+    /// Routes the channel's IRQ to the boot processor, where the waiting threads run, or
+    /// disables the interrupts of its drives if that is not possible.
+    fn init_interrupts(&self) {
+        let routed = apic::is_enabled()
+            && match acpi::madt() {
+                Ok(madt) => apic::route_isa_irq(
+                    &madt,
+                    self.irq,
+                    self.vector.as_u8(),
+                    percpu::boot_cpu().apic_id(),
+                ),
+                Err(_) => false,
+            };
+        self.uses_interrupts.store(routed, Ordering::SeqCst);
+        let control = if routed { 0 } else { CONTROL_NO_INTERRUPTS };
+        unsafe { Port::new(self.control).write(control) };
+    }
+
+    /// Waits the 400ns a drive needs to present its status after a selection or command.
+    fn settle(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn select(&self, slave: bool, head: u8) {
+        let slave = if slave { DRIVE_SLAVE } else { 0 };
+        self.write_register(REGISTER_DRIVE, DRIVE_LBA | slave | head);
+        self.settle();
+    }
+
+    fn issue(&self, command: u8) {
+        self.interrupted.store(false, Ordering::SeqCst);
+        self.write_register(REGISTER_COMMAND, command);
+        self.settle();
+    }
+
+    /// Polls until the drive is not busy and `condition` holds for its status.
+    fn poll<F>(&self, condition: F) -> Result<u8, BlockError>
+    where
+        F: Fn(u8) -> bool,
+    {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                if status & (STATUS_ERROR | STATUS_DEVICE_FAULT) != 0 {
+                    return Err(BlockError::Device(self.read_register(REGISTER_ERROR)));
+                }
+                if condition(status) {
+                    return Ok(status);
+                }
+            }
+            apic::delay_us(10);
+        }
+        Err(BlockError::Timeout)
+    }
+
+    /// Waits until the drive finished the current step of a command.
+    fn wait_for_completion(&self) -> Result<u8, BlockError> {
+        if self.uses_interrupts.load(Ordering::SeqCst) {
+            self.waiters
+                .wait_until(|| self.interrupted.swap(false, Ordering::SeqCst));
+        }
+        let status = self.poll(|_| true)?;
+        // acknowledges the interrupt when polling
+        self.read_register(REGISTER_STATUS);
+        Ok(status)
+    }
+
+    fn read_data(&self, buffer: &mut [u8]) {
+        let mut data: Port<u16> = Port::new(self.base + REGISTER_DATA);
+        for bytes in buffer.chunks_mut(2) {
+            let word = unsafe { data.read() };
+            bytes[0] = word as u8;
+            bytes[1] = (word >> 8) as u8;
+        }
+    }
+
+    fn write_data(&self, buffer: &[u8]) {
+        let mut data: Port<u16> = Port::new(self.base + REGISTER_DATA);
+        for bytes in buffer.chunks(2) {
+            unsafe { data.write(u16::from(bytes[0]) | u16::from(bytes[1]) << 8) };
+        }
+    }
+
+    /// Sends IDENTIFY DEVICE to a drive. Returns `None` if there is no ATA drive.
+    fn identify(&self, slave: bool) -> Option<Identity> {
+        let _guard = self.lock.lock();
+        self.select(slave, 0);
+        for &register in [REGISTER_SECTOR_COUNT, REGISTER_LBA_LOW, REGISTER_LBA_MID, REGISTER_LBA_HIGH].iter() {
+            self.write_register(register, 0);
+        }
+        self.issue(COMMAND_IDENTIFY);
+        let status = self.alternate_status();
+        // 0xff is read from a channel without drives
+        if status == 0 || status == 0xff {
+            return None;
+        }
+        self.poll(|_| true).ok()?;
+        // ATAPI and SATA drives answer with a signature instead
+        if self.read_register(REGISTER_LBA_MID) != 0 || self.read_register(REGISTER_LBA_HIGH) != 0 {
+            return None;
+        }
+        self.poll(|status| status & STATUS_DATA_REQUEST != 0).ok()?;
+        let mut data = [0u8; SECTOR_SIZE];
+        self.read_data(&mut data);
+        self.read_register(REGISTER_STATUS);
+
+        let word = |index: usize| u16::from(data[2 * index]) | u16::from(data[2 * index + 1]) << 8;
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (100..104).rev().fold(0, |sectors, index| sectors << 16 | u64::from(word(index)))
+        } else {
+            u64::from(word(60)) | u64::from(word(61)) << 16
+        };
+        // the model is stored as big-endian words, padded with spaces
+        let mut model = String::new();
+        for index in 27..47 {
+            model.push((word(index) >> 8) as u8 as char);
+            model.push(word(index) as u8 as char);
+        }
+        let model = model.trim_end().into();
+        Some(Identity {
+            lba48,
+            sectors,
+            model,
+        })
+    }
+}
+
+/// A drive found on one of the IDE channels.
+pub struct AtaDrive {
+    name: String,
+    channel: &'static Channel,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+    model: String,
+}
+
+impl AtaDrive {
+    /// The model name the drive reported.
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn supports_lba48(&self) -> bool {
+        self.lba48
+    }
+
+    /// Selects the drive and sets up the address registers for a transfer. Returns whether
+    /// the 48-bit command has to be used.
+    fn set_address(&self, sector: u64, count: u64) -> bool {
+        let channel = self.channel;
+        let lba48 = self.lba48 && (sector + count > LBA28_SECTORS || count > LBA28_MAX_COUNT);
+        if lba48 {
+            channel.select(self.slave, 0);
+            // the high bytes are written first, the registers keep the previous value
+            channel.write_register(REGISTER_SECTOR_COUNT, (count >> 8) as u8);
+            channel.write_register(REGISTER_LBA_LOW, (sector >> 24) as u8);
+            channel.write_register(REGISTER_LBA_MID, (sector >> 32) as u8);
+            channel.write_register(REGISTER_LBA_HIGH, (sector >> 40) as u8);
+        } else {
+            channel.select(self.slave, (sector >> 24) as u8 & 0x0f);
+        }
+        // a count of 0 means the maximum
+        channel.write_register(REGISTER_SECTOR_COUNT, count as u8);
+        channel.write_register(REGISTER_LBA_LOW, sector as u8);
+        channel.write_register(REGISTER_LBA_MID, (sector >> 8) as u8);
+        channel.write_register(REGISTER_LBA_HIGH, (sector >> 16) as u8);
+        lba48
+    }
+
+    /// The largest number of sectors one command transfers.
+    fn max_count(&self) -> u64 {
+        if self.lba48 {
+            LBA48_MAX_COUNT
+        } else {
+            LBA28_MAX_COUNT
+        }
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buffer.len())?;
+        let _guard = self.channel.lock.lock();
+        let mut sector = sector;
+        for chunk in buffer.chunks_mut(self.max_count() as usize * SECTOR_SIZE) {
+            let count = (chunk.len() / SECTOR_SIZE) as u64;
+            let lba48 = self.set_address(sector, count);
+            self.channel.issue(if lba48 { COMMAND_READ_SECTORS_EXT } else { COMMAND_READ_SECTORS });
+            // the drive interrupts whenever the next sector is ready
+            for data in chunk.chunks_mut(SECTOR_SIZE) {
+                self.channel.wait_for_completion()?;
+                self.channel.poll(|status| status & STATUS_DATA_REQUEST != 0)?;
+                self.channel.read_data(data);
+            }
+            sector += count;
+        }
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buffer.len())?;
+        let _guard = self.channel.lock.lock();
+        let mut sector = sector;
+        for chunk in buffer.chunks(self.max_count() as usize * SECTOR_SIZE) {
+            let count = (chunk.len() / SECTOR_SIZE) as u64;
+            let lba48 = self.set_address(sector, count);
+            self.channel.issue(if lba48 { COMMAND_WRITE_SECTORS_EXT } else { COMMAND_WRITE_SECTORS });
+            // the drive asks for the first sector without an interrupt, and interrupts after
+            // each sector it stored
+            for data in chunk.chunks(SECTOR_SIZE) {
+                self.channel.poll(|status| status & STATUS_DATA_REQUEST != 0)?;
+                self.channel.write_data(data);
+                self.channel.wait_for_completion()?;
+            }
+            sector += count;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let _guard = self.channel.lock.lock();
+        self.channel.select(self.slave, 0);
+        self.channel.issue(if self.lba48 { COMMAND_FLUSH_CACHE_EXT } else { COMMAND_FLUSH_CACHE });
+        self.channel.wait_for_completion().map(|_| ())
+    }
+}
+
+/// Called by the interrupt handler of the channel's IRQ.
+pub(crate) fn handle_interrupt(channel: usize) {
+    let channel = &CHANNELS[channel];
+    // reading the status acknowledges the interrupt at the drive
+    channel.read_register(REGISTER_STATUS);
+    channel.interrupted.store(true, Ordering::SeqCst);
+    channel.waiters.wake_all();
+}
+
+/// Registers the driver of IDE controllers. The drives it finds are named `ata<channel>.<drive>`
+/// and registered as block devices.
+pub fn init() {
+    pci::register_driver(&IDE_DRIVER);
+}
+
+fn probe(device: &PciDevice) -> bool {
+    // channels in native mode are configured through BARs, which is not supported
+    if device.prog_if & (PROG_IF_PRIMARY_NATIVE | PROG_IF_SECONDARY_NATIVE) != 0 {
+        return false;
+    }
+    for channel in CHANNELS.iter() {
+        channel.init_interrupts();
+        for &slave in [false, true].iter() {
+            if let Some(identity) = channel.identify(slave) {
+                block::register(Arc::new(AtaDrive {
+                    name: format!("ata{}.{}", channel.index, slave as u8),
+                    channel,
+                    slave,
+                    lba48: identity.lba48,
+                    sectors: identity.sectors,
+                    model: identity.model,
+                }));
+            }
+        }
+    }
+    true
+}
@@ -0,0 +1,196 @@
+//! Disks, as devices that read and write whole sectors.
+//!
+//! Drivers register the disks they find with `register`; file systems and the persistence
+//! code pick them from `devices`. `scan_partitions` registers the primary MBR partitions of
+//! the disks as devices of their own.
+
+use crate::sync::IrqMutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+
+/// Where the partition table starts in the first sector of a disk.
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_LENGTH: usize = 16;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+
+lazy_static! {
+    static ref DEVICES: IrqMutex<Vec<Arc<dyn BlockDevice>>> = IrqMutex::new("DEVICES", Vec::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the last sector.
+    OutOfRange,
+    /// The buffer length is not a multiple of the sector size.
+    BadBufferLength,
+    /// The device reported an error, with a driver specific code.
+    Device(u8),
+    /// The device did not complete the request in time.
+    Timeout,
+    /// The device does not accept writes.
+    ReadOnly,
+}
+
+/// A device storing fixed-size sectors.
+///
+/// Requests cover as many consecutive sectors as fit in the buffer. Methods take `&self`,
+/// drivers serialize requests internally, so a device can be shared between threads.
+pub trait BlockDevice: Send + Sync {
+    /// A short name identifying the device, like `ata0.1`.
+    fn name(&self) -> &str;
+
+    /// The size of a sector in bytes.
+    fn sector_size(&self) -> usize;
+
+    /// The number of sectors of the device.
+    fn sector_count(&self) -> u64;
+
+    /// Reads the sectors starting at `sector` into `buffer`.
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buffer` to the sectors starting at `sector`.
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// Waits until all written data reached the medium.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Checks that a request of `length` bytes starting at `sector` fits the device.
+///
+/// Returns the number of sectors it covers.
+pub fn check_request(device: &dyn BlockDevice, sector: u64, length: usize) -> Result<u64, BlockError> {
+    let sector_size = device.sector_size();
+    if length % sector_size != 0 {
+        return Err(BlockError::BadBufferLength);
+    }
+    let count = (length / sector_size) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Makes a device available to the rest of the kernel.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    DEVICES.lock().push(device);
+}
+
+/// Returns all registered devices, in the order they were found.
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.lock().clone()
+}
+
+/// Returns the device with the given name.
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|device| device.name() == name).cloned()
+}
+
+/// A range of sectors of a disk, like an MBR partition.
+pub struct Partition {
+    name: String,
+    device: Arc<dyn BlockDevice>,
+    start: u64,
+    sectors: u64,
+}
+
+impl Partition {
+    /// Creates the device for `sectors` sectors of `device` from `start` on. It is named
+    /// like the disk, followed by `p` and `number`.
+    pub fn new(device: Arc<dyn BlockDevice>, number: usize, start: u64, sectors: u64) -> Partition {
+        Partition {
+            name: format!("{}p{}", device.name(), number),
+            device,
+            start,
+            sectors,
+        }
+    }
+
+    /// The first sector of the partition on its disk.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        self.device.sector_size()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        self.device.read(self.start + sector, buffer)
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buffer.len())?;
+        self.device.write(self.start + sector, buffer)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
+
+/// Reads the MBR of a disk and returns its primary partitions, numbered from 1 by their
+/// slot in the table. Extended partitions are not followed.
+///
+/// A disk without a valid table, like one holding a file system from its first sector on,
+/// has no partitions.
+pub fn mbr_partitions(device: &Arc<dyn BlockDevice>) -> Result<Vec<Partition>, BlockError> {
+    let mut first = vec![0; device.sector_size()];
+    device.read(0, &mut first)?;
+    if first.len() < 512 || first[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    let mut partitions = Vec::new();
+    for slot in 0..4 {
+        let entry = &first[MBR_TABLE_OFFSET + slot * MBR_ENTRY_LENGTH..][..MBR_ENTRY_LENGTH];
+        let (status, kind) = (entry[0], entry[4]);
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&entry[8..12]);
+        let start = u64::from(u32::from_le_bytes(bytes));
+        bytes.copy_from_slice(&entry[12..16]);
+        let sectors = u64::from(u32::from_le_bytes(bytes));
+        if kind == 0 {
+            continue;
+        }
+        // boot code in the place of the table fails these checks
+        if status & 0x7f != 0 || start == 0 || sectors == 0 || start + sectors > device.sector_count() {
+            return Ok(Vec::new());
+        }
+        // extended partitions
+        if kind == 0x05 || kind == 0x0f {
+            continue;
+        }
+        partitions.push(Partition::new(device.clone(), slot + 1, start, sectors));
+    }
+    Ok(partitions)
+}
+
+/// Registers the primary partitions of all registered disks. Returns the number of new
+/// partitions; those registered by an earlier call and disks that cannot be read are
+/// skipped.
+pub fn scan_partitions() -> usize {
+    let mut found = 0;
+    for device in devices() {
+        for partition in mbr_partitions(&device).unwrap_or_default() {
+            if find(partition.name()).is_none() {
+                register(Arc::new(partition));
+                found += 1;
+            }
+        }
+    }
+    found
+}
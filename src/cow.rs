@@ -0,0 +1,173 @@
+use crate::memory::{self, FRAME_ALLOCATOR, MAPPER};
+use alloc::collections::BTreeMap;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    instructions::tlb,
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        PhysFrame,
+    },
+    VirtAddr,
+};
+
+/// Marks a read-only mapping that becomes a private writable copy on the first write.
+///
+/// This is one of the page table entry bits that the CPU ignores.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+lazy_static! {
+    /// Number of mappings of every shared frame, keyed by the frame's start address.
+    ///
+    /// Frames that are not in the map are owned by exactly one mapping.
+    static ref REFERENCE_COUNTS: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Debug)]
+pub enum CowError {
+    /// The source page is not mapped by a 4KiB page.
+    NotMapped,
+    /// The memory manager was not installed yet.
+    Uninitialized,
+    /// Mapping the destination page failed.
+    Map(MapToError),
+}
+
+/// Returns the number of mappings that share the given frame.
+pub fn reference_count(frame: PhysFrame) -> usize {
+    let key = frame.start_address().as_u64();
+    REFERENCE_COUNTS.lock().get(&key).cloned().unwrap_or(1)
+}
+
+/// Maps `dst` to the frame backing `src`, sharing the frame between both pages.
+///
+/// If `src` is writable, both mappings become read-only and copy-on-write, so that the first
+/// write through either page gives it a private copy of the frame.
+pub fn share_page(src: Page, dst: Page) -> Result<(), CowError> {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(CowError::Uninitialized),
+    };
+
+    let entry = unsafe { memory::page_table_entry(src) }.ok_or(CowError::NotMapped)?;
+    let mut flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        return Err(CowError::NotMapped);
+    }
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags.remove(PageTableFlags::WRITABLE);
+        flags.insert(COPY_ON_WRITE);
+        entry.set_flags(flags);
+        tlb::flush(src.start_address());
+    }
+    let frame = PhysFrame::containing_address(entry.addr());
+
+    unsafe {
+        mapper
+            .map_to(dst, frame, flags, frame_allocator)
+            .map_err(CowError::Map)?
+            .flush()
+    };
+
+    let mut counts = REFERENCE_COUNTS.lock();
+    let count = counts.entry(frame.start_address().as_u64()).or_insert(1);
+    *count += 1;
+    Ok(())
+}
+
+/// Unmaps the given page and frees its frame once no other mapping shares it.
+pub fn unmap_page(page: Page) -> Result<(), CowError> {
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(CowError::Uninitialized),
+    };
+
+    let (frame, flush) = mapper.unmap(page).map_err(|_| CowError::NotMapped)?;
+    flush.flush();
+    if release(frame) {
+        frame_allocator.deallocate_frame(frame);
+    }
+    Ok(())
+}
+
+/// Drops one reference to the frame and returns whether it was the last one.
+fn release(frame: PhysFrame) -> bool {
+    let key = frame.start_address().as_u64();
+    let mut counts = REFERENCE_COUNTS.lock();
+    match counts.get(&key).cloned() {
+        Some(count) if count > 2 => {
+            counts.insert(key, count - 1);
+            false
+        }
+        Some(_) => {
+            counts.remove(&key);
+            false
+        }
+        None => true,
+    }
+}
+
+/// Tries to resolve a write fault on a copy-on-write page.
+///
+/// If the frame is still shared, its content is copied into a new frame that is mapped
+/// writable in place of the shared one. If the faulting page holds the last reference, the
+/// frame is simply made writable again. Returns `true` if the faulting write can be retried.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let write_to_present_page =
+        PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if !error_code.contains(write_to_present_page) {
+        return false;
+    }
+    let (_mapper, mut frame_allocator, mut counts) = match (
+        MAPPER.try_lock(),
+        FRAME_ALLOCATOR.try_lock(),
+        REFERENCE_COUNTS.try_lock(),
+    ) {
+        (Some(mapper), Some(frame_allocator), Some(counts)) => (mapper, frame_allocator, counts),
+        _ => return false,
+    };
+    let frame_allocator = match frame_allocator.as_mut() {
+        Some(frame_allocator) => frame_allocator,
+        None => return false,
+    };
+
+    let page: Page = Page::containing_address(addr);
+    let entry = match unsafe { memory::page_table_entry(page) } {
+        Some(entry) => entry,
+        None => return false,
+    };
+    let mut flags = entry.flags();
+    if !flags.contains(COPY_ON_WRITE) {
+        return false;
+    }
+    flags.remove(COPY_ON_WRITE);
+    flags.insert(PageTableFlags::WRITABLE);
+
+    let frame: PhysFrame = PhysFrame::containing_address(entry.addr());
+    let key = frame.start_address().as_u64();
+    match counts.get(&key).cloned() {
+        Some(count) if count > 1 => {
+            let copy: PhysFrame = match frame_allocator.allocate_frame() {
+                Some(copy) => copy,
+                None => return false,
+            };
+            let src: *const u8 = memory::phys_to_virt(frame.start_address()).as_ptr();
+            let dst: *mut u8 = memory::phys_to_virt(copy.start_address()).as_mut_ptr();
+            unsafe { core::ptr::copy_nonoverlapping(src, dst, 4096) };
+            entry.set_addr(copy.start_address(), flags);
+            if count > 2 {
+                counts.insert(key, count - 1);
+            } else {
+                counts.remove(&key);
+            }
+        }
+        _ => entry.set_flags(flags),
+    }
+    tlb::flush(page.start_address());
+    true
+}
@@ -0,0 +1,135 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::PageTableFlags;
+
+/// CR4 bit that enables user-mode instruction prevention.
+const CR4_UMIP: u64 = 1 << 11;
+/// CR4 bit that enables supervisor-mode execution prevention.
+const CR4_SMEP: u64 = 1 << 20;
+/// CR4 bit that enables supervisor-mode access prevention.
+const CR4_SMAP: u64 = 1 << 21;
+
+/// Whether SMAP was enabled, which makes `stac` and `clac` valid instructions.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Whether `EFER.NXE` was set, which makes `NO_EXECUTE` a valid page table bit.
+static NO_EXECUTE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the `ebx` and `ecx` registers of the structured extended feature leaf.
+fn structured_features() -> (u32, u32) {
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf >= 7 {
+        let result = unsafe { __cpuid_count(7, 0) };
+        (result.ebx, result.ecx)
+    } else {
+        (0, 0)
+    }
+}
+
+/// Returns the `edx` register of the extended processor info leaf, or 0 if it is missing.
+fn extended_features_edx() -> u32 {
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf >= 0x8000_0001 {
+        unsafe { __cpuid(0x8000_0001) }.edx
+    } else {
+        0
+    }
+}
+
+/// Returns whether the CPU supports 1GiB pages.
+pub fn has_1gib_pages() -> bool {
+    extended_features_edx() & (1 << 26) != 0
+}
+
+/// Returns whether the CPU supports the no-execute page table bit.
+pub fn has_no_execute() -> bool {
+    extended_features_edx() & (1 << 20) != 0
+}
+
+/// Enables the no-execute page table bit by setting `EFER.NXE`, if the CPU supports it.
+///
+/// Without it, setting `NO_EXECUTE` in a page table entry is a reserved bit violation, so
+/// mappings take the bit from `no_execute`.
+pub fn enable_no_execute() {
+    if has_no_execute() {
+        unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+        NO_EXECUTE_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Returns `NO_EXECUTE` if `enable_no_execute` enabled it, and no flags otherwise.
+pub fn no_execute() -> PageTableFlags {
+    if NO_EXECUTE_ENABLED.load(Ordering::Relaxed) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/// Returns whether the CPU supports supervisor-mode execution prevention.
+pub fn has_smep() -> bool {
+    structured_features().0 & (1 << 7) != 0
+}
+
+/// Returns whether the CPU supports supervisor-mode access prevention.
+pub fn has_smap() -> bool {
+    structured_features().0 & (1 << 20) != 0
+}
+
+/// Returns whether the CPU supports user-mode instruction prevention.
+pub fn has_umip() -> bool {
+    structured_features().1 & (1 << 2) != 0
+}
+
+/// Returns whether SMAP was enabled by `enable_supervisor_protection`.
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Enables SMEP, SMAP and UMIP, each only if the CPU supports it.
+///
+/// Afterwards the kernel faults when it executes code on a user page, or accesses user
+/// memory outside of `stac`/`clac` brackets. User mode can no longer read the descriptor
+/// table registers.
+pub fn enable_supervisor_protection() {
+    let mut cr4 = unsafe { read_cr4() };
+    if has_smep() {
+        cr4 |= CR4_SMEP;
+    }
+    if has_smap() {
+        cr4 |= CR4_SMAP;
+    }
+    if has_umip() {
+        cr4 |= CR4_UMIP;
+    }
+    unsafe { write_cr4(cr4) };
+    SMAP_ENABLED.store(cr4 & CR4_SMAP != 0, Ordering::Relaxed);
+}
+
+/// Allows supervisor accesses to user pages by setting `RFLAGS.AC`.
+///
+/// This is a no-op if SMAP is not enabled.
+pub fn stac() {
+    if smap_enabled() {
+        unsafe { asm!("stac" :::: "volatile") };
+    }
+}
+
+/// Forbids supervisor accesses to user pages again by clearing `RFLAGS.AC`.
+///
+/// This is a no-op if SMAP is not enabled.
+pub fn clac() {
+    if smap_enabled() {
+        unsafe { asm!("clac" :::: "volatile") };
+    }
+}
+
+pub(crate) unsafe fn read_cr4() -> u64 {
+    let value: u64;
+    asm!("mov %cr4, $0" : "=r" (value));
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    asm!("mov $0, %cr4" :: "r" (value) : "memory");
+}
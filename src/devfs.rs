@@ -0,0 +1,241 @@
+//! The `/dev` namespace, a flat directory of device files.
+//!
+//! `init` adds the serial port, the VGA console, `null`, `zero` and the registered disks.
+//! Drivers may add more devices with `register`.
+
+use crate::block::{self, BlockDevice};
+use crate::serial::SERIAL1;
+use crate::sync::IrqMutex;
+use crate::vfs::{DirEntry, FileSystem, FileType, Inode, Metadata, VfsError};
+use crate::vga_buffer::WRITER;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    static ref DEVICES: IrqMutex<BTreeMap<String, Arc<dyn Inode>>> = IrqMutex::new("DEVFS", BTreeMap::new());
+}
+
+/// Adds a device file. Fails with `Exists` if the name is taken.
+pub fn register(name: &str, device: Arc<dyn Inode>) -> Result<(), VfsError> {
+    let mut devices = DEVICES.lock();
+    if devices.contains_key(name) {
+        return Err(VfsError::Exists);
+    }
+    devices.insert(String::from(name), device);
+    Ok(())
+}
+
+/// Adds the built-in devices and a file for every registered disk, named like the disk.
+pub fn init() {
+    let devices: [(&str, Arc<dyn Inode>); 4] = [
+        ("null", Arc::new(Null)),
+        ("zero", Arc::new(Zero)),
+        ("serial0", Arc::new(Serial)),
+        ("console", Arc::new(Console)),
+    ];
+    for (name, device) in devices.iter() {
+        // a second `init` keeps the files of the first
+        let _ = register(name, device.clone());
+    }
+    for disk in block::devices() {
+        let _ = register(disk.name(), Arc::new(Disk(disk.clone())));
+    }
+}
+
+/// The file system to mount at `/dev`.
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
+
+struct Root;
+
+impl Inode for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        DEVICES.lock().get(name).cloned().ok_or(VfsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Ok(DEVICES
+            .lock()
+            .iter()
+            .map(|(name, device)| DirEntry {
+                name: name.clone(),
+                file_type: device.metadata().file_type,
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+}
+
+fn char_device() -> Metadata {
+    Metadata {
+        file_type: FileType::CharDevice,
+        size: 0,
+    }
+}
+
+/// Discards writes and reads nothing.
+struct Null;
+
+impl Inode for Null {
+    fn metadata(&self) -> Metadata {
+        char_device()
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        Ok(buffer.len())
+    }
+}
+
+/// Discards writes and reads zeros.
+struct Zero;
+
+impl Inode for Zero {
+    fn metadata(&self) -> Metadata {
+        char_device()
+    }
+
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        for byte in buffer.iter_mut() {
+            *byte = 0;
+        }
+        Ok(buffer.len())
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        Ok(buffer.len())
+    }
+}
+
+/// The first serial port. Input is not buffered, so reads return nothing.
+struct Serial;
+
+impl Inode for Serial {
+    fn metadata(&self) -> Metadata {
+        char_device()
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        let mut serial = SERIAL1.lock();
+        for &byte in buffer {
+            serial.send(byte);
+        }
+        Ok(buffer.len())
+    }
+}
+
+/// The VGA text console, which only shows ASCII.
+struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        char_device()
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        WRITER.lock().write_ascii(buffer);
+        Ok(buffer.len())
+    }
+}
+
+/// A disk as a file of `sector_count * sector_size` bytes.
+///
+/// Unaligned requests are split into sectors; partial sectors are read before they are
+/// written.
+struct Disk(Arc<dyn BlockDevice>);
+
+impl Disk {
+    fn size(&self) -> u64 {
+        self.0.sector_count() * self.0.sector_size() as u64
+    }
+}
+
+impl Inode for Disk {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::BlockDevice,
+            size: self.size(),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let sector_size = self.0.sector_size() as u64;
+        let end = self.size().min(offset.saturating_add(buffer.len() as u64));
+        let mut sector = vec![0; sector_size as usize];
+        let mut position = offset;
+        while position < end {
+            let within = position % sector_size;
+            let count = (sector_size - within).min(end - position);
+            self.0.read(position / sector_size, &mut sector)?;
+            let done = (position - offset) as usize;
+            buffer[done..done + count as usize]
+                .copy_from_slice(&sector[within as usize..(within + count) as usize]);
+            position += count;
+        }
+        Ok(end.saturating_sub(offset) as usize)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        let sector_size = self.0.sector_size() as u64;
+        let end = offset.saturating_add(buffer.len() as u64);
+        if end > self.size() {
+            return Err(VfsError::NoSpace);
+        }
+        let mut sector = vec![0; sector_size as usize];
+        let mut position = offset;
+        while position < end {
+            let within = position % sector_size;
+            let count = (sector_size - within).min(end - position);
+            if count < sector_size {
+                self.0.read(position / sector_size, &mut sector)?;
+            }
+            let done = (position - offset) as usize;
+            sector[within as usize..(within + count) as usize]
+                .copy_from_slice(&buffer[done..done + count as usize]);
+            self.0.write(position / sector_size, &sector)?;
+            position += count;
+        }
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::Unsupported)
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        self.0.flush().map_err(VfsError::from)
+    }
+}
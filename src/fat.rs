@@ -0,0 +1,1023 @@
+//! FAT16 and FAT32 file systems on a block device.
+//!
+//! Long file names are read and written; short names are generated for names that are not
+//! valid 8.3 names. All operations of a volume are serialized by one lock, and inodes keep
+//! only the position of their directory entry, so two inodes of the same file never
+//! disagree about its size or clusters.
+
+use crate::block::{self, BlockDevice};
+use crate::sync::SleepMutex;
+use crate::vfs::{self, DirEntry, FileSystem, FileType, Inode, Metadata, VfsError};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// Volumes with fewer clusters are FAT12, which is not supported.
+const MIN_FAT16_CLUSTERS: u64 = 4085;
+const MIN_FAT32_CLUSTERS: u64 = 65525;
+
+const FAT16_END: u32 = 0xfff8;
+const FAT32_END: u32 = 0x0fff_fff8;
+const FAT32_MASK: u32 = 0x0fff_ffff;
+
+const FS_INFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FS_INFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FS_INFO_FREE_COUNT: usize = 488;
+const FS_INFO_UNKNOWN: u32 = 0xffff_ffff;
+
+const ENTRY_LENGTH: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xe5;
+/// Stored in the first byte of short names that start with 0xe5.
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// Set in byte 12 of short entries whose base name or extension is lower case.
+const LOWER_CASE_BASE: u8 = 0x08;
+const LOWER_CASE_EXTENSION: u8 = 0x10;
+
+const LONG_NAME_LAST: u8 = 0x40;
+const LONG_NAME_CHARS: usize = 13;
+const MAX_NAME_LENGTH: usize = 255;
+/// The offsets of the name characters in a long name entry.
+const LONG_NAME_OFFSETS: [usize; LONG_NAME_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// 1980-01-01, the earliest FAT date, for entries the kernel creates without a clock.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatKind {
+    Fat16,
+    Fat32,
+}
+
+/// A FAT volume that can be mounted.
+pub struct FatFs {
+    volume: Arc<Volume>,
+}
+
+struct Volume {
+    device: Arc<dyn BlockDevice>,
+    kind: FatKind,
+    label: String,
+    sector_size: usize,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    /// The fixed root directory of FAT16 volumes.
+    root_start: u64,
+    root_sectors: u64,
+    data_start: u64,
+    cluster_count: u32,
+    /// The first cluster of the root directory of FAT32 volumes.
+    root_cluster: u32,
+    fs_info: Option<u64>,
+    state: SleepMutex<State>,
+}
+
+/// What the lock of a volume protects besides its sectors.
+struct State {
+    /// Where the search for a free cluster starts.
+    next_free: u32,
+    /// Whether the free cluster count in the FSInfo sector was marked unknown.
+    fs_info_invalidated: bool,
+    /// The last sector read from the first FAT.
+    fat_cache: Option<(u64, Vec<u8>)>,
+}
+
+/// A directory: the fixed root of FAT16 volumes, or a cluster chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    FixedRoot,
+    Chain(u32),
+}
+
+/// Where a short directory entry is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct EntryPosition {
+    sector: u64,
+    offset: usize,
+}
+
+/// A file or directory read from a directory.
+struct DirItem {
+    /// The long name, or the short name if there is none.
+    name: String,
+    short_name: [u8; 11],
+    attributes: u8,
+    first_cluster: u32,
+    position: EntryPosition,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from(data[offset]) | u16::from(data[offset + 1]) << 8
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from(u16_at(data, offset)) | u32::from(u16_at(data, offset + 2)) << 16
+}
+
+fn put_u16_at(data: &mut [u8], offset: usize, value: u16) {
+    data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32_at(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// The checksum of a short name that long name entries store.
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(byte))
+}
+
+/// Formats a short name as `BASE.EXT`, applying the lower case flags of its entry.
+fn short_name_string(short_name: &[u8; 11], flags: u8) -> String {
+    let mut name = String::new();
+    let part = |bytes: &[u8], lower: bool, name: &mut String| {
+        let length = bytes.iter().rposition(|&byte| byte != b' ').map_or(0, |last| last + 1);
+        for (i, &byte) in bytes[..length].iter().enumerate() {
+            let byte = if i == 0 && byte == ENTRY_KANJI_E5 { ENTRY_DELETED } else { byte };
+            let character = char::from(byte);
+            name.push(if lower { character.to_ascii_lowercase() } else { character });
+        }
+    };
+    part(&short_name[..8], flags & LOWER_CASE_BASE != 0, &mut name);
+    if short_name[8..].iter().any(|&byte| byte != b' ') {
+        name.push('.');
+        part(&short_name[8..], flags & LOWER_CASE_EXTENSION != 0, &mut name);
+    }
+    name
+}
+
+/// Whether a byte may appear in a short name the kernel writes.
+fn is_short_name_byte(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || b"!#$%&'()-@^_`{}~".contains(&byte)
+}
+
+/// Returns the short name of a name that is a valid upper case 8.3 name.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let bytes = name.as_bytes();
+    let (base, extension) = match bytes.iter().position(|&byte| byte == b'.') {
+        Some(dot) => (&bytes[..dot], &bytes[dot + 1..]),
+        None => (bytes, &[][..]),
+    };
+    let valid = |part: &[u8]| part.iter().all(|&byte| is_short_name_byte(byte));
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || !valid(base) || !valid(extension) {
+        return None;
+    }
+    // a trailing dot is not part of a short name
+    if extension.is_empty() && bytes.len() != base.len() {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base);
+    short_name[8..8 + extension.len()].copy_from_slice(extension);
+    Some(short_name)
+}
+
+/// Derives a short name like `LONGNA~1.TXT` for a long name, unique among `taken`.
+fn generate_short_name(name: &str, taken: &[[u8; 11]]) -> Option<[u8; 11]> {
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|&character| character != ' ' && character != '.')
+            .map(|character| {
+                let byte = if character.is_ascii() { character.to_ascii_uppercase() as u8 } else { b'_' };
+                if is_short_name_byte(byte) { byte } else { b'_' }
+            })
+            .collect()
+    };
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (clean(&name[..dot]), clean(&name[dot + 1..])),
+        _ => (clean(name), Vec::new()),
+    };
+    let base = if base.is_empty() { vec![b'_'] } else { base };
+    let mut short_name = [b' '; 11];
+    let extension_length = extension.len().min(3);
+    short_name[8..8 + extension_length].copy_from_slice(&extension[..extension_length]);
+    for number in 1..1_000_000u32 {
+        let tail = format!("~{}", number);
+        let base_length = base.len().min(8 - tail.len());
+        short_name[..8].copy_from_slice(b"        ");
+        short_name[..base_length].copy_from_slice(&base[..base_length]);
+        short_name[base_length..base_length + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.contains(&short_name) {
+            return Some(short_name);
+        }
+    }
+    None
+}
+
+/// Checks a name for a new file or directory.
+fn check_name(name: &str) -> Result<(), VfsError> {
+    let invalid = |character: char| character < ' ' || "\"*/:<>?\\|".contains(character);
+    if name.is_empty() || name == "." || name == ".." || name.chars().any(invalid) {
+        return Err(VfsError::InvalidPath);
+    }
+    if name.encode_utf16().count() > MAX_NAME_LENGTH {
+        return Err(VfsError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// Builds the long name entries for a name, in the order they are stored: the last part
+/// of the name first.
+fn long_name_entries(name: &str, checksum: u8) -> Vec<[u8; ENTRY_LENGTH]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    // the name ends with a NUL, unless it fills the last entry, and is padded with 0xffff
+    if units.len() % LONG_NAME_CHARS != 0 {
+        units.push(0);
+    }
+    while units.len() % LONG_NAME_CHARS != 0 {
+        units.push(0xffff);
+    }
+    let count = units.len() / LONG_NAME_CHARS;
+    (1..=count)
+        .rev()
+        .map(|sequence| {
+            let mut entry = [0; ENTRY_LENGTH];
+            entry[0] = sequence as u8 | if sequence == count { LONG_NAME_LAST } else { 0 };
+            entry[11] = ATTR_LONG_NAME;
+            entry[13] = checksum;
+            let chunk = &units[(sequence - 1) * LONG_NAME_CHARS..sequence * LONG_NAME_CHARS];
+            for (&offset, &unit) in LONG_NAME_OFFSETS.iter().zip(chunk) {
+                put_u16_at(&mut entry, offset, unit);
+            }
+            entry
+        })
+        .collect()
+}
+
+fn short_entry(short_name: &[u8; 11], attributes: u8, first_cluster: u32) -> [u8; ENTRY_LENGTH] {
+    let mut entry = [0; ENTRY_LENGTH];
+    entry[..11].copy_from_slice(short_name);
+    entry[11] = attributes;
+    put_u16_at(&mut entry, 16, DEFAULT_DATE);
+    put_u16_at(&mut entry, 18, DEFAULT_DATE);
+    put_u16_at(&mut entry, 24, DEFAULT_DATE);
+    put_u16_at(&mut entry, 20, (first_cluster >> 16) as u16);
+    put_u16_at(&mut entry, 26, first_cluster as u16);
+    entry
+}
+
+/// Collects the long name entries that precede a short entry.
+#[derive(Default)]
+struct LongName {
+    /// The characters of the parts read so far, indexed by sequence number minus one.
+    parts: Vec<Option<[u16; LONG_NAME_CHARS]>>,
+    checksum: u8,
+}
+
+impl LongName {
+    fn add(&mut self, entry: &[u8]) {
+        let sequence = (entry[0] & 0x1f) as usize;
+        if entry[0] & LONG_NAME_LAST != 0 {
+            self.parts = vec![None; sequence];
+            self.checksum = entry[13];
+        }
+        if sequence == 0 || sequence > self.parts.len() || entry[13] != self.checksum {
+            self.parts.clear();
+            return;
+        }
+        let mut units = [0; LONG_NAME_CHARS];
+        for (unit, &offset) in units.iter_mut().zip(LONG_NAME_OFFSETS.iter()) {
+            *unit = u16_at(entry, offset);
+        }
+        self.parts[sequence - 1] = Some(units);
+    }
+
+    /// Returns the name if all parts belong to the given short name.
+    fn take(&mut self, short_name: &[u8; 11]) -> Option<String> {
+        let parts = core::mem::replace(&mut self.parts, Vec::new());
+        if parts.is_empty() || self.checksum != short_name_checksum(short_name) {
+            return None;
+        }
+        let mut units = Vec::with_capacity(parts.len() * LONG_NAME_CHARS);
+        for part in parts {
+            units.extend_from_slice(&part?);
+        }
+        let length = units.iter().position(|&unit| unit == 0).unwrap_or(units.len());
+        Some(
+            core::char::decode_utf16(units[..length].iter().cloned())
+                .map(|character| character.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                .collect(),
+        )
+    }
+}
+
+impl FatFs {
+    /// Reads the boot sector of a device holding a FAT16 or FAT32 file system.
+    ///
+    /// Fails with `Unsupported` for FAT12 and sector sizes other than the device's, and with
+    /// `Corrupted` if the boot sector is not one of a FAT file system.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<FatFs, VfsError> {
+        let sector_size = device.sector_size();
+        let mut boot = vec![0; sector_size];
+        device.read(0, &mut boot)?;
+        if boot.len() < 512 || boot[510..512] != BOOT_SIGNATURE {
+            return Err(VfsError::Corrupted);
+        }
+        let bytes_per_sector = u64::from(u16_at(&boot, 11));
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved = u64::from(u16_at(&boot, 14));
+        let fat_count = u64::from(boot[16]);
+        let root_entries = u64::from(u16_at(&boot, 17));
+        let total = match u16_at(&boot, 19) {
+            0 => u64::from(u32_at(&boot, 32)),
+            total => u64::from(total),
+        };
+        let fat_sectors = match u16_at(&boot, 22) {
+            0 => u64::from(u32_at(&boot, 36)),
+            sectors => u64::from(sectors),
+        };
+        if !sectors_per_cluster.is_power_of_two() || reserved == 0 || fat_count == 0 || fat_sectors == 0 {
+            return Err(VfsError::Corrupted);
+        }
+        if bytes_per_sector != sector_size as u64 {
+            return Err(VfsError::Unsupported);
+        }
+        let root_sectors = (root_entries * ENTRY_LENGTH as u64 + bytes_per_sector - 1) / bytes_per_sector;
+        let root_start = reserved + fat_count * fat_sectors;
+        let data_start = root_start + root_sectors;
+        if total > device.sector_count() || total <= data_start {
+            return Err(VfsError::Corrupted);
+        }
+        let clusters = (total - data_start) / sectors_per_cluster;
+        let kind = if clusters < MIN_FAT16_CLUSTERS {
+            return Err(VfsError::Unsupported);
+        } else if clusters < MIN_FAT32_CLUSTERS {
+            FatKind::Fat16
+        } else {
+            FatKind::Fat32
+        };
+        let entry_length = if kind == FatKind::Fat16 { 2 } else { 4 };
+        if fat_sectors * bytes_per_sector / entry_length < clusters + 2 {
+            return Err(VfsError::Corrupted);
+        }
+
+        let (label, root_cluster, fs_info) = match kind {
+            FatKind::Fat16 => (&boot[43..54], 0, None),
+            FatKind::Fat32 => {
+                let fs_info = match u16_at(&boot, 48) {
+                    0 | 0xffff => None,
+                    sector => Some(u64::from(sector)),
+                };
+                (&boot[71..82], u32_at(&boot, 44), fs_info)
+            }
+        };
+        if kind == FatKind::Fat32 && (root_cluster < 2 || u64::from(root_cluster) >= clusters + 2) {
+            return Err(VfsError::Corrupted);
+        }
+        let label = String::from_utf8_lossy(label).trim_end().into();
+        Ok(FatFs {
+            volume: Arc::new(Volume {
+                device,
+                kind,
+                label,
+                sector_size,
+                sectors_per_cluster,
+                fat_start: reserved,
+                fat_sectors,
+                fat_count,
+                root_start,
+                root_sectors,
+                data_start,
+                cluster_count: clusters as u32,
+                root_cluster,
+                fs_info,
+                state: SleepMutex::new("FAT volume", State {
+                    next_free: 2,
+                    fs_info_invalidated: false,
+                    fat_cache: None,
+                }),
+            }),
+        })
+    }
+
+    pub fn kind(&self) -> FatKind {
+        self.volume.kind
+    }
+
+    /// The volume label from the boot sector, without trailing spaces.
+    pub fn label(&self) -> &str {
+        &self.volume.label
+    }
+}
+
+/// Mounts every registered disk or partition holding a FAT16 or FAT32 file system at
+/// `/mnt/<device>`. Returns the number of new mounts.
+pub fn mount_all() -> usize {
+    let mut mounted = 0;
+    for device in block::devices() {
+        let path = format!("/mnt/{}", device.name());
+        if let Ok(file_system) = FatFs::new(device) {
+            if vfs::mount(&path, Arc::new(file_system)).is_ok() {
+                mounted += 1;
+            }
+        }
+    }
+    mounted
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &str {
+        match self.volume.kind {
+            FatKind::Fat16 => "fat16",
+            FatKind::Fat32 => "fat32",
+        }
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatDir {
+            volume: self.volume.clone(),
+            dir: self.volume.root_dir(),
+        })
+    }
+}
+
+impl Volume {
+    fn root_dir(&self) -> Dir {
+        match self.kind {
+            FatKind::Fat16 => Dir::FixedRoot,
+            FatKind::Fat32 => Dir::Chain(self.root_cluster),
+        }
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        self.sectors_per_cluster * self.sector_size as u64
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start + u64::from(cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.kind {
+            FatKind::Fat16 => 0xffff,
+            FatKind::Fat32 => FAT32_MASK,
+        }
+    }
+
+    fn is_end_of_chain(&self, entry: u32) -> bool {
+        match self.kind {
+            FatKind::Fat16 => entry >= FAT16_END,
+            FatKind::Fat32 => entry >= FAT32_END,
+        }
+    }
+
+    /// Returns the sector of the first FAT holding a cluster's entry, and the entry's offset
+    /// in it.
+    fn fat_position(&self, cluster: u32) -> (u64, usize) {
+        let entry_length = if self.kind == FatKind::Fat16 { 2 } else { 4 };
+        let offset = u64::from(cluster) * entry_length;
+        let sector_size = self.sector_size as u64;
+        (offset / sector_size, (offset % sector_size) as usize)
+    }
+
+    /// Loads a sector of the first FAT into the cache.
+    fn load_fat_sector<'a>(&self, state: &'a mut State, sector: u64) -> Result<&'a mut Vec<u8>, VfsError> {
+        let cached = match state.fat_cache {
+            Some((cached, _)) => cached == sector,
+            None => false,
+        };
+        if !cached {
+            let mut data = vec![0; self.sector_size];
+            self.device.read(self.fat_start + sector, &mut data)?;
+            state.fat_cache = Some((sector, data));
+        }
+        match state.fat_cache {
+            Some((_, ref mut data)) => Ok(data),
+            None => unreachable!(),
+        }
+    }
+
+    fn fat_entry(&self, state: &mut State, cluster: u32) -> Result<u32, VfsError> {
+        let (sector, offset) = self.fat_position(cluster);
+        let data = self.load_fat_sector(state, sector)?;
+        Ok(match self.kind {
+            FatKind::Fat16 => u32::from(u16_at(data, offset)),
+            FatKind::Fat32 => u32_at(data, offset) & FAT32_MASK,
+        })
+    }
+
+    /// Sets a cluster's entry in every FAT.
+    fn set_fat_entry(&self, state: &mut State, cluster: u32, value: u32) -> Result<(), VfsError> {
+        let (sector, offset) = self.fat_position(cluster);
+        let kind = self.kind;
+        let data = self.load_fat_sector(state, sector)?;
+        match kind {
+            FatKind::Fat16 => put_u16_at(data, offset, value as u16),
+            FatKind::Fat32 => {
+                // the upper four bits are reserved and kept
+                let reserved = u32_at(data, offset) & !FAT32_MASK;
+                put_u32_at(data, offset, reserved | (value & FAT32_MASK));
+            }
+        }
+        for copy in 0..self.fat_count {
+            self.device.write(self.fat_start + copy * self.fat_sectors + sector, data)?;
+        }
+        self.invalidate_fs_info(state)
+    }
+
+    /// Marks the free cluster count of FAT32 volumes unknown before clusters are allocated
+    /// or freed, so it is never stale.
+    fn invalidate_fs_info(&self, state: &mut State) -> Result<(), VfsError> {
+        let sector = match self.fs_info {
+            Some(sector) if !state.fs_info_invalidated => sector,
+            _ => return Ok(()),
+        };
+        state.fs_info_invalidated = true;
+        let mut data = vec![0; self.sector_size];
+        self.device.read(sector, &mut data)?;
+        if u32_at(&data, 0) != FS_INFO_LEAD_SIGNATURE || u32_at(&data, 484) != FS_INFO_STRUCT_SIGNATURE {
+            return Ok(());
+        }
+        put_u32_at(&mut data, FS_INFO_FREE_COUNT, FS_INFO_UNKNOWN);
+        self.device.write(sector, &data)?;
+        Ok(())
+    }
+
+    /// Returns the clusters of the chain starting at `first`, which is empty for zero.
+    fn chain(&self, state: &mut State, first: u32) -> Result<Vec<u32>, VfsError> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        if cluster == 0 {
+            return Ok(chain);
+        }
+        loop {
+            // a loop in the chain would make it longer than the volume
+            if !self.is_cluster(cluster) || chain.len() >= self.cluster_count as usize {
+                return Err(VfsError::Corrupted);
+            }
+            chain.push(cluster);
+            let next = self.fat_entry(state, cluster)?;
+            if self.is_end_of_chain(next) {
+                return Ok(chain);
+            }
+            cluster = next;
+        }
+    }
+
+    /// Allocates a zeroed cluster and appends it to the chain ending with `last`.
+    fn allocate(&self, state: &mut State, last: Option<u32>) -> Result<u32, VfsError> {
+        let start = state.next_free;
+        let mut cluster = start;
+        loop {
+            if !self.is_cluster(cluster) {
+                cluster = 2;
+            }
+            if self.fat_entry(state, cluster)? == 0 {
+                break;
+            }
+            cluster += 1;
+            if cluster == start {
+                return Err(VfsError::NoSpace);
+            }
+        }
+        let zeros = vec![0; self.cluster_bytes() as usize];
+        self.device.write(self.cluster_sector(cluster), &zeros)?;
+        self.set_fat_entry(state, cluster, self.end_of_chain())?;
+        if let Some(last) = last {
+            self.set_fat_entry(state, last, cluster)?;
+        }
+        state.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    /// Appends clusters to a chain until it has `length` of them.
+    fn extend(&self, state: &mut State, chain: &mut Vec<u32>, length: usize) -> Result<(), VfsError> {
+        while chain.len() < length {
+            let cluster = self.allocate(state, chain.last().cloned())?;
+            chain.push(cluster);
+        }
+        Ok(())
+    }
+
+    /// Returns the sectors of a directory.
+    fn dir_sectors(&self, state: &mut State, dir: Dir) -> Result<Vec<u64>, VfsError> {
+        Ok(match dir {
+            Dir::FixedRoot => (self.root_start..self.root_start + self.root_sectors).collect(),
+            Dir::Chain(first) => self
+                .chain(state, first)?
+                .into_iter()
+                .flat_map(|cluster| {
+                    let sector = self.cluster_sector(cluster);
+                    sector..sector + self.sectors_per_cluster
+                })
+                .collect(),
+        })
+    }
+
+    /// Reads the files and directories of a directory, without `.`, `..` and the label.
+    fn read_dir(&self, state: &mut State, dir: Dir) -> Result<Vec<DirItem>, VfsError> {
+        let mut items = Vec::new();
+        let mut long_name = LongName::default();
+        let mut data = vec![0; self.sector_size];
+        for sector in self.dir_sectors(state, dir)? {
+            self.device.read(sector, &mut data)?;
+            for (index, entry) in data.chunks(ENTRY_LENGTH).enumerate() {
+                match entry[0] {
+                    ENTRY_END => return Ok(items),
+                    ENTRY_DELETED => {
+                        long_name.parts.clear();
+                        continue;
+                    }
+                    _ => {}
+                }
+                let attributes = entry[11];
+                if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                    long_name.add(entry);
+                    continue;
+                }
+                let mut short_name = [0; 11];
+                short_name.copy_from_slice(&entry[..11]);
+                let name = long_name.take(&short_name);
+                if attributes & ATTR_VOLUME_ID != 0 || short_name[0] == b'.' {
+                    continue;
+                }
+                items.push(DirItem {
+                    name: name.unwrap_or_else(|| short_name_string(&short_name, entry[12])),
+                    short_name,
+                    attributes,
+                    first_cluster: u32::from(u16_at(entry, 20)) << 16 | u32::from(u16_at(entry, 26)),
+                    position: EntryPosition {
+                        sector,
+                        offset: index * ENTRY_LENGTH,
+                    },
+                });
+            }
+        }
+        Ok(items)
+    }
+
+    /// Finds a name in a directory. Names are compared ignoring ASCII case, and short names
+    /// match as well as long ones.
+    fn find(&self, state: &mut State, dir: Dir, name: &str) -> Result<DirItem, VfsError> {
+        self.read_dir(state, dir)?
+            .into_iter()
+            .find(|item| {
+                item.name.eq_ignore_ascii_case(name)
+                    || short_name_string(&item.short_name, 0).eq_ignore_ascii_case(name)
+            })
+            .ok_or(VfsError::NotFound)
+    }
+
+    /// Adds the entries for a new name to a directory, growing it if needed. Returns the
+    /// position of the short entry.
+    fn add_entry(&self, state: &mut State, dir: Dir, name: &str, attributes: u8, first_cluster: u32) -> Result<EntryPosition, VfsError> {
+        check_name(name)?;
+        let items = self.read_dir(state, dir)?;
+        if items.iter().any(|item| item.name.eq_ignore_ascii_case(name)) {
+            return Err(VfsError::Exists);
+        }
+        let mut entries = Vec::new();
+        let short_name = match exact_short_name(name) {
+            Some(short_name) if !items.iter().any(|item| item.short_name == short_name) => short_name,
+            _ => {
+                let taken: Vec<[u8; 11]> = items.iter().map(|item| item.short_name).collect();
+                let short_name = generate_short_name(name, &taken).ok_or(VfsError::Exists)?;
+                entries = long_name_entries(name, short_name_checksum(&short_name));
+                short_name
+            }
+        };
+        entries.push(short_entry(&short_name, attributes, first_cluster));
+
+        let slots = self.free_slots(state, dir, entries.len())?;
+        let mut data = vec![0; self.sector_size];
+        for (entry, slot) in entries.iter().zip(slots.iter()) {
+            self.device.read(slot.sector, &mut data)?;
+            data[slot.offset..slot.offset + ENTRY_LENGTH].copy_from_slice(entry);
+            self.device.write(slot.sector, &data)?;
+        }
+        Ok(slots[slots.len() - 1])
+    }
+
+    /// Finds `count` consecutive unused entries in a directory, growing it if needed.
+    fn free_slots(&self, state: &mut State, dir: Dir, count: usize) -> Result<Vec<EntryPosition>, VfsError> {
+        let mut run = Vec::with_capacity(count);
+        let mut data = vec![0; self.sector_size];
+        let mut searched = 0;
+        loop {
+            let sectors = self.dir_sectors(state, dir)?;
+            for &sector in sectors[searched..].iter() {
+                self.device.read(sector, &mut data)?;
+                for offset in (0..self.sector_size).step_by(ENTRY_LENGTH) {
+                    if data[offset] == ENTRY_END || data[offset] == ENTRY_DELETED {
+                        run.push(EntryPosition { sector, offset });
+                        if run.len() == count {
+                            return Ok(run);
+                        }
+                    } else {
+                        run.clear();
+                    }
+                }
+            }
+            searched = sectors.len();
+            match dir {
+                Dir::FixedRoot => return Err(VfsError::NoSpace),
+                Dir::Chain(first) => {
+                    let mut chain = self.chain(state, first)?;
+                    let length = chain.len() + 1;
+                    self.extend(state, &mut chain, length)?;
+                }
+            }
+        }
+    }
+
+    /// Reads the first cluster and size from a short entry.
+    fn read_entry(&self, position: EntryPosition) -> Result<(u32, u64), VfsError> {
+        let mut data = vec![0; self.sector_size];
+        self.device.read(position.sector, &mut data)?;
+        let entry = &data[position.offset..position.offset + ENTRY_LENGTH];
+        let first_cluster = u32::from(u16_at(entry, 20)) << 16 | u32::from(u16_at(entry, 26));
+        Ok((first_cluster, u64::from(u32_at(entry, 28))))
+    }
+
+    fn write_entry(&self, position: EntryPosition, first_cluster: u32, size: u64) -> Result<(), VfsError> {
+        let mut data = vec![0; self.sector_size];
+        self.device.read(position.sector, &mut data)?;
+        let entry = &mut data[position.offset..position.offset + ENTRY_LENGTH];
+        put_u16_at(entry, 20, (first_cluster >> 16) as u16);
+        put_u16_at(entry, 26, first_cluster as u16);
+        put_u32_at(entry, 28, size as u32);
+        self.device.write(position.sector, &data)?;
+        Ok(())
+    }
+
+    /// The sector holding a byte of a file, and the byte's offset in it.
+    fn data_position(&self, chain: &[u32], position: u64) -> (u64, usize) {
+        let cluster = chain[(position / self.cluster_bytes()) as usize];
+        let within = position % self.cluster_bytes();
+        let sector_size = self.sector_size as u64;
+        (self.cluster_sector(cluster) + within / sector_size, (within % sector_size) as usize)
+    }
+
+    fn read_data(&self, chain: &[u32], offset: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        let mut data = vec![0; self.sector_size];
+        let mut done = 0;
+        while done < buffer.len() {
+            let (sector, within) = self.data_position(chain, offset + done as u64);
+            let count = (self.sector_size - within).min(buffer.len() - done);
+            self.device.read(sector, &mut data)?;
+            buffer[done..done + count].copy_from_slice(&data[within..within + count]);
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn write_data(&self, chain: &[u32], offset: u64, buffer: &[u8]) -> Result<(), VfsError> {
+        let mut data = vec![0; self.sector_size];
+        let mut done = 0;
+        while done < buffer.len() {
+            let (sector, within) = self.data_position(chain, offset + done as u64);
+            let count = (self.sector_size - within).min(buffer.len() - done);
+            if count < self.sector_size {
+                self.device.read(sector, &mut data)?;
+            }
+            data[within..within + count].copy_from_slice(&buffer[done..done + count]);
+            self.device.write(sector, &data)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// The number of clusters holding `size` bytes.
+    fn clusters_for(&self, size: u64) -> usize {
+        ((size + self.cluster_bytes() - 1) / self.cluster_bytes()) as usize
+    }
+
+    /// Writes to a file, filling a gap after its end with zeros.
+    fn write_file(&self, state: &mut State, position: EntryPosition, offset: u64, buffer: &[u8]) -> Result<(), VfsError> {
+        let (first, size) = self.read_entry(position)?;
+        let end = offset + buffer.len() as u64;
+        if end > u64::from(u32::max_value()) {
+            return Err(VfsError::NoSpace);
+        }
+        let mut chain = self.chain(state, first)?;
+        self.extend(state, &mut chain, self.clusters_for(end))?;
+        let first = chain.first().cloned().unwrap_or(0);
+        if offset > size {
+            self.write_data(&chain, size, &vec![0; (offset - size) as usize])?;
+        }
+        self.write_data(&chain, offset, buffer)?;
+        self.write_entry(position, first, size.max(end))
+    }
+
+    fn truncate_file(&self, state: &mut State, position: EntryPosition, length: u64) -> Result<(), VfsError> {
+        let (first, size) = self.read_entry(position)?;
+        if length > size {
+            return self.write_file(state, position, length, &[]);
+        }
+        let chain = self.chain(state, first)?;
+        let keep = self.clusters_for(length);
+        if keep < chain.len() {
+            if keep > 0 {
+                let end = self.end_of_chain();
+                self.set_fat_entry(state, chain[keep - 1], end)?;
+            }
+            for &cluster in chain[keep..].iter() {
+                self.set_fat_entry(state, cluster, 0)?;
+            }
+        }
+        let first = if keep == 0 { 0 } else { first };
+        self.write_entry(position, first, length)
+    }
+
+    /// Creates a directory with its `.` and `..` entries.
+    fn create_dir(&self, state: &mut State, parent: Dir, name: &str) -> Result<u32, VfsError> {
+        let cluster = self.allocate(state, None)?;
+        let parent_cluster = match parent {
+            // `..` of directories in the root is zero, even on FAT32
+            Dir::Chain(cluster) if cluster != self.root_cluster => cluster,
+            _ => 0,
+        };
+        let mut data = vec![0; self.sector_size];
+        data[..ENTRY_LENGTH].copy_from_slice(&short_entry(b".          ", ATTR_DIRECTORY, cluster));
+        data[ENTRY_LENGTH..2 * ENTRY_LENGTH].copy_from_slice(&short_entry(b"..         ", ATTR_DIRECTORY, parent_cluster));
+        self.device.write(self.cluster_sector(cluster), &data)?;
+        if let Err(error) = self.add_entry(state, parent, name, ATTR_DIRECTORY, cluster) {
+            self.set_fat_entry(state, cluster, 0)?;
+            return Err(error);
+        }
+        Ok(cluster)
+    }
+}
+
+struct FatDir {
+    volume: Arc<Volume>,
+    dir: Dir,
+}
+
+struct FatFile {
+    volume: Arc<Volume>,
+    position: EntryPosition,
+}
+
+impl FatDir {
+    fn child(&self, item: &DirItem) -> Result<Arc<dyn Inode>, VfsError> {
+        if item.attributes & ATTR_DIRECTORY == 0 {
+            return Ok(Arc::new(FatFile {
+                volume: self.volume.clone(),
+                position: item.position,
+            }));
+        }
+        if !self.volume.is_cluster(item.first_cluster) {
+            return Err(VfsError::Corrupted);
+        }
+        Ok(Arc::new(FatDir {
+            volume: self.volume.clone(),
+            dir: Dir::Chain(item.first_cluster),
+        }))
+    }
+}
+
+impl Inode for FatDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let mut state = self.volume.state.lock();
+        let item = self.volume.find(&mut state, self.dir, name)?;
+        self.child(&item)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let mut state = self.volume.state.lock();
+        Ok(self
+            .volume
+            .read_dir(&mut state, self.dir)?
+            .into_iter()
+            .map(|item| DirEntry {
+                name: item.name,
+                file_type: if item.attributes & ATTR_DIRECTORY != 0 {
+                    FileType::Directory
+                } else {
+                    FileType::File
+                },
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        let volume = &self.volume;
+        let mut state = volume.state.lock();
+        match file_type {
+            FileType::File => {
+                let position = volume.add_entry(&mut state, self.dir, name, ATTR_ARCHIVE, 0)?;
+                Ok(Arc::new(FatFile {
+                    volume: volume.clone(),
+                    position,
+                }))
+            }
+            FileType::Directory => {
+                let cluster = volume.create_dir(&mut state, self.dir, name)?;
+                Ok(Arc::new(FatDir {
+                    volume: volume.clone(),
+                    dir: Dir::Chain(cluster),
+                }))
+            }
+            FileType::CharDevice | FileType::BlockDevice => Err(VfsError::Unsupported),
+        }
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        self.volume.device.flush().map_err(VfsError::from)
+    }
+}
+
+impl Inode for FatFile {
+    fn metadata(&self) -> Metadata {
+        let _state = self.volume.state.lock();
+        // an unreadable entry shows as an empty file, reading it reports the error
+        let size = self.volume.read_entry(self.position).map_or(0, |(_, size)| size);
+        Metadata {
+            file_type: FileType::File,
+            size,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let mut state = self.volume.state.lock();
+        let (first, size) = self.volume.read_entry(self.position)?;
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((size - offset) as usize);
+        let chain = self.volume.chain(&mut state, first)?;
+        if chain.len() < self.volume.clusters_for(size) {
+            return Err(VfsError::Corrupted);
+        }
+        self.volume.read_data(&chain, offset, &mut buffer[..count])?;
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        let mut state = self.volume.state.lock();
+        self.volume.write_file(&mut state, self.position, offset, buffer)?;
+        Ok(buffer.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), VfsError> {
+        let mut state = self.volume.state.lock();
+        self.volume.truncate_file(&mut state, self.position, size)
+    }
+
+    fn sync(&self) -> Result<(), VfsError> {
+        self.volume.device.flush().map_err(VfsError::from)
+    }
+}
+
+#[test_case]
+fn test_short_names() {
+    serial_print!("test_short_names... ");
+    assert_eq!(exact_short_name("README.TXT"), Some(*b"README  TXT"));
+    assert_eq!(exact_short_name("KERNEL"), Some(*b"KERNEL     "));
+    assert_eq!(exact_short_name("readme.txt"), None);
+    assert_eq!(exact_short_name("A.B.C"), None);
+    assert_eq!(exact_short_name("TOOLONGNAME"), None);
+    assert_eq!(generate_short_name("A long file name.txt", &[]), Some(*b"ALONGF~1TXT"));
+    assert_eq!(generate_short_name("A long file name.txt", &[*b"ALONGF~1TXT"]), Some(*b"ALONGF~2TXT"));
+    assert_eq!(short_name_string(b"README  TXT", LOWER_CASE_BASE), "readme.TXT");
+    assert_eq!(short_name_checksum(b"ALONGF~1TXT"), 0x02);
+    assert_eq!(short_name_checksum(b"README  TXT"), 0x73);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_long_name_entries() {
+    serial_print!("test_long_name_entries... ");
+    let short_name = *b"ALONGF~1TXT";
+    let name = "A long file name.txt";
+    let entries = long_name_entries(name, short_name_checksum(&short_name));
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0][0], 2 | LONG_NAME_LAST);
+    assert_eq!(entries[1][0], 1);
+    let mut long_name = LongName::default();
+    for entry in entries.iter() {
+        long_name.add(entry);
+    }
+    assert_eq!(long_name.take(&short_name), Some(String::from(name)));
+    for entry in entries.iter() {
+        long_name.add(entry);
+    }
+    assert_eq!(long_name.take(b"OTHER   TXT"), None);
+    serial_println!("[ok]");
+}
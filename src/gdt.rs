@@ -1,3 +1,4 @@
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -5,11 +6,13 @@ use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096;
+
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096;
+            const STACK_SIZE: usize = DOUBLE_FAULT_STACK_SIZE;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
@@ -21,18 +24,20 @@ lazy_static! {
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = new_gdt(&TSS);
+}
+
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            tss_selector,
+        },
+    )
 }
 
 struct Selectors {
@@ -41,12 +46,30 @@ struct Selectors {
 }
 
 pub fn init() {
+    load(&GDT);
+}
+
+/// Loads a GDT with its own TSS and double fault stack on an application processor.
+///
+/// Every processor needs its own TSS, since loading a TSS marks it busy. The tables are
+/// leaked, as processors are never shut down. Requires the heap.
+pub fn init_ap() {
+    let stack = Box::leak(vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+    let gdt: &'static (GlobalDescriptorTable, Selectors) = Box::leak(Box::new(new_gdt(tss)));
+    load(gdt);
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        set_cs(gdt.1.code_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
@@ -0,0 +1,391 @@
+//! The initial ramdisk, a read-only file system available without any disk driver.
+//!
+//! `build.rs` packs the `initrd/` directory into a ustar archive that is linked into the
+//! kernel image. It is parsed on first use into a tree of directories whose files point
+//! into the archive, so reading a file copies nothing. `InitrdFs` mounts it in the VFS.
+
+use crate::vfs::{DirEntry, FileSystem, FileType, Inode, Metadata, VfsError};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+const BLOCK_SIZE: usize = 512;
+
+const TYPE_FILE: u8 = b'0';
+/// Used for regular files by tar implementations older than POSIX.
+const TYPE_OLD_FILE: u8 = 0;
+const TYPE_DIRECTORY: u8 = b'5';
+
+lazy_static! {
+    static ref RAMDISK: Result<Ramdisk, InitrdError> = Ramdisk::parse(ARCHIVE);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitrdError {
+    /// The archive ends inside a header or file, at the given offset.
+    Truncated(usize),
+    /// The header at the given offset has a wrong checksum.
+    BadChecksum(usize),
+    /// A numeric field of the header at the given offset is not an octal number.
+    BadNumber(usize),
+    /// The path names nothing, or one of its parents is a file.
+    NotFound,
+    /// The path names a directory where a file was expected.
+    NotAFile,
+    /// The path names a file where a directory was expected.
+    NotADirectory,
+}
+
+/// A file or directory of the ramdisk.
+#[derive(Debug)]
+pub enum Node {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, Node>),
+}
+
+/// A tree of files parsed from a ustar archive.
+#[derive(Debug)]
+pub struct Ramdisk {
+    root: BTreeMap<String, Node>,
+}
+
+/// Splits a path into its names, ignoring empty names and `.`. Paths are relative to the
+/// root of the ramdisk, with or without a leading `/`.
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty() && *name != ".")
+}
+
+/// Reads a NUL- or space-terminated octal number.
+fn octal(field: &[u8]) -> Option<u64> {
+    let mut value: u64 = 0;
+    let mut digits = field
+        .iter()
+        .skip_while(|&&byte| byte == b' ')
+        .take_while(|&&byte| byte != 0 && byte != b' ')
+        .peekable();
+    digits.peek()?;
+    for &digit in digits {
+        if !(b'0'..=b'7').contains(&digit) {
+            return None;
+        }
+        value = value.checked_mul(8)?.checked_add(u64::from(digit - b'0'))?;
+    }
+    Some(value)
+}
+
+/// Reads a NUL-terminated string field.
+fn string(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+fn checksum_matches(header: &[u8], offset: usize) -> Result<(), InitrdError> {
+    let expected = octal(&header[148..156]).ok_or(InitrdError::BadNumber(offset))?;
+    // the checksum field itself counts as spaces
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &byte)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(byte) })
+        .sum();
+    if sum == expected {
+        Ok(())
+    } else {
+        Err(InitrdError::BadChecksum(offset))
+    }
+}
+
+impl Ramdisk {
+    /// Parses a ustar archive. Entries other than files and directories, like links, are
+    /// skipped; missing parent directories are created.
+    pub fn parse(archive: &'static [u8]) -> Result<Ramdisk, InitrdError> {
+        let mut ramdisk = Ramdisk {
+            root: BTreeMap::new(),
+        };
+        let mut offset = 0;
+        while offset < archive.len() {
+            let header = archive
+                .get(offset..offset + BLOCK_SIZE)
+                .ok_or(InitrdError::Truncated(offset))?;
+            // the archive ends with zero blocks
+            if header.iter().all(|&byte| byte == 0) {
+                break;
+            }
+            checksum_matches(header, offset)?;
+            let size = octal(&header[124..136]).ok_or(InitrdError::BadNumber(offset))? as usize;
+            let start = offset + BLOCK_SIZE;
+            let data = archive
+                .get(start..start + size)
+                .ok_or(InitrdError::Truncated(offset))?;
+
+            let mut path = Vec::new();
+            if &header[257..262] == b"ustar" {
+                path.extend_from_slice(string(&header[345..500]));
+                if !path.is_empty() {
+                    path.push(b'/');
+                }
+            }
+            path.extend_from_slice(string(&header[..100]));
+            // names that are not UTF-8 cannot be looked up, so they are skipped
+            if let Ok(path) = core::str::from_utf8(&path) {
+                match header[156] {
+                    TYPE_FILE | TYPE_OLD_FILE => ramdisk.insert(path, Node::File(data)),
+                    TYPE_DIRECTORY => ramdisk.insert(path, Node::Directory(BTreeMap::new())),
+                    _ => {}
+                }
+            }
+            offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+        }
+        Ok(ramdisk)
+    }
+
+    /// Adds a node, creating its parents. A later entry replaces an earlier file of the
+    /// same name, like it does when extracting the archive.
+    fn insert(&mut self, path: &str, node: Node) {
+        let names: Vec<&str> = components(path).collect();
+        let (last, parents) = match names.split_last() {
+            Some(split) => split,
+            None => return,
+        };
+        let mut directory = &mut self.root;
+        for &name in parents {
+            let entry = directory
+                .entry(String::from(name))
+                .or_insert_with(|| Node::Directory(BTreeMap::new()));
+            if let Node::File(_) = entry {
+                *entry = Node::Directory(BTreeMap::new());
+            }
+            directory = match entry {
+                Node::Directory(children) => children,
+                Node::File(_) => unreachable!(),
+            };
+        }
+        // an explicit directory entry must not drop the files already inside
+        if let (Some(Node::Directory(_)), Node::Directory(_)) = (directory.get(*last), &node) {
+            return;
+        }
+        directory.insert(String::from(*last), node);
+    }
+
+    /// Returns the node at a path. The empty path and `/` name the root directory, which
+    /// is not a `Node`, so they return `None`.
+    pub fn lookup(&self, path: &str) -> Option<&Node> {
+        let mut names = components(path);
+        let mut node = self.root.get(names.next()?)?;
+        for name in names {
+            node = match node {
+                Node::Directory(children) => children.get(name)?,
+                Node::File(_) => return None,
+            };
+        }
+        Some(node)
+    }
+
+    /// Returns the contents of a file.
+    pub fn read(&self, path: &str) -> Result<&'static [u8], InitrdError> {
+        match self.lookup(path) {
+            Some(Node::File(data)) => Ok(*data),
+            Some(Node::Directory(_)) => Err(InitrdError::NotAFile),
+            None if components(path).next().is_none() => Err(InitrdError::NotAFile),
+            None => Err(InitrdError::NotFound),
+        }
+    }
+
+    /// Returns the names in a directory, in byte order.
+    pub fn list(&self, path: &str) -> Result<Vec<&str>, InitrdError> {
+        let children = if components(path).next().is_none() {
+            &self.root
+        } else {
+            match self.lookup(path) {
+                Some(Node::Directory(children)) => children,
+                Some(Node::File(_)) => return Err(InitrdError::NotADirectory),
+                None => return Err(InitrdError::NotFound),
+            }
+        };
+        Ok(children.keys().map(|name| name.as_str()).collect())
+    }
+
+    /// The number of files, not counting directories.
+    pub fn file_count(&self) -> usize {
+        fn count(directory: &BTreeMap<String, Node>) -> usize {
+            directory
+                .values()
+                .map(|node| match node {
+                    Node::File(_) => 1,
+                    Node::Directory(children) => count(children),
+                })
+                .sum()
+        }
+        count(&self.root)
+    }
+}
+
+/// Parses the ramdisk linked into the kernel. Returns its number of files.
+pub fn init() -> Result<usize, InitrdError> {
+    ramdisk().map(Ramdisk::file_count)
+}
+
+/// Returns the ramdisk linked into the kernel.
+pub fn ramdisk() -> Result<&'static Ramdisk, InitrdError> {
+    RAMDISK.as_ref().map_err(|error| *error)
+}
+
+/// Returns the contents of a file of the ramdisk linked into the kernel.
+pub fn read(path: &str) -> Result<&'static [u8], InitrdError> {
+    ramdisk()?.read(path)
+}
+
+/// The ramdisk linked into the kernel, as a file system.
+pub struct InitrdFs(&'static Ramdisk);
+
+impl InitrdFs {
+    pub fn new() -> Result<InitrdFs, InitrdError> {
+        ramdisk().map(InitrdFs)
+    }
+}
+
+impl FileSystem for InitrdFs {
+    fn name(&self) -> &str {
+        "initrd"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let ramdisk: &'static Ramdisk = self.0;
+        Arc::new(InitrdInode::Directory(&ramdisk.root))
+    }
+}
+
+enum InitrdInode {
+    File(&'static [u8]),
+    Directory(&'static BTreeMap<String, Node>),
+}
+
+impl InitrdInode {
+    fn of(node: &'static Node) -> InitrdInode {
+        match node {
+            Node::File(data) => InitrdInode::File(*data),
+            Node::Directory(children) => InitrdInode::Directory(children),
+        }
+    }
+}
+
+impl Inode for InitrdInode {
+    fn metadata(&self) -> Metadata {
+        match self {
+            InitrdInode::File(data) => Metadata {
+                file_type: FileType::File,
+                size: data.len() as u64,
+            },
+            InitrdInode::Directory(_) => Metadata {
+                file_type: FileType::Directory,
+                size: 0,
+            },
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        match *self {
+            InitrdInode::Directory(children) => {
+                let node = children.get(name).ok_or(VfsError::NotFound)?;
+                Ok(Arc::new(InitrdInode::of(node)))
+            }
+            InitrdInode::File(_) => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        match self {
+            InitrdInode::Directory(children) => Ok(children
+                .iter()
+                .map(|(name, node)| DirEntry {
+                    name: name.clone(),
+                    file_type: match node {
+                        Node::File(_) => FileType::File,
+                        Node::Directory(_) => FileType::Directory,
+                    },
+                })
+                .collect()),
+            InitrdInode::File(_) => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        match self {
+            InitrdInode::Directory(_) => Err(VfsError::ReadOnly),
+            InitrdInode::File(_) => Err(VfsError::NotADirectory),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        match self {
+            InitrdInode::File(data) => {
+                let start = data.len().min(offset as usize);
+                let count = buffer.len().min(data.len() - start);
+                buffer[..count].copy_from_slice(&data[start..start + count]);
+                Ok(count)
+            }
+            InitrdInode::Directory(_) => Err(VfsError::IsADirectory),
+        }
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, VfsError> {
+        match self {
+            InitrdInode::File(_) => Err(VfsError::ReadOnly),
+            InitrdInode::Directory(_) => Err(VfsError::IsADirectory),
+        }
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        self.write_at(0, &[]).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+fn test_header(name: &str, size: usize, kind: u8) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    let size = format!("{:011o}", size);
+    header[124..135].copy_from_slice(size.as_bytes());
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    let checksum = format!("{:06o}\0", sum);
+    header[148..155].copy_from_slice(checksum.as_bytes());
+    header
+}
+
+#[test_case]
+fn test_parse_archive() {
+    serial_print!("test_parse_archive... ");
+    let mut archive = Vec::new();
+    archive.extend_from_slice(&test_header("./bin/", 0, TYPE_DIRECTORY));
+    archive.extend_from_slice(&test_header("./bin/hello.mec", 5, TYPE_FILE));
+    let mut data = [0; BLOCK_SIZE];
+    data[..5].copy_from_slice(b"hello");
+    archive.extend_from_slice(&data);
+    archive.extend_from_slice(&test_header("notes", 0, TYPE_FILE));
+    archive.extend_from_slice(&[0; 2 * BLOCK_SIZE]);
+    let archive: &'static [u8] = alloc::boxed::Box::leak(archive.into_boxed_slice());
+
+    let ramdisk = Ramdisk::parse(archive).expect("parsing failed");
+    assert_eq!(ramdisk.file_count(), 2);
+    assert_eq!(ramdisk.read("/bin/hello.mec"), Ok(&b"hello"[..]));
+    assert_eq!(ramdisk.read("notes"), Ok(&b""[..]));
+    assert_eq!(ramdisk.read("bin"), Err(InitrdError::NotAFile));
+    assert_eq!(ramdisk.read("notes/x"), Err(InitrdError::NotFound));
+    assert_eq!(ramdisk.list("/"), Ok(vec!["bin", "notes"]));
+    assert_eq!(ramdisk.list("bin"), Ok(vec!["hello.mec"]));
+
+    let mut corrupted = archive.to_vec();
+    corrupted[BLOCK_SIZE] ^= 1;
+    let corrupted: &'static [u8] = alloc::boxed::Box::leak(corrupted.into_boxed_slice());
+    assert_eq!(Ramdisk::parse(corrupted).err(), Some(InitrdError::BadChecksum(BLOCK_SIZE)));
+    serial_println!("[ok]");
+}
@@ -4,16 +4,12 @@
 // problem we skip compilation of this module on Windows.
 #![cfg(not(windows))]
 
-use crate::{gdt, hlt_loop, print, println};
+use crate::{apic, ata, cow, gdt, hlt_loop, percpu, println, task, thread, timer, virtio_blk, vma};
 use lazy_static::lazy_static;
 use pic8259_simple::ChainedPics;
 use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
-use ::HiveCore;
 use ::Time;
-use mech_core::{Transaction, Change, Table, Value, Hasher, Index};
-use alloc::vec::Vec;
-use alloc::string::ToString;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -23,10 +19,25 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Serial = PIC_1_OFFSET + 4,
+    /// Raised by the master PIC for IRQs that vanished before they were acknowledged.
+    SpuriousMaster = PIC_1_OFFSET + 7,
+    /// Raised by the slave PIC for IRQs that vanished before they were acknowledged.
+    SpuriousSlave = PIC_2_OFFSET + 7,
+    /// IRQ 14 and 15, only delivered through the I/O APIC.
+    PrimaryAta = 0x30,
+    SecondaryAta,
+    /// Shared by the interrupt lines of all virtio devices, only delivered through the
+    /// I/O APIC.
+    Virtio,
+    /// Sent by other processors with `apic::send_ipi`.
+    Ipi = 0xf0,
+    /// Raised by the local APIC for interrupts that vanished before they were accepted.
+    SpuriousApic = apic::SPURIOUS_VECTOR,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -50,6 +61,14 @@ lazy_static! {
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::SpuriousMaster.as_usize()].set_handler_fn(spurious_interrupt_handler);
+        idt[InterruptIndex::SpuriousSlave.as_usize()].set_handler_fn(spurious_slave_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()].set_handler_fn(secondary_ata_handler);
+        idt[InterruptIndex::Virtio.as_usize()].set_handler_fn(virtio_interrupt_handler);
+        idt[InterruptIndex::Ipi.as_usize()].set_handler_fn(ipi_handler);
+        idt[InterruptIndex::SpuriousApic.as_usize()].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -58,6 +77,15 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Signals the end of a hardware interrupt to whichever controller delivered it.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+    }
+}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
@@ -68,8 +96,13 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    let addr = Cr2::read();
+    if cow::handle_page_fault(addr, error_code) || vma::handle_page_fault(addr, error_code) {
+        return;
+    }
+
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {:?}", addr);
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
     hlt_loop();
@@ -84,54 +117,72 @@ extern "x86-interrupt" fn double_fault_handler(
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    let timer: u64 = Hasher::hash_str("timer");
-    let time = Time.lock()[0];
-    let txn = Transaction::from_changeset(vec![
-        Change::NewTable{ id: timer, rows: 10, columns: 10 },
-        Change::Set{table: timer, row: Index::Index(1), column: Index::Index(1), value: Value::from_u64(time)}
-    ]);
-    Time.lock()[0] = time + 1;
-    HiveCore.lock().process_transaction(&txn);
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    let now = {
+        let mut time = Time.lock();
+        time[0] += 1;
+        time[0]
+    };
+    task::ticks::notify();
+    timer::tick(now);
+    end_of_interrupt(InterruptIndex::Timer);
+    // may switch to another thread, so it comes after the end of interrupt
+    thread::timer_tick(now);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
-    use spin::Mutex;
     use x86_64::instructions::port::Port;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1));
-    }
-
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
-
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => {
-                    let keypress: u64 = Hasher::hash_str("keypress");
-                    let timer: u64 = Hasher::hash_str("timer");
-                    let txn = Transaction::from_changeset(vec![
-                        Change::NewTable{ id: keypress, rows: 10, columns: 10 },
-                        Change::Set{table: keypress, row: Index::Index(1), column: Index::Index(1), value: Value::from_str(&character.to_string())}
-                    ]);
-                    HiveCore.lock().process_transaction(&txn);
-                    println!("{:?}", HiveCore.lock().store.get_table(timer).unwrap().data[0][0]);
-                },
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
+    task::keyboard::add_scancode(scancode);
+
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    use x86_64::instructions::port::Port;
+
+    // serial input has no consumer yet; reading it clears the interrupt
+    let mut line_status: Port<u8> = Port::new(0x3fd);
+    let mut data: Port<u8> = Port::new(0x3f8);
+    while unsafe { line_status.read() } & 1 != 0 {
+        let _: u8 = unsafe { data.read() };
     }
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+
+    end_of_interrupt(InterruptIndex::Serial);
+}
+
+extern "x86-interrupt" fn primary_ata_handler(_stack_frame: &mut InterruptStackFrame) {
+    ata::handle_interrupt(0);
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn secondary_ata_handler(_stack_frame: &mut InterruptStackFrame) {
+    ata::handle_interrupt(1);
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn virtio_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    virtio_blk::handle_interrupt();
+    apic::end_of_interrupt();
+}
+
+extern "x86-interrupt" fn ipi_handler(_stack_frame: &mut InterruptStackFrame) {
+    percpu::current().count_ipi();
+    apic::end_of_interrupt();
+}
+
+/// Spurious interrupts must not be acknowledged at the controller that raised them.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {}
+
+/// A spurious interrupt of the slave PIC still reached the master through the cascade line,
+/// which needs an end of interrupt.
+extern "x86-interrupt" fn spurious_slave_handler(_stack_frame: &mut InterruptStackFrame) {
+    if !apic::is_enabled() {
+        use x86_64::instructions::port::Port;
+
+        let mut master_command: Port<u8> = Port::new(0x20);
+        unsafe { master_command.write(0x20) };
     }
 }
 
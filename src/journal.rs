@@ -0,0 +1,255 @@
+//! The write-ahead journal of the table store.
+//!
+//! The journal occupies a region of the store disk. Its first sector is a header naming the
+//! sequence number of the first record; records follow, each starting on a sector boundary
+//! so appending never rewrites sectors that hold earlier records:
+//!
+//! ```text
+//! header: magic "HIVEJRNL", version: u32, first sequence: u64, CRC-32: u32
+//! record: payload length: u32, sequence: u64, CRC-32 of sequence and payload: u32,
+//!         payload: change count: u32, changes
+//! ```
+//!
+//! Reading stops at the first record that is torn or does not continue the sequence, which
+//! is where the next record is appended.
+
+use crate::block::BlockDevice;
+use crate::persist::{self, PersistError, Reader};
+use alloc::vec::Vec;
+use mech_core::{Change, Index};
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+#[cfg(test)]
+use mech_core::Value;
+
+const MAGIC: [u8; 8] = *b"HIVEJRNL";
+const VERSION: u32 = 1;
+const HEADER_LENGTH: usize = 24;
+const RECORD_HEADER_LENGTH: usize = 16;
+
+const CHANGE_NEW_TABLE: u8 = 0;
+const CHANGE_SET: u8 = 1;
+
+/// The sectors of a journal and the position of its next record.
+#[derive(Debug, Clone, Copy)]
+pub struct Journal {
+    start: u64,
+    sectors: u64,
+    first_sequence: u64,
+    next_sequence: u64,
+    /// The sector the next record is written to, relative to `start`.
+    tail: u64,
+}
+
+fn put_index(out: &mut Vec<u8>, index: &Index) -> Option<()> {
+    match index {
+        Index::Index(index) => persist::put_u64(out, *index as u64),
+        // aliases depend on column names, which the store does not keep
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Serializes a changeset. Returns `None` if it contains changes other than new tables and
+/// set values addressed by position.
+pub fn encode_changes(changes: &[Change]) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    persist::put_u32(&mut out, changes.len() as u32);
+    for change in changes {
+        match change {
+            Change::NewTable { id, rows, columns } => {
+                out.push(CHANGE_NEW_TABLE);
+                persist::put_u64(&mut out, *id);
+                persist::put_u64(&mut out, *rows as u64);
+                persist::put_u64(&mut out, *columns as u64);
+            }
+            Change::Set { table, row, column, value } => {
+                out.push(CHANGE_SET);
+                persist::put_u64(&mut out, *table);
+                put_index(&mut out, row)?;
+                put_index(&mut out, column)?;
+                persist::put_value(&mut out, value);
+            }
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+/// Deserializes a changeset written by `encode_changes`.
+pub fn decode_changes(payload: &[u8]) -> Result<Vec<Change>, PersistError> {
+    let mut reader = Reader::new(payload);
+    let count = reader.u32()? as usize;
+    // every change takes at least 25 bytes
+    if count > payload.len() / 25 {
+        return Err(PersistError::Corrupted);
+    }
+    let mut changes = Vec::with_capacity(count);
+    for _ in 0..count {
+        let change = match reader.u8()? {
+            CHANGE_NEW_TABLE => Change::NewTable {
+                id: reader.u64()?,
+                rows: reader.u64()? as _,
+                columns: reader.u64()? as _,
+            },
+            CHANGE_SET => Change::Set {
+                table: reader.u64()?,
+                row: Index::Index(reader.u64()? as _),
+                column: Index::Index(reader.u64()? as _),
+                value: reader.value()?,
+            },
+            _ => return Err(PersistError::Corrupted),
+        };
+        changes.push(change);
+    }
+    if !reader.is_empty() {
+        return Err(PersistError::Corrupted);
+    }
+    Ok(changes)
+}
+
+/// The number of sectors a record with a payload of `length` bytes occupies.
+fn record_sectors(sector_size: usize, length: usize) -> u64 {
+    ((RECORD_HEADER_LENGTH + length + sector_size - 1) / sector_size) as u64
+}
+
+fn record_checksum(sequence: u64, payload: &[u8]) -> u32 {
+    let mut data = Vec::with_capacity(8 + payload.len());
+    persist::put_u64(&mut data, sequence);
+    data.extend_from_slice(payload);
+    persist::crc32(&data)
+}
+
+impl Journal {
+    /// Writes an empty journal to `sectors` sectors starting at `start`. Its first record
+    /// gets the number `sequence`.
+    pub fn create(device: &dyn BlockDevice, start: u64, sectors: u64, sequence: u64) -> Result<Journal, PersistError> {
+        let mut header = Vec::with_capacity(device.sector_size());
+        header.extend_from_slice(&MAGIC);
+        persist::put_u32(&mut header, VERSION);
+        persist::put_u64(&mut header, sequence);
+        let checksum = persist::crc32(&header);
+        persist::put_u32(&mut header, checksum);
+        header.resize(device.sector_size(), 0);
+        device.write(start, &header)?;
+        device.flush()?;
+        Ok(Journal {
+            start,
+            sectors,
+            first_sequence: sequence,
+            next_sequence: sequence,
+            tail: 1,
+        })
+    }
+
+    /// Reads the journal in the given sectors.
+    ///
+    /// Returns it positioned after its last valid record, together with the sequence
+    /// number and changes of every valid record.
+    pub fn open(device: &dyn BlockDevice, start: u64, sectors: u64) -> Result<(Journal, Vec<(u64, Vec<Change>)>), PersistError> {
+        let sector_size = device.sector_size();
+        let mut sector = vec![0; sector_size];
+        device.read(start, &mut sector)?;
+        if sector[..MAGIC.len()] != MAGIC {
+            return Err(PersistError::NoImage);
+        }
+        let mut reader = Reader::new(&sector[MAGIC.len()..HEADER_LENGTH]);
+        let version = reader.u32()?;
+        let first_sequence = reader.u64()?;
+        if persist::crc32(&sector[..HEADER_LENGTH - 4]) != reader.u32()? {
+            return Err(PersistError::Corrupted);
+        }
+        if version != VERSION {
+            return Err(PersistError::UnsupportedVersion(version));
+        }
+
+        let mut journal = Journal {
+            start,
+            sectors,
+            first_sequence,
+            next_sequence: first_sequence,
+            tail: 1,
+        };
+        let mut records = Vec::new();
+        while journal.tail < sectors {
+            device.read(start + journal.tail, &mut sector)?;
+            let mut reader = Reader::new(&sector[..RECORD_HEADER_LENGTH]);
+            let length = reader.u32()? as usize;
+            let sequence = reader.u64()?;
+            let checksum = reader.u32()?;
+            let record_sectors = record_sectors(sector_size, length);
+            if sequence != journal.next_sequence || journal.tail + record_sectors > sectors {
+                break;
+            }
+            let mut data = vec![0; record_sectors as usize * sector_size];
+            data[..sector_size].copy_from_slice(&sector);
+            if record_sectors > 1 {
+                device.read(start + journal.tail + 1, &mut data[sector_size..])?;
+            }
+            let payload = &data[RECORD_HEADER_LENGTH..RECORD_HEADER_LENGTH + length];
+            if record_checksum(sequence, payload) != checksum {
+                break;
+            }
+            let changes = match decode_changes(payload) {
+                Ok(changes) => changes,
+                Err(_) => break,
+            };
+            records.push((sequence, changes));
+            journal.next_sequence += 1;
+            journal.tail += record_sectors;
+        }
+        Ok((journal, records))
+    }
+
+    /// Whether the journal holds no records.
+    pub fn is_empty(&self) -> bool {
+        self.tail == 1
+    }
+
+    /// The sequence number of the first record.
+    pub fn first_sequence(&self) -> u64 {
+        self.first_sequence
+    }
+
+    /// The sequence number the next record gets.
+    pub fn next_sequence(&self) -> u64 {
+        self.next_sequence
+    }
+
+    /// Appends a record holding an encoded changeset and waits until it is on the disk.
+    ///
+    /// Fails with `JournalFull` if the record does not fit; the journal is unchanged then.
+    pub fn append(&mut self, device: &dyn BlockDevice, payload: &[u8]) -> Result<(), PersistError> {
+        let sector_size = device.sector_size();
+        let record_sectors = record_sectors(sector_size, payload.len());
+        if self.tail + record_sectors > self.sectors {
+            return Err(PersistError::JournalFull);
+        }
+        let mut data = Vec::with_capacity(record_sectors as usize * sector_size);
+        persist::put_u32(&mut data, payload.len() as u32);
+        persist::put_u64(&mut data, self.next_sequence);
+        persist::put_u32(&mut data, record_checksum(self.next_sequence, payload));
+        data.extend_from_slice(payload);
+        data.resize(record_sectors as usize * sector_size, 0);
+        device.write(self.start + self.tail, &data)?;
+        device.flush()?;
+        self.next_sequence += 1;
+        self.tail += record_sectors;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_encode_decode_changes() {
+    serial_print!("test_encode_decode_changes... ");
+    let changes = vec![
+        Change::NewTable{ id: 7, rows: 1, columns: 2 },
+        Change::Set{table: 7, row: Index::Index(1), column: Index::Index(2), value: Value::from_str("drone")},
+    ];
+    let payload = encode_changes(&changes).expect("changes not encodable");
+    let decoded = decode_changes(&payload).expect("decoding failed");
+    assert_eq!(encode_changes(&decoded), Some(payload.clone()));
+    assert!(decode_changes(&payload[1..]).is_err());
+    serial_println!("[ok]");
+}
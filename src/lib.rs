@@ -3,6 +3,8 @@
 #![feature(custom_test_frameworks)]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
+#![feature(asm)]
+#![feature(global_asm)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
@@ -21,30 +23,62 @@ extern crate volatile;
 extern crate mech_core;
 
 use core::panic::PanicInfo;
-use linked_list_allocator::LockedHeap;
-use spin::Mutex;
+use sync::IrqMutex;
 use alloc::vec::Vec;
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod ata;
+pub mod block;
+pub mod cow;
+pub mod cpu;
+pub mod devfs;
+pub mod fat;
 pub mod gdt;
+pub mod initrd;
 pub mod interrupts;
+pub mod journal;
 pub mod memory;
+pub mod pci;
+pub mod percpu;
+pub mod persist;
+pub mod power;
+pub mod program;
+pub mod repl;
 pub mod serial;
+pub mod smp;
+pub mod stats;
+pub mod sync;
+pub mod systables;
+pub mod tablefs;
+pub mod task;
+pub mod thread;
+pub mod timer;
+pub mod uaccess;
+pub mod vfs;
 pub mod vga_buffer;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod vma;
+pub mod watch;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: allocator::CountingHeap = allocator::CountingHeap::empty();
 
 lazy_static! {
-  pub static ref HiveCore: Mutex<mech_core::Core> = Mutex::new(mech_core::Core::new(1000, 10));
+  pub static ref HiveCore: IrqMutex<mech_core::Core> = IrqMutex::new("HiveCore", mech_core::Core::new(1000, 10));
 }
 
 lazy_static! {
-    static ref Time: Mutex<Vec<u64>> = Mutex::new(vec![0]);
+    static ref Time: IrqMutex<Vec<u64>> = IrqMutex::new("Time", vec![0]);
 }
 
 pub fn init() {
+    percpu::init();
     gdt::init();
+    cpu::enable_no_execute();
+    cpu::enable_supervisor_protection();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
     x86_64::instructions::interrupts::enable();
@@ -95,8 +129,17 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::BootInfoFrameAllocator;
+
     init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
     test_main();
     hlt_loop();
 }
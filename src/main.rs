@@ -11,8 +11,11 @@ extern crate mech_core;
 extern crate lazy_static;
 extern crate spin;
 
-use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
-use hivemind::println;
+use alloc::{boxed::Box, rc::Rc, string::String, sync::Arc, vec, vec::Vec};
+use hivemind::persist::PersistError;
+use hivemind::vfs::{self, OpenMode};
+use hivemind::{println, timer, watch};
+use hivemind::task::{executor::Executor, keyboard, ticks, Task};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use spin::Mutex;
@@ -30,7 +33,51 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
 
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    memory::protect_kernel(&boot_info.memory_map, &mut mapper);
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    hivemind::systables::init();
+    if let Err(error) = hivemind::apic::init() {
+        println!("APIC unavailable, staying with the 8259 PIC: {:?}", error);
+    }
+    match hivemind::smp::init(&boot_info.memory_map) {
+        Ok(cpus) => println!("{} processors online", cpus),
+        Err(error) => println!("running on the boot processor only: {:?}", error),
+    }
+    match hivemind::initrd::init() {
+        Ok(files) => println!("{} files in the initrd", files),
+        Err(error) => println!("initrd unusable: {:?}", error),
+    }
+    if let Ok(initrd) = hivemind::initrd::InitrdFs::new() {
+        vfs::mount("/", Arc::new(initrd)).expect("mounting the initrd failed");
+    }
+    if let Ok(motd) = vfs::open("/etc/motd", OpenMode::Read).and_then(|mut file| file.read_to_end()) {
+        println!("{}", String::from_utf8_lossy(&motd).trim_end());
+    }
+    println!("{} PCI functions found", hivemind::pci::init());
+    hivemind::ata::init();
+    hivemind::virtio_blk::init();
+    hivemind::block::scan_partitions();
+    for disk in hivemind::block::devices() {
+        println!("{}: {} sectors", disk.name(), disk.sector_count());
+    }
+    hivemind::devfs::init();
+    vfs::mount("/dev", Arc::new(hivemind::devfs::DevFs)).expect("mounting /dev failed");
+    println!("{} FAT volumes mounted", hivemind::fat::mount_all());
+    hivemind::tablefs::init();
+    vfs::mount("/tables", Arc::new(hivemind::tablefs::TableFs)).expect("mounting /tables failed");
+    match hivemind::persist::init() {
+        Ok(restored) => println!(
+            "{} tables restored, {} transactions replayed",
+            restored.tables, restored.transactions
+        ),
+        Err(PersistError::NoDevice) => println!("tables are not persisted: no store disk"),
+        Err(error) => println!("restoring tables failed: {:?}", error),
+    }
+    println!("{} Mech blocks loaded", hivemind::program::init());
+    hivemind::thread::init();
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
@@ -60,7 +107,16 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     test_main();
 
     println!("It did not crash!");
-    hivemind::hlt_loop();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(keyboard::process_keypresses()));
+    executor.spawn(Task::new(ticks::record_ticks()));
+    executor.spawn(Task::new(timer::process_timers()));
+    executor.spawn(Task::new(watch::process_watchers()));
+    hivemind::power::init();
+    hivemind::persist::compact_periodically();
+    hivemind::stats::publish_periodically();
+    executor.run();
 }
 
 /// This function is called on panic.
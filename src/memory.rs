@@ -1,26 +1,161 @@
+use crate::cpu;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, MappedPageTable, Mapper, MapperAllSizes, Page, PageTable, PhysFrame,
-        Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, MappedPageTable, Mapper,
+        MapperAllSizes, Page, PageSize, PageTable, PageTableEntry, PageTableFlags, PhysFrame,
+        Size1GiB, Size2MiB, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+/// The page table mapper type used by the kernel.
+///
+/// Page table frames are translated through `phys_to_virt`, so the type can be named
+/// and stored in a static, unlike a `MappedPageTable` built from a closure.
+pub type KernelMapper = MappedPageTable<'static, fn(PhysFrame) -> *mut PageTable>;
+
+/// The kernel's mapper, available after `install` was called.
+pub static MAPPER: Mutex<Option<KernelMapper>> = Mutex::new(None);
+
+/// The kernel's frame allocator, available after `install` was called.
+pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/// The virtual address at which the complete physical memory is mapped.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The end of the memory below 1MiB, which the frame allocator does not hand out.
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// The end of the physical addresses below 4GiB, where devices place their registers.
+/// `phys_to_virt` covers them as well as all memory.
+pub const DEVICE_MEMORY_END: u64 = 0x1_0000_0000;
+
+/// Where `remap_physical_memory` places its huge page mapping of physical memory.
+pub const PHYSICAL_MEMORY_WINDOW: u64 = 0x_6000_0000_0000;
+
+/// A frame allocator that can hand out frames for pages of every size.
+pub trait FrameAllocatorAllSizes:
+    FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB> + FrameAllocator<Size1GiB>
+{
+}
+
+impl<A> FrameAllocatorAllSizes for A where
+    A: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB> + FrameAllocator<Size1GiB>
+{
+}
+
 /// Initialize a new MappedPageTable.
 ///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
-pub unsafe fn init(physical_memory_offset: u64) -> impl MapperAllSizes {
+pub unsafe fn init(physical_memory_offset: u64) -> KernelMapper {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Ordering::SeqCst);
     let level_4_table = active_level_4_table(physical_memory_offset);
-    let phys_to_virt = move |frame: PhysFrame| -> *mut PageTable {
-        let phys = frame.start_address().as_u64();
-        let virt = VirtAddr::new(phys + physical_memory_offset);
-        virt.as_mut_ptr()
-    };
-    MappedPageTable::new(level_4_table, phys_to_virt)
+    MappedPageTable::new(level_4_table, frame_to_page_table as fn(PhysFrame) -> *mut PageTable)
+}
+
+/// Hands the mapper and frame allocator over to the kernel.
+///
+/// After this call they are reachable from contexts that have no access to the locals of
+/// `kernel_main`, such as the page fault handler.
+pub fn install(mapper: KernelMapper, frame_allocator: BootInfoFrameAllocator) {
+    *MAPPER.lock() = Some(mapper);
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// Returns the virtual address at which the given physical address is accessible.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(addr.as_u64() + PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed))
+}
+
+fn frame_to_page_table(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Replaces the bootloader's mapping of physical memory with one made of huge pages.
+///
+/// The new window starts at `PHYSICAL_MEMORY_WINDOW` and covers all regions of the memory
+/// map as well as the first 4GiB, which contain the memory mapped devices. It uses 1GiB
+/// pages if the CPU supports them and 2MiB pages otherwise. The old window stays mapped,
+/// but `phys_to_virt` only returns addresses in the new one afterwards.
+pub fn remap_physical_memory(
+    memory_map: &MemoryMap,
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError> {
+    let memory_end = memory_map
+        .iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0)
+        .max(DEVICE_MEMORY_END);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::HUGE_PAGE
+        | cpu::no_execute();
+    if cpu::has_1gib_pages() {
+        for phys in (0..memory_end).step_by(Size1GiB::SIZE as usize) {
+            let frame = PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(phys));
+            let page = Page::containing_address(VirtAddr::new(PHYSICAL_MEMORY_WINDOW + phys));
+            unsafe { Mapper::map_to(mapper, page, frame, flags, frame_allocator)?.flush() };
+        }
+    } else {
+        for phys in (0..memory_end).step_by(Size2MiB::SIZE as usize) {
+            let frame = PhysFrame::<Size2MiB>::containing_address(PhysAddr::new(phys));
+            let page = Page::containing_address(VirtAddr::new(PHYSICAL_MEMORY_WINDOW + phys));
+            unsafe { Mapper::map_to(mapper, page, frame, flags, frame_allocator)?.flush() };
+        }
+    }
+    PHYSICAL_MEMORY_OFFSET.store(PHYSICAL_MEMORY_WINDOW, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Maps `size` bytes starting at `start` to newly allocated frames.
+///
+/// Parts of the range that are aligned to 1GiB or 2MiB are mapped with huge pages if the
+/// allocator can provide contiguous frames for them. Everything else falls back to 4KiB
+/// pages.
+pub fn map_range(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut impl MapperAllSizes,
+    frame_allocator: &mut impl FrameAllocatorAllSizes,
+) -> Result<(), MapToError> {
+    let end = start.as_u64() + size;
+    let mut addr = start.as_u64();
+    while addr < end {
+        let remaining = end - addr;
+        if cpu::has_1gib_pages() && addr % Size1GiB::SIZE == 0 && remaining >= Size1GiB::SIZE {
+            if let Some(frame) = FrameAllocator::<Size1GiB>::allocate_frame(frame_allocator) {
+                let page = Page::<Size1GiB>::containing_address(VirtAddr::new(addr));
+                let flags = flags | PageTableFlags::HUGE_PAGE;
+                unsafe { Mapper::map_to(mapper, page, frame, flags, frame_allocator)?.flush() };
+                addr += Size1GiB::SIZE;
+                continue;
+            }
+        }
+        if addr % Size2MiB::SIZE == 0 && remaining >= Size2MiB::SIZE {
+            if let Some(frame) = FrameAllocator::<Size2MiB>::allocate_frame(frame_allocator) {
+                let page = Page::<Size2MiB>::containing_address(VirtAddr::new(addr));
+                let flags = flags | PageTableFlags::HUGE_PAGE;
+                unsafe { Mapper::map_to(mapper, page, frame, flags, frame_allocator)?.flush() };
+                addr += Size2MiB::SIZE;
+                continue;
+            }
+        }
+        let frame = FrameAllocator::<Size4KiB>::allocate_frame(frame_allocator)
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        unsafe { Mapper::map_to(mapper, page, frame, flags, frame_allocator)?.flush() };
+        addr += Size4KiB::SIZE;
+    }
+    Ok(())
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -41,6 +176,169 @@ unsafe fn active_level_4_table(physical_memory_offset: u64) -> &'static mut Page
     &mut *page_table_ptr // unsafe
 }
 
+/// Returns the level 1 page table entry that maps the given page.
+///
+/// Returns `None` if one of the parent tables is not present or the page is part of a huge
+/// page. This function is unsafe because the returned reference aliases the page tables
+/// managed by `MAPPER`, so the caller must hold its lock while using the entry.
+pub unsafe fn page_table_entry(page: Page) -> Option<&'static mut PageTableEntry> {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut table = &mut *frame_to_page_table(level_4_table_frame);
+    for &index in &[page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT)
+            || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+        {
+            return None;
+        }
+        table = &mut *frame_to_page_table(PhysFrame::containing_address(entry.addr()));
+    }
+    Some(&mut table[page.p1_index()])
+}
+
+/// Marks the given page, and every page table on the way to it, as accessible from user mode.
+///
+/// The CPU only treats a page as a user page if all levels of the translation allow user
+/// access. Returns `false` if the page is not mapped by a 4KiB page. This function is unsafe
+/// because the caller must have exclusive access to the active page tables.
+pub unsafe fn set_user_accessible(page: Page) -> bool {
+    use x86_64::registers::control::Cr3;
+    use x86_64::instructions::tlb;
+
+    let (level_4_table_frame, _) = Cr3::read();
+    let mut table = &mut *frame_to_page_table(level_4_table_frame);
+    let indices = [page.p4_index(), page.p3_index(), page.p2_index(), page.p1_index()];
+    for (level, &index) in indices.iter().enumerate() {
+        let entry = &mut table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return false;
+        }
+        entry.set_flags(flags | PageTableFlags::USER_ACCESSIBLE);
+        if level == indices.len() - 1 {
+            break;
+        }
+        table = &mut *frame_to_page_table(PhysFrame::containing_address(entry.addr()));
+    }
+    tlb::flush(page.start_address());
+    true
+}
+
+/// ELF program header type of loadable segments.
+const PT_LOAD: u32 = 1;
+/// ELF segment flag for executable segments.
+const PF_X: u32 = 1;
+/// ELF segment flag for writable segments.
+const PF_W: u32 = 2;
+
+/// The maximum number of pages of the boot stack whose mapping `protect_kernel` updates.
+const MAX_STACK_PAGES: u64 = 1024;
+
+/// Enforces W^X on the kernel's own mappings.
+///
+/// The kernel ELF file is located through the `Kernel` region of the memory map. Pages of
+/// executable segments become read-only, all other segments become non-executable and are
+/// only writable if the segment is. A page shared by several segments gets the union of
+/// their permissions. Panics if that makes a page writable and executable, which the
+/// `-z separate-code` linker argument of the target rules out. The pages of the current stack
+/// are made non-executable as well. Call `cpu::enable_no_execute` first, or nothing will be
+/// non-executable.
+pub fn protect_kernel(memory_map: &MemoryMap, mapper: &mut impl MapperAllSizes) {
+    let kernel = memory_map
+        .iter()
+        .find(|r| r.region_type == MemoryRegionType::Kernel)
+        .expect("memory map contains no kernel region");
+    let elf = phys_to_virt(PhysAddr::new(kernel.range.start_addr())).as_u64();
+    let read_u16 = |offset: u64| unsafe { ((elf + offset) as *const u16).read_unaligned() };
+    let read_u32 = |offset: u64| unsafe { ((elf + offset) as *const u32).read_unaligned() };
+    let read_u64 = |offset: u64| unsafe { ((elf + offset) as *const u64).read_unaligned() };
+    assert_eq!(read_u32(0), 0x464c_457f, "kernel region does not start with an ELF header");
+
+    let program_headers = read_u64(0x20);
+    let header_size = u64::from(read_u16(0x36));
+    let header_count = u64::from(read_u16(0x38));
+    // the start, end and flags of a non-empty loadable segment
+    let segment = |i: u64| {
+        let header = program_headers + i * header_size;
+        let size = read_u64(header + 40);
+        if read_u32(header) != PT_LOAD || size == 0 {
+            return None;
+        }
+        let start = read_u64(header + 16);
+        Some((start, start + size, read_u32(header + 4)))
+    };
+    // the heap is not set up yet, so shared pages are found by comparing all segments
+    for i in 0..header_count {
+        let (start, end, _) = match segment(i) {
+            Some(segment) => segment,
+            None => continue,
+        };
+        let pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(VirtAddr::new(start)),
+            Page::containing_address(VirtAddr::new(end - 1)),
+        );
+        for page in pages {
+            let page_start = page.start_address().as_u64();
+            let page_end = page_start + Size4KiB::SIZE;
+            let mut segment_flags = 0;
+            for j in 0..header_count {
+                match segment(j) {
+                    Some((start, end, flags)) if start < page_end && end > page_start => {
+                        segment_flags |= flags
+                    }
+                    _ => {}
+                }
+            }
+            // the target links code into pages of its own, so this only fails if that changes
+            assert!(
+                segment_flags & PF_X == 0 || segment_flags & PF_W == 0,
+                "kernel page {:?} holds both code and writable data", page
+            );
+            let flags = if segment_flags & PF_X != 0 {
+                PageTableFlags::PRESENT
+            } else if segment_flags & PF_W != 0 {
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | cpu::no_execute()
+            } else {
+                PageTableFlags::PRESENT | cpu::no_execute()
+            };
+            if let Ok(flush) = Mapper::<Size4KiB>::update_flags(mapper, page, flags) {
+                flush.flush();
+            }
+        }
+    }
+
+    protect_stack(mapper);
+}
+
+/// Marks the pages of the current stack as non-executable.
+///
+/// The stack is found by walking from the page containing the stack pointer in both
+/// directions until an unmapped page, such as the guard page, is reached.
+fn protect_stack(mapper: &mut impl MapperAllSizes) {
+    let marker = 0u8;
+    let stack_page = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(&marker));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | cpu::no_execute();
+    let mut protect = |page: Page<Size4KiB>| match Mapper::update_flags(mapper, page, flags) {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => false,
+    };
+    for n in 0..MAX_STACK_PAGES {
+        if !protect(stack_page - n) {
+            break;
+        }
+    }
+    for n in 1..MAX_STACK_PAGES {
+        if !protect(stack_page + n) {
+            break;
+        }
+    }
+}
+
 /// Creates an example mapping for the given page to frame `0xb8000`.
 pub fn create_example_mapping(
     page: Page,
@@ -65,10 +363,17 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+/// Marks the end of the free list of a `BootInfoFrameAllocator`.
+const FREE_LIST_END: u64 = u64::max_value();
+
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
+///
+/// Deallocated frames are kept in a free list that is threaded through the frames
+/// themselves, so freeing a frame never needs the heap.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    free_list: Option<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -81,10 +386,14 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_list: None,
         }
     }
 
     /// Returns an iterator over the usable frames specified in the memory map.
+    ///
+    /// Frames below 1MiB are left out, since starting application processors needs a page
+    /// there.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // get usable regions from memory map
         let regions = self.memory_map.iter();
@@ -92,16 +401,105 @@ impl BootInfoFrameAllocator {
         // map each region to its address range
         let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
         // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        let frame_addresses = addr_ranges
+            .flat_map(|r| r.step_by(4096))
+            .filter(|&addr| addr >= LOW_MEMORY_END);
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Allocates a run of contiguous 4KiB frames that backs a single page of size `S`.
+    fn allocate_contiguous<S: PageSize>(&mut self) -> Option<PhysFrame<S>> {
+        let frames_per_page = (S::SIZE / Size4KiB::SIZE) as usize;
+        let start = self.allocate_run(frames_per_page, S::SIZE)?;
+        Some(PhysFrame::containing_address(start))
+    }
+
+    /// Allocates `count` physically contiguous 4KiB frames, for devices that access
+    /// memory directly. Returns the first frame.
+    pub fn allocate_frames(&mut self, count: usize) -> Option<PhysFrame> {
+        let start = self.allocate_run(count, Size4KiB::SIZE)?;
+        Some(PhysFrame::containing_address(start))
+    }
+
+    /// Allocates a run of `count` contiguous 4KiB frames, starting at a multiple of
+    /// `alignment`.
+    ///
+    /// Frames that are skipped to reach the next suitable run are put on the free list
+    /// instead of being lost.
+    fn allocate_run(&mut self, count: usize, alignment: u64) -> Option<PhysAddr> {
+        let mut run: Option<(usize, u64)> = None;
+        let mut run_len = 0;
+        for (index, frame) in self.usable_frames().enumerate().skip(self.next) {
+            let addr = frame.start_address().as_u64();
+            match run {
+                Some((_, start)) if addr == start + run_len as u64 * Size4KiB::SIZE => {
+                    run_len += 1;
+                }
+                _ if addr % alignment == 0 => {
+                    run = Some((index, addr));
+                    run_len = 1;
+                }
+                _ => {
+                    run = None;
+                    run_len = 0;
+                }
+            }
+            if run_len == count {
+                break;
+            }
+        }
+        if run_len != count {
+            return None;
+        }
+        let (start_index, start) = run?;
+        let skipped = self.usable_frames().skip(self.next).take(start_index - self.next);
+        for frame in skipped {
+            self.deallocate_frame(frame);
+        }
+        self.next = start_index + count;
+        Some(PhysAddr::new(start))
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free_list {
+            let link: *const u64 = phys_to_virt(frame.start_address()).as_ptr();
+            let next = unsafe { link.read() };
+            self.free_list = if next == FREE_LIST_END {
+                None
+            } else {
+                Some(PhysFrame::containing_address(PhysAddr::new(next)))
+            };
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
+
+unsafe impl FrameAllocator<Size2MiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_contiguous()
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        self.allocate_contiguous()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self
+            .free_list
+            .map(|head| head.start_address().as_u64())
+            .unwrap_or(FREE_LIST_END);
+        let link: *mut u64 = phys_to_virt(frame.start_address()).as_mut_ptr();
+        unsafe { link.write(next) };
+        self.free_list = Some(frame);
+    }
+}
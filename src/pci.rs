@@ -0,0 +1,402 @@
+//! PCI device discovery through the configuration space I/O ports.
+
+use crate::sync::IrqMutex;
+use crate::systables::{self, PCI};
+use crate::println;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const COMMAND: u8 = 0x04;
+const COMMAND_IO_SPACE: u32 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u32 = 1 << 1;
+const BAR_0: u8 = 0x10;
+const CAPABILITIES_POINTER: u8 = 0x34;
+const INTERRUPT: u8 = 0x3c;
+/// Set in the status register if the function has a capability list.
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+/// Capabilities are at least 4 bytes long, so a valid list is no longer than this.
+const MAX_CAPABILITIES: usize = 48;
+
+/// Returned for the vendor id of functions that do not exist.
+const NO_VENDOR: u16 = 0xffff;
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_DEVICE: u8 = 0x00;
+
+lazy_static! {
+    static ref DEVICES: IrqMutex<Vec<Slot>> = IrqMutex::new("DEVICES", Vec::new());
+    static ref DRIVERS: IrqMutex<Vec<&'static Driver>> = IrqMutex::new("DRIVERS", Vec::new());
+}
+
+/// The location of a function on the PCI bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    fn config_address(&self, offset: u8) -> u32 {
+        1 << 31
+            | u32::from(self.bus) << 16
+            | u32::from(self.device) << 11
+            | u32::from(self.function) << 8
+            | u32::from(offset & 0xfc)
+    }
+
+    /// Reads a dword of the function's configuration space.
+    pub fn read(&self, offset: u8) -> u32 {
+        let mut address: Port<u32> = Port::new(CONFIG_ADDRESS);
+        let mut data: Port<u32> = Port::new(CONFIG_DATA);
+        // the address and data ports must not be interleaved with another access
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            address.write(self.config_address(offset));
+            data.read()
+        })
+    }
+
+    /// Writes a dword of the function's configuration space.
+    pub fn write(&self, offset: u8, value: u32) {
+        let mut address: Port<u32> = Port::new(CONFIG_ADDRESS);
+        let mut data: Port<u32> = Port::new(CONFIG_DATA);
+        x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+            address.write(self.config_address(offset));
+            data.write(value);
+        })
+    }
+
+    /// Returns the id and configuration space offset of each capability of the function.
+    pub fn capabilities(&self) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+        if self.read(COMMAND) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+        let mut offset = self.read(CAPABILITIES_POINTER) as u8 & 0xfc;
+        while offset != 0 && capabilities.len() < MAX_CAPABILITIES {
+            let header = self.read(offset);
+            capabilities.push((header as u8, offset));
+            offset = (header >> 8) as u8 & 0xfc;
+        }
+        capabilities
+    }
+
+    /// Enables memory and I/O decoding and bus mastering.
+    pub fn enable(&self) {
+        const BUS_MASTER: u32 = 1 << 2;
+        let command = self.read(COMMAND);
+        self.write(COMMAND, command | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | BUS_MASTER);
+    }
+}
+
+/// A base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    /// Not implemented, or the upper half of a 64-bit memory BAR.
+    None,
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+/// A function found on the PCI bus.
+#[derive(Debug, Clone, Copy)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// Only the BARs of ordinary devices are read; bridges have none here.
+    pub bars: [Bar; 6],
+    /// The legacy IRQ the firmware routed the interrupt pin to, 0xff if none.
+    pub interrupt_line: u8,
+    /// 1 to 4 for `INTA#` to `INTD#`, 0 if the function does not use an interrupt.
+    pub interrupt_pin: u8,
+}
+
+/// Describes the devices a driver handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Match {
+    Id { vendor: u16, device: u16 },
+    Class { class: u8, subclass: u8 },
+}
+
+impl Match {
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            Match::Id { vendor, device: id } => {
+                device.vendor_id == vendor && device.device_id == id
+            }
+            Match::Class { class, subclass } => {
+                device.class == class && device.subclass == subclass
+            }
+        }
+    }
+}
+
+/// A device driver. `probe` is called for every matching device that has no driver yet
+/// and returns whether the driver took the device.
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    pub probe: fn(&PciDevice) -> bool,
+}
+
+struct Slot {
+    device: PciDevice,
+    driver: Option<&'static str>,
+}
+
+/// Reads a BAR and determines its size by writing all ones to it.
+///
+/// Returns the BAR and the number of registers it occupies. Decoding is disabled while the
+/// BAR is changed.
+fn read_bar(address: PciAddress, index: usize) -> (Bar, usize) {
+    let offset = BAR_0 + 4 * index as u8;
+    let original = address.read(offset);
+    let command = address.read(COMMAND);
+    address.write(COMMAND, command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+    let size_of = |offset: u8, original: u32| {
+        address.write(offset, 0xffff_ffff);
+        let mask = address.read(offset);
+        address.write(offset, original);
+        mask
+    };
+
+    let result = if original & 1 == 1 {
+        let mask = size_of(offset, original) & !0b11;
+        let size = (!mask).wrapping_add(1) & 0xffff;
+        if mask == 0 {
+            (Bar::None, 1)
+        } else {
+            (
+                Bar::Io {
+                    port: (original & !0b11) as u16,
+                    size,
+                },
+                1,
+            )
+        }
+    } else {
+        let is_64 = (original >> 1) & 0b11 == 0b10 && index < 5;
+        let prefetchable = original & 0b1000 != 0;
+        let low_mask = size_of(offset, original) & !0xf;
+        let (address_value, mask) = if is_64 {
+            let high = address.read(offset + 4);
+            let high_mask = size_of(offset + 4, high);
+            (
+                u64::from(high) << 32 | u64::from(original & !0xf),
+                u64::from(high_mask) << 32 | u64::from(low_mask),
+            )
+        } else {
+            (
+                u64::from(original & !0xf),
+                0xffff_ffff_0000_0000 | u64::from(low_mask),
+            )
+        };
+        let registers = if is_64 { 2 } else { 1 };
+        if low_mask == 0 {
+            (Bar::None, registers)
+        } else {
+            (
+                Bar::Memory {
+                    address: address_value,
+                    size: (!mask).wrapping_add(1),
+                    prefetchable,
+                },
+                registers,
+            )
+        }
+    };
+
+    address.write(COMMAND, command);
+    result
+}
+
+/// Reads the function at `address`, if it exists.
+fn read_device(address: PciAddress) -> Option<PciDevice> {
+    let id = address.read(0x00);
+    let vendor_id = id as u16;
+    if vendor_id == NO_VENDOR {
+        return None;
+    }
+    let class = address.read(0x08);
+    let header_type = (address.read(0x0c) >> 16) as u8 & !HEADER_TYPE_MULTI_FUNCTION;
+    let interrupt = address.read(INTERRUPT);
+
+    let mut bars = [Bar::None; 6];
+    if header_type == HEADER_TYPE_DEVICE {
+        let mut index = 0;
+        while index < bars.len() {
+            let (bar, registers) = read_bar(address, index);
+            bars[index] = bar;
+            index += registers;
+        }
+    }
+
+    Some(PciDevice {
+        address,
+        vendor_id,
+        device_id: (id >> 16) as u16,
+        class: (class >> 24) as u8,
+        subclass: (class >> 16) as u8,
+        prog_if: (class >> 8) as u8,
+        revision: class as u8,
+        bars,
+        interrupt_line: interrupt as u8,
+        interrupt_pin: (interrupt >> 8) as u8,
+    })
+}
+
+/// Returns all functions on all buses.
+pub fn enumerate() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = PciAddress {
+                bus,
+                device,
+                function: 0,
+            };
+            if first.read(0x00) as u16 == NO_VENDOR {
+                continue;
+            }
+            let multi_function = (first.read(0x0c) >> 16) as u8 & HEADER_TYPE_MULTI_FUNCTION != 0;
+            let functions = if multi_function { 8 } else { 1 };
+            for function in 0..functions {
+                let address = PciAddress {
+                    bus,
+                    device,
+                    function,
+                };
+                devices.extend(read_device(address));
+            }
+        }
+    }
+    devices
+}
+
+/// Enumerates the bus, publishes the result in `#pci` and probes the registered drivers.
+///
+/// Returns the number of functions found. Requires the heap.
+pub fn init() -> usize {
+    let devices = enumerate();
+    let count = devices.len();
+    publish(&devices);
+    *DEVICES.lock() = devices
+        .into_iter()
+        .map(|device| Slot {
+            device,
+            driver: None,
+        })
+        .collect();
+    bind_drivers();
+    count
+}
+
+/// Writes one row per function to `#pci`, which `systables::init` created: bus, device,
+/// function, vendor id, device id, class, subclass and interrupt line.
+fn publish(devices: &[PciDevice]) {
+    if devices.len() > PCI.capacity {
+        println!("WARNING: #pci only lists {} of {} PCI functions", PCI.capacity, devices.len());
+    }
+    let mut changes = Vec::new();
+    for (index, device) in devices.iter().take(PCI.capacity).enumerate() {
+        let row = index + 1;
+        changes.push(PCI.set_u64(row, "bus", u64::from(device.address.bus)));
+        changes.push(PCI.set_u64(row, "device", u64::from(device.address.device)));
+        changes.push(PCI.set_u64(row, "function", u64::from(device.address.function)));
+        changes.push(PCI.set_u64(row, "vendor-id", u64::from(device.vendor_id)));
+        changes.push(PCI.set_u64(row, "device-id", u64::from(device.device_id)));
+        changes.push(PCI.set_u64(row, "class", u64::from(device.class)));
+        changes.push(PCI.set_u64(row, "subclass", u64::from(device.subclass)));
+        changes.push(PCI.set_u64(row, "interrupt-line", u64::from(device.interrupt_line)));
+    }
+    systables::set(changes);
+}
+
+/// Returns the functions found by `init`.
+pub fn devices() -> Vec<PciDevice> {
+    DEVICES.lock().iter().map(|slot| slot.device).collect()
+}
+
+/// Returns the name of the driver bound to the function at `address`.
+pub fn driver_of(address: PciAddress) -> Option<&'static str> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|slot| slot.device.address == address)
+        .and_then(|slot| slot.driver)
+}
+
+/// Registers a driver and probes it for the matching devices that have no driver yet.
+pub fn register_driver(driver: &'static Driver) {
+    DRIVERS.lock().push(driver);
+    bind_drivers();
+}
+
+/// Offers every device without a driver to the matching drivers, in registration order.
+///
+/// Probes run without the device list locked, since they may take long.
+fn bind_drivers() {
+    let unbound: Vec<PciDevice> = DEVICES
+        .lock()
+        .iter()
+        .filter(|slot| slot.driver.is_none())
+        .map(|slot| slot.device)
+        .collect();
+    let drivers = DRIVERS.lock().clone();
+    for device in unbound {
+        let driver = drivers.iter().find(|driver| {
+            driver.matches.iter().any(|m| m.matches(&device)) && (driver.probe)(&device)
+        });
+        if let Some(driver) = driver {
+            let mut devices = DEVICES.lock();
+            if let Some(slot) = devices.iter_mut().find(|slot| slot.device.address == device.address) {
+                slot.driver = Some(driver.name);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_match_by_id_and_class() {
+    serial_print!("test_match_by_id_and_class... ");
+    let device = PciDevice {
+        address: PciAddress {
+            bus: 0,
+            device: 3,
+            function: 0,
+        },
+        vendor_id: 0x1af4,
+        device_id: 0x1001,
+        class: 0x01,
+        subclass: 0x00,
+        prog_if: 0,
+        revision: 0,
+        bars: [Bar::None; 6],
+        interrupt_line: 11,
+        interrupt_pin: 1,
+    };
+    assert!(Match::Id { vendor: 0x1af4, device: 0x1001 }.matches(&device));
+    assert!(!Match::Id { vendor: 0x1af4, device: 0x1000 }.matches(&device));
+    assert!(Match::Class { class: 0x01, subclass: 0x00 }.matches(&device));
+    assert!(!Match::Class { class: 0x01, subclass: 0x01 }.matches(&device));
+    serial_println!("[ok]");
+}
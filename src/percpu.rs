@@ -0,0 +1,102 @@
+//! Per-processor data, reached through the `GS` base register.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+
+/// The data of one processor.
+///
+/// `GS` points to it, and its first field holds its own address so `gs:0` returns it.
+#[repr(C)]
+pub struct Cpu {
+    self_address: AtomicU64,
+    /// The processor's position in the order they were started, 0 for the boot processor.
+    pub index: usize,
+    apic_id: AtomicU32,
+    /// The id of the kernel thread running on this processor.
+    pub(crate) current_thread: AtomicU64,
+    /// The number of inter-processor interrupts this processor received.
+    ipis: AtomicU64,
+    online: AtomicBool,
+}
+
+impl Cpu {
+    /// Creates the data of an application processor that is not started yet.
+    pub(crate) fn new(index: usize, apic_id: u8) -> Cpu {
+        Cpu {
+            self_address: AtomicU64::new(0),
+            index,
+            apic_id: AtomicU32::new(u32::from(apic_id)),
+            current_thread: AtomicU64::new(0),
+            ipis: AtomicU64::new(0),
+            online: AtomicBool::new(false),
+        }
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::SeqCst) as u8
+    }
+
+    pub(crate) fn set_apic_id(&self, apic_id: u8) {
+        self.apic_id.store(u32::from(apic_id), Ordering::SeqCst);
+    }
+
+    pub fn ipis(&self) -> u64 {
+        self.ipis.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn count_ipi(&self) {
+        self.ipis.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns whether the processor finished its initialization.
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_online(&self) {
+        self.online.store(true, Ordering::SeqCst);
+    }
+}
+
+static BOOT_CPU: Cpu = Cpu {
+    self_address: AtomicU64::new(0),
+    index: 0,
+    apic_id: AtomicU32::new(0),
+    current_thread: AtomicU64::new(0),
+    ipis: AtomicU64::new(0),
+    online: AtomicBool::new(true),
+};
+
+/// Whether `GS` was set up on the boot processor. Application processors set it up before
+/// they run any code that uses it.
+static GS_READY: AtomicBool = AtomicBool::new(false);
+
+/// Points `GS` of the running processor to `cpu`.
+pub(crate) fn install(cpu: &'static Cpu) {
+    let address = cpu as *const Cpu as u64;
+    cpu.self_address.store(address, Ordering::SeqCst);
+    unsafe { Msr::new(IA32_GS_BASE).write(address) };
+}
+
+/// Sets up `GS` on the boot processor.
+pub fn init() {
+    install(&BOOT_CPU);
+    GS_READY.store(true, Ordering::SeqCst);
+}
+
+/// Returns the data of the boot processor.
+pub fn boot_cpu() -> &'static Cpu {
+    &BOOT_CPU
+}
+
+/// Returns the data of the running processor.
+pub fn current() -> &'static Cpu {
+    if !GS_READY.load(Ordering::Relaxed) {
+        return &BOOT_CPU;
+    }
+    let address: u64;
+    unsafe { asm!("mov %gs:0, $0" : "=r" (address) ::: "volatile") };
+    unsafe { &*(address as *const Cpu) }
+}
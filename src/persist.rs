@@ -0,0 +1,780 @@
+//! Keeping the tables of `HiveCore` on a disk and restoring them at boot.
+//!
+//! A store disk holds two snapshot slots, each a quarter of the disk, followed by the
+//! journal in the second half. Every transaction is appended to the journal before it is
+//! processed. Compaction writes a snapshot to the slot not holding the newest one and then
+//! empties the journal, so a valid snapshot exists at every moment.
+//!
+//! A snapshot is an image: a header sector, followed by the payload in the next sectors:
+//!
+//! ```text
+//! header:  magic "HIVEIMG\0", version: u32, table count: u32, sequence: u64,
+//!          payload length: u64, payload CRC-32: u32, header CRC-32: u32 (over the
+//!          preceding 36 bytes)
+//! payload: per table: id: u64, rows: u64, columns: u64,
+//!          then rows * columns cells, column by column: tag: u8, value
+//! ```
+//!
+//! The sequence is that of the first journal record not contained in the snapshot. At boot,
+//! the newest valid snapshot is restored and the journal records from its sequence on are
+//! replayed. All integers are little-endian. Column and row names are not part of the image.
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::journal::{self, Journal};
+use crate::stats;
+use crate::sync::SleepMutex;
+use crate::timer::{self, TimerId};
+use crate::watch;
+use crate::{println, HiveCore};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use mech_core::{Change, Index, Transaction, Value};
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+const MAGIC: [u8; 8] = *b"HIVEIMG\0";
+const VERSION: u32 = 2;
+const HEADER_LENGTH: usize = 40;
+
+/// The smallest disk with room for two snapshots and a journal of two sectors each.
+const MIN_SECTORS: u64 = 8;
+
+/// How often the store is compacted, in ticks.
+const COMPACTION_PERIOD: u64 = 1000;
+
+const TAG_EMPTY: u8 = 0;
+const TAG_NUMBER: u8 = 1;
+const TAG_STRING: u8 = 2;
+const TAG_BOOL: u8 = 3;
+const TAG_REFERENCE: u8 = 4;
+
+lazy_static! {
+    /// The store opened by `open`, which transactions are journaled to.
+    static ref STORE: SleepMutex<Option<Store>> = SleepMutex::new("STORE", None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersistError {
+    Block(BlockError),
+    /// The disk holds no image.
+    NoImage,
+    /// The image was written by an incompatible version.
+    UnsupportedVersion(u32),
+    /// A checksum does not match, or the payload cannot be decoded.
+    Corrupted,
+    /// The image does not fit into a snapshot slot.
+    TooLarge,
+    /// The disk is too small to hold a store.
+    TooSmall,
+    /// No disk was chosen for the store.
+    NoDevice,
+    /// The journal has no room for another record.
+    JournalFull,
+    /// A transaction contains changes the journal cannot record, like cells set by name.
+    Unsupported,
+}
+
+impl From<BlockError> for PersistError {
+    fn from(error: BlockError) -> PersistError {
+        PersistError::Block(error)
+    }
+}
+
+/// A table as stored in an image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableImage {
+    pub id: u64,
+    pub rows: u64,
+    pub columns: u64,
+    /// `rows * columns` values, column by column.
+    pub cells: Vec<Value>,
+}
+
+/// One of the two regions of a store disk that hold snapshots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    fn start(self, device: &dyn BlockDevice) -> u64 {
+        match self {
+            Slot::A => 0,
+            Slot::B => slot_sectors(device),
+        }
+    }
+
+    fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+fn slot_sectors(device: &dyn BlockDevice) -> u64 {
+    device.sector_count() / 4
+}
+
+fn journal_start(device: &dyn BlockDevice) -> u64 {
+    2 * slot_sectors(device)
+}
+
+fn journal_sectors(device: &dyn BlockDevice) -> u64 {
+    device.sector_count() - journal_start(device)
+}
+
+/// A snapshot read from a slot.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    /// The sequence number of the first journal record not contained in the snapshot.
+    pub sequence: u64,
+    pub tables: Vec<TableImage>,
+}
+
+/// The CRC-32 used by Ethernet and zlib.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// Copies all tables of the core. `Table::data` holds one vector per column.
+pub fn snapshot(core: &mech_core::Core) -> Vec<TableImage> {
+    let mut tables: Vec<TableImage> = core
+        .store
+        .tables
+        .iter()
+        .map(|(&id, table)| {
+            let (rows, columns) = (table.rows as u64, table.columns as u64);
+            let mut cells = Vec::with_capacity((rows * columns) as usize);
+            for column in 0..columns as usize {
+                for row in 0..rows as usize {
+                    let value = table.data.get(column).and_then(|column| column.get(row));
+                    cells.push(value.cloned().unwrap_or(Value::Empty));
+                }
+            }
+            TableImage {
+                id,
+                rows,
+                columns,
+                cells,
+            }
+        })
+        .collect();
+    // a stable order keeps images of the same state identical
+    tables.sort_by_key(|table| table.id);
+    tables
+}
+
+pub(crate) fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn put_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Empty => out.push(TAG_EMPTY),
+        Value::Number(quantity) => {
+            out.push(TAG_NUMBER);
+            put_u64(out, *quantity);
+        }
+        Value::String(string) => {
+            out.push(TAG_STRING);
+            put_u32(out, string.len() as u32);
+            out.extend_from_slice(string.as_bytes());
+        }
+        Value::Bool(boolean) => {
+            out.push(TAG_BOOL);
+            out.push(*boolean as u8);
+        }
+        Value::Reference(id) => {
+            out.push(TAG_REFERENCE);
+            put_u64(out, *id);
+        }
+    }
+}
+
+/// Serializes tables into a payload.
+pub fn encode(tables: &[TableImage]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for table in tables {
+        put_u64(&mut out, table.id);
+        put_u64(&mut out, table.rows);
+        put_u64(&mut out, table.columns);
+        for value in table.cells.iter() {
+            put_value(&mut out, value);
+        }
+    }
+    out
+}
+
+/// Reads values from a payload, failing with `Corrupted` at its end.
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, offset: 0 }
+    }
+
+    /// Whether all of the data has been read.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.offset == self.data.len()
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], PersistError> {
+        let end = self.offset.checked_add(length).ok_or(PersistError::Corrupted)?;
+        let bytes = self.data.get(self.offset..end).ok_or(PersistError::Corrupted)?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, PersistError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, PersistError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, PersistError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn value(&mut self) -> Result<Value, PersistError> {
+        Ok(match self.u8()? {
+            TAG_EMPTY => Value::Empty,
+            TAG_NUMBER => Value::Number(self.u64()?),
+            TAG_STRING => {
+                let length = self.u32()? as usize;
+                let bytes = self.bytes(length)?;
+                let string = core::str::from_utf8(bytes).map_err(|_| PersistError::Corrupted)?;
+                Value::String(String::from(string))
+            }
+            TAG_BOOL => Value::Bool(self.u8()? != 0),
+            TAG_REFERENCE => Value::Reference(self.u64()?),
+            _ => return Err(PersistError::Corrupted),
+        })
+    }
+}
+
+/// Deserializes `count` tables from a payload.
+pub fn decode(payload: &[u8], count: usize) -> Result<Vec<TableImage>, PersistError> {
+    let mut reader = Reader::new(payload);
+    // every table takes at least 24 bytes
+    if count > payload.len() / 24 + 1 {
+        return Err(PersistError::Corrupted);
+    }
+    let mut tables = Vec::with_capacity(count);
+    for _ in 0..count {
+        let id = reader.u64()?;
+        let rows = reader.u64()?;
+        let columns = reader.u64()?;
+        let cell_count = rows.checked_mul(columns).ok_or(PersistError::Corrupted)?;
+        // every cell takes at least a byte, which bounds the allocation below
+        if cell_count > (payload.len() - reader.offset) as u64 {
+            return Err(PersistError::Corrupted);
+        }
+        let mut cells = Vec::with_capacity(cell_count as usize);
+        for _ in 0..cell_count {
+            cells.push(reader.value()?);
+        }
+        tables.push(TableImage {
+            id,
+            rows,
+            columns,
+            cells,
+        });
+    }
+    if !reader.is_empty() {
+        return Err(PersistError::Corrupted);
+    }
+    Ok(tables)
+}
+
+fn header(count: u32, sequence: u64, payload: &[u8]) -> [u8; HEADER_LENGTH] {
+    let mut out = Vec::with_capacity(HEADER_LENGTH);
+    out.extend_from_slice(&MAGIC);
+    put_u32(&mut out, VERSION);
+    put_u32(&mut out, count);
+    put_u64(&mut out, sequence);
+    put_u64(&mut out, payload.len() as u64);
+    put_u32(&mut out, crc32(payload));
+    let checksum = crc32(&out);
+    put_u32(&mut out, checksum);
+    let mut header = [0; HEADER_LENGTH];
+    header.copy_from_slice(&out);
+    header
+}
+
+fn sectors_for(device: &dyn BlockDevice, length: usize) -> usize {
+    let sector_size = device.sector_size();
+    (length + sector_size - 1) / sector_size
+}
+
+/// Writes tables as an image to a snapshot slot.
+///
+/// The header is written last, so an interrupted write leaves an image whose checksum
+/// does not match.
+pub fn write_image(device: &dyn BlockDevice, slot: Slot, sequence: u64, tables: &[TableImage]) -> Result<(), PersistError> {
+    let payload = encode(tables);
+    let sector_size = device.sector_size();
+    let start = slot.start(device);
+    let payload_sectors = sectors_for(device, payload.len());
+    if 1 + payload_sectors as u64 > slot_sectors(device) {
+        return Err(PersistError::TooLarge);
+    }
+    let mut data = payload.clone();
+    data.resize(payload_sectors * sector_size, 0);
+    device.write(start + 1, &data)?;
+    device.flush()?;
+
+    let mut first = vec![0; sector_size];
+    first[..HEADER_LENGTH].copy_from_slice(&header(tables.len() as u32, sequence, &payload));
+    device.write(start, &first)?;
+    device.flush()?;
+    Ok(())
+}
+
+/// Reads and verifies the image in a snapshot slot.
+pub fn read_image(device: &dyn BlockDevice, slot: Slot) -> Result<Image, PersistError> {
+    let sector_size = device.sector_size();
+    let start = slot.start(device);
+    let mut first = vec![0; sector_size];
+    device.read(start, &mut first)?;
+    if first[..MAGIC.len()] != MAGIC {
+        return Err(PersistError::NoImage);
+    }
+    let mut reader = Reader::new(&first[MAGIC.len()..HEADER_LENGTH]);
+    let version = reader.u32()?;
+    let count = reader.u32()?;
+    let sequence = reader.u64()?;
+    let length = reader.u64()?;
+    let payload_checksum = reader.u32()?;
+    let header_checksum = reader.u32()?;
+    if crc32(&first[..HEADER_LENGTH - 4]) != header_checksum {
+        return Err(PersistError::Corrupted);
+    }
+    if version != VERSION {
+        return Err(PersistError::UnsupportedVersion(version));
+    }
+    if length > (slot_sectors(device) - 1) * sector_size as u64 {
+        return Err(PersistError::Corrupted);
+    }
+
+    let mut payload = vec![0; sectors_for(device, length as usize) * sector_size];
+    device.read(start + 1, &mut payload)?;
+    payload.truncate(length as usize);
+    if crc32(&payload) != payload_checksum {
+        return Err(PersistError::Corrupted);
+    }
+    Ok(Image {
+        sequence,
+        tables: decode(&payload, count as usize)?,
+    })
+}
+
+/// Reads both slots and returns the valid image with the higher sequence number.
+fn newest_image(device: &dyn BlockDevice) -> Result<(Slot, Image), PersistError> {
+    match (read_image(device, Slot::A), read_image(device, Slot::B)) {
+        (Ok(a), Ok(b)) => {
+            if b.sequence > a.sequence {
+                Ok((Slot::B, b))
+            } else {
+                Ok((Slot::A, a))
+            }
+        }
+        (Ok(a), Err(_)) => Ok((Slot::A, a)),
+        (Err(_), Ok(b)) => Ok((Slot::B, b)),
+        (Err(PersistError::Block(error)), _) | (_, Err(PersistError::Block(error))) => {
+            Err(PersistError::Block(error))
+        }
+        (Err(PersistError::NoImage), Err(PersistError::NoImage)) => Err(PersistError::NoImage),
+        (Err(PersistError::UnsupportedVersion(version)), _)
+        | (_, Err(PersistError::UnsupportedVersion(version))) => {
+            Err(PersistError::UnsupportedVersion(version))
+        }
+        _ => Err(PersistError::Corrupted),
+    }
+}
+
+/// The state kept on a store disk.
+pub struct Contents {
+    /// The slot holding the newest snapshot.
+    pub slot: Slot,
+    /// The newest snapshot.
+    pub image: Image,
+    /// The changes of the journal records not contained in the snapshot, in order.
+    pub records: Vec<Vec<Change>>,
+    /// The journal, if its header is valid.
+    journal: Option<Journal>,
+}
+
+/// Reads the newest snapshot and the journal of a store disk.
+///
+/// Reading the journal stops at the first torn or corrupted record.
+pub fn read_store(device: &dyn BlockDevice) -> Result<Contents, PersistError> {
+    if device.sector_count() < MIN_SECTORS {
+        return Err(PersistError::TooSmall);
+    }
+    let (slot, image) = newest_image(device)?;
+    let (journal, records) = match Journal::open(device, journal_start(device), journal_sectors(device)) {
+        // records missing between the snapshot and the journal would leave a gap
+        Ok((journal, records)) if journal.first_sequence() <= image.sequence => (Some(journal), records),
+        Err(PersistError::Block(error)) => return Err(PersistError::Block(error)),
+        // the snapshot is consistent on its own, the journal is created again by `open`
+        _ => (None, Vec::new()),
+    };
+    let records = records
+        .into_iter()
+        .filter(|&(sequence, _)| sequence >= image.sequence)
+        .map(|(_, changes)| changes)
+        .collect();
+    Ok(Contents {
+        slot,
+        image,
+        records,
+        journal,
+    })
+}
+
+/// Writes an empty store to a disk: an empty snapshot in slot A and an empty journal.
+pub fn format(device: &dyn BlockDevice) -> Result<(), PersistError> {
+    if device.sector_count() < MIN_SECTORS {
+        return Err(PersistError::TooSmall);
+    }
+    // a stale image in slot B must not be taken for a newer snapshot
+    device.write(Slot::B.start(device), &vec![0; device.sector_size()])?;
+    write_image(device, Slot::A, 0, &[])?;
+    Journal::create(device, journal_start(device), journal_sectors(device), 0)?;
+    Ok(())
+}
+
+/// The disk transactions are journaled to.
+struct Store {
+    device: Arc<dyn BlockDevice>,
+    journal: Journal,
+    /// The slot holding the newest snapshot.
+    slot: Slot,
+}
+
+impl Store {
+    /// Appends a transaction to the journal, compacting the store first if it is full.
+    fn append(&mut self, changes: &[Change]) -> Result<(), PersistError> {
+        let payload = journal::encode_changes(changes).ok_or(PersistError::Unsupported)?;
+        match self.journal.append(&*self.device, &payload) {
+            Err(PersistError::JournalFull) => {
+                self.compact()?;
+                self.journal.append(&*self.device, &payload)
+            }
+            result => result,
+        }
+    }
+
+    /// Writes a snapshot of `HiveCore` to the other slot and empties the journal.
+    ///
+    /// The snapshot is complete before the journal is emptied. If the journal is not
+    /// emptied, its records are older than the snapshot and are skipped at the next boot.
+    fn compact(&mut self) -> Result<(), PersistError> {
+        if self.journal.is_empty() {
+            return Ok(());
+        }
+        let device = &*self.device;
+        let sequence = self.journal.next_sequence();
+        let slot = self.slot.other();
+        let tables = snapshot(&HiveCore.lock());
+        write_image(device, slot, sequence, &tables)?;
+        self.slot = slot;
+        self.journal = Journal::create(device, journal_start(device), journal_sectors(device), sequence)?;
+        Ok(())
+    }
+}
+
+/// The number of tables and journaled transactions restored by `open`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Restored {
+    pub tables: usize,
+    pub transactions: usize,
+}
+
+/// The table a change applies to.
+pub(crate) fn changed_table(change: &Change) -> Option<u64> {
+    match change {
+        Change::NewTable { id, .. } => Some(*id),
+        Change::Set { table, .. } => Some(*table),
+        _ => None,
+    }
+}
+
+/// Loads the newest snapshot of a store into `HiveCore` and replays its journal.
+///
+/// Tables that exist already are kept, so tables describing the running machine are not
+/// replaced by stale copies; journaled changes to them are skipped as well.
+fn restore(contents: &Contents) -> Result<Restored, PersistError> {
+    let core = HiveCore.lock();
+    // the changes are processed at the end, so the core still shows the tables that existed
+    let mut changes = Vec::new();
+    let mut tables = 0;
+    for table in contents.image.tables.iter() {
+        if core.store.get_table(table.id).is_some() {
+            continue;
+        }
+        changes.push(Change::NewTable{ id: table.id, rows: table.rows as _, columns: table.columns as _ });
+        for (i, value) in table.cells.iter().enumerate() {
+            if *value == Value::Empty {
+                continue;
+            }
+            let (column, row) = (i as u64 / table.rows, i as u64 % table.rows);
+            changes.push(Change::Set{table: table.id, row: Index::Index((row + 1) as _), column: Index::Index((column + 1) as _), value: value.clone()});
+        }
+        tables += 1;
+    }
+    for change in contents.records.iter().flatten() {
+        match changed_table(change) {
+            Some(table) if core.store.get_table(table).is_none() => changes.push(change.clone()),
+            _ => {}
+        }
+    }
+    drop(core);
+    transact_unjournaled(changes)?;
+    Ok(Restored {
+        tables,
+        transactions: contents.records.len(),
+    })
+}
+
+/// Restores the store on a disk and journals all later transactions to it.
+///
+/// A store whose snapshots are both corrupted is formatted, so later transactions are
+/// kept, and `Corrupted` is returned.
+pub fn open(device: Arc<dyn BlockDevice>) -> Result<Restored, PersistError> {
+    let contents = match read_store(&*device) {
+        Err(PersistError::Corrupted) => {
+            format(&*device)?;
+            let contents = read_store(&*device)?;
+            install(device, contents)?;
+            return Err(PersistError::Corrupted);
+        }
+        result => result?,
+    };
+    let restored = restore(&contents)?;
+    install(device, contents)?;
+    Ok(restored)
+}
+
+fn install(device: Arc<dyn BlockDevice>, contents: Contents) -> Result<(), PersistError> {
+    let sequence = contents.image.sequence;
+    let journal = match contents.journal {
+        Some(journal) if journal.next_sequence() >= sequence => journal,
+        // compaction was interrupted before it emptied the journal
+        _ => Journal::create(&*device, journal_start(&*device), journal_sectors(&*device), sequence)?,
+    };
+    *STORE.lock() = Some(Store {
+        device,
+        journal,
+        slot: contents.slot,
+    });
+    Ok(())
+}
+
+/// Chooses the disk for the store and opens it.
+///
+/// The store is the first disk that holds a snapshot, even a corrupted one, or else the
+/// first blank disk, which is formatted. Disks with other data are never chosen.
+pub fn init() -> Result<Restored, PersistError> {
+    let mut blank = None;
+    for device in block::devices() {
+        if device.sector_count() < MIN_SECTORS {
+            continue;
+        }
+        match newest_image(&*device) {
+            Err(PersistError::NoImage) => {
+                if blank.is_none() && is_blank(&*device)? {
+                    blank = Some(device);
+                }
+            }
+            Ok(_) | Err(PersistError::Corrupted) | Err(PersistError::UnsupportedVersion(_)) => {
+                return open(device);
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    match blank {
+        Some(device) => {
+            format(&*device)?;
+            open(device)
+        }
+        None => Err(PersistError::NoDevice),
+    }
+}
+
+/// Whether the first sectors of both slots and of the journal are all zeros.
+fn is_blank(device: &dyn BlockDevice) -> Result<bool, PersistError> {
+    let mut sector = vec![0; device.sector_size()];
+    for &start in [Slot::A.start(device), Slot::B.start(device), journal_start(device)].iter() {
+        device.read(start, &mut sector)?;
+        if sector.iter().any(|&byte| byte != 0) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Returns the disk of the open store.
+pub fn store() -> Option<Arc<dyn BlockDevice>> {
+    STORE.lock().as_ref().map(|store| store.device.clone())
+}
+
+/// Journals a transaction to the open store, processes it in `HiveCore`, which runs the
+/// Mech blocks reading the changed tables, and queues the changed cells for watchers.
+///
+/// The transaction is processed even if journaling it fails, and then it is lost at the next
+/// boot. Without an open store, it is only processed. Transactions with changes the journal
+/// cannot record are rejected with `Unsupported` and not processed.
+pub fn transact(changes: Vec<Change>) -> Result<(), PersistError> {
+    if !changes.iter().all(is_supported) {
+        return Err(PersistError::Unsupported);
+    }
+    let mut notifications = watch::collect(&changes);
+    let result = {
+        // the store stays locked while processing, so the journal has the order of `HiveCore`
+        let mut store = STORE.lock();
+        let result = match store.as_mut() {
+            Some(store) => store.append(&changes),
+            None => Ok(()),
+        };
+        process(changes, &mut notifications);
+        result
+    };
+    watch::queue(notifications);
+    result
+}
+
+/// Processes a transaction like `transact`, but does not journal it.
+///
+/// For tables whose writers fill them anew after every boot, like `#timer`, whose records
+/// would only cost a disk write each.
+pub fn transact_unjournaled(changes: Vec<Change>) -> Result<(), PersistError> {
+    if !changes.iter().all(is_supported) {
+        return Err(PersistError::Unsupported);
+    }
+    let mut notifications = watch::collect(&changes);
+    process(changes, &mut notifications);
+    watch::queue(notifications);
+    Ok(())
+}
+
+/// Registers Mech blocks with `HiveCore`, which runs them now and then within every
+/// transaction changing a table they read, and queues the cells they set for watchers.
+///
+/// Nothing is journaled: programs are loaded anew on every boot, and the cells their blocks
+/// set follow from the tables they read, which are journaled themselves.
+pub fn register(blocks: Vec<mech_core::Block>) {
+    let mut notifications = Vec::new();
+    {
+        let mut core = HiveCore.lock();
+        let before = watch::cells(&core);
+        core.register_blocks(blocks);
+        watch::add_derived(&mut notifications, before, &core);
+    }
+    watch::queue(notifications);
+}
+
+/// Whether a change is a new table or sets a cell by position. Other changes are neither
+/// journaled nor seen by watchers, so they are not processed.
+fn is_supported(change: &Change) -> bool {
+    match change {
+        Change::NewTable { .. } => true,
+        Change::Set { row: Index::Index(_), column: Index::Index(_), .. } => true,
+        _ => false,
+    }
+}
+
+/// Processes a transaction in `HiveCore` and counts it. Every transaction on `HiveCore` goes
+/// through here.
+///
+/// The blocks reading the changed tables run within the transaction, so the cells they set
+/// are added to the notifications by comparing the watched tables before and after.
+fn process(changes: Vec<Change>, notifications: &mut Vec<watch::Notification>) {
+    let count = changes.len();
+    let txn = Transaction::from_changeset(changes);
+    let mut core = HiveCore.lock();
+    let before = watch::cells(&core);
+    let start = stats::cycles();
+    core.process_transaction(&txn);
+    stats::record(count, stats::cycles().wrapping_sub(start));
+    watch::add_derived(notifications, before, &core);
+}
+
+/// Writes a snapshot of `HiveCore` to the open store and empties its journal.
+pub fn compact() -> Result<(), PersistError> {
+    match STORE.lock().as_mut() {
+        Some(store) => store.compact(),
+        None => Err(PersistError::NoDevice),
+    }
+}
+
+/// Compacts the open store periodically, so that replaying its journal at boot stays
+/// short. Returns the timer, so it can be cancelled.
+pub fn compact_periodically() -> TimerId {
+    timer::every(COMPACTION_PERIOD, || match compact() {
+        Ok(()) | Err(PersistError::NoDevice) => {}
+        Err(error) => println!("compacting the store failed: {:?}", error),
+    })
+}
+
+#[test_case]
+fn test_crc32() {
+    serial_print!("test_crc32... ");
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32(b""), 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_encode_decode() {
+    serial_print!("test_encode_decode... ");
+    let tables = vec![
+        TableImage {
+            id: 1,
+            rows: 2,
+            columns: 2,
+            cells: vec![
+                Value::from_u64(42),
+                Value::from_str("hive"),
+                Value::Bool(true),
+                Value::Empty,
+            ],
+        },
+        TableImage {
+            id: 2,
+            rows: 0,
+            columns: 3,
+            cells: Vec::new(),
+        },
+    ];
+    let payload = encode(&tables);
+    assert_eq!(decode(&payload, 2), Ok(tables));
+    assert_eq!(decode(&payload[..payload.len() - 1], 2), Err(PersistError::Corrupted));
+    serial_println!("[ok]");
+}
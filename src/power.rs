@@ -0,0 +1,288 @@
+//! Power-off through ACPI and reboot, also available by writing to the `#power` table.
+
+use crate::acpi::{self, AcpiError, Fadt, GenericAddress};
+use crate::systables::{self, POWER};
+use crate::watch::{self, ChangedCell, WatcherId};
+use crate::{apic, memory, println};
+use mech_core::Value;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+#[cfg(test)]
+use crate::sync::IrqMutex;
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+#[cfg(test)]
+use alloc::vec::Vec;
+#[cfg(test)]
+use lazy_static::lazy_static;
+
+/// Set in a PM1 control register to enter the sleep state in `SLP_TYP`.
+const SLP_EN: u16 = 1 << 13;
+/// Whether the firmware is in ACPI mode, in the PM1 control registers.
+const SCI_EN: u16 = 1 << 0;
+
+const KEYBOARD_CONTROLLER_COMMAND: u16 = 0x64;
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+/// Pulses the reset line of the processor.
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xfe;
+
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerError {
+    Acpi(AcpiError),
+    /// The DSDT has no `\_S5` object.
+    NoSoftOff,
+    /// The machine is still running after the power-off request.
+    StillRunning,
+}
+
+/// Returns the `SLP_TYPa` and `SLP_TYPb` values of the soft-off state `\_S5`.
+///
+/// Understands the form every firmware uses: a `Name` whose value is a package starting
+/// with two integers.
+pub fn parse_s5(aml: &[u8]) -> Option<(u16, u16)> {
+    let name = aml.windows(4).enumerate().find_map(|(i, window)| {
+        let defined = match i {
+            0 => false,
+            1 => aml[0] == AML_NAME_OP,
+            _ => aml[i - 1] == AML_NAME_OP || (aml[i - 1] == b'\\' && aml[i - 2] == AML_NAME_OP),
+        };
+        if window == b"_S5_" && defined {
+            Some(i)
+        } else {
+            None
+        }
+    })?;
+
+    let mut offset = name + 4;
+    if *aml.get(offset)? != AML_PACKAGE_OP {
+        return None;
+    }
+    // the top two bits of the lead byte give the number of following length bytes
+    let length_bytes = usize::from(*aml.get(offset + 1)? >> 6);
+    offset += 2 + length_bytes;
+    // the number of elements
+    offset += 1;
+    let a = parse_integer(aml, &mut offset)?;
+    let b = parse_integer(aml, &mut offset)?;
+    Some(((a & 0b111) as u16, (b & 0b111) as u16))
+}
+
+fn parse_integer(aml: &[u8], offset: &mut usize) -> Option<u64> {
+    let op = *aml.get(*offset)?;
+    let (value, length) = match op {
+        AML_ZERO_OP => (0, 1),
+        AML_ONE_OP => (1, 1),
+        AML_BYTE_PREFIX => (u64::from(*aml.get(*offset + 1)?), 2),
+        AML_WORD_PREFIX => {
+            let bytes = aml.get(*offset + 1..*offset + 3)?;
+            (u64::from(bytes[0]) | u64::from(bytes[1]) << 8, 3)
+        }
+        AML_DWORD_PREFIX => {
+            let bytes = aml.get(*offset + 1..*offset + 5)?;
+            let value = bytes.iter().rev().fold(0, |value, &byte| value << 8 | u64::from(byte));
+            (value, 5)
+        }
+        _ => return None,
+    };
+    *offset += length;
+    Some(value)
+}
+
+/// Switches the firmware into ACPI mode if it is not yet.
+fn enable_acpi(fadt: &Fadt) {
+    let mut pm1a_control: Port<u16> = Port::new(fadt.pm1a_control);
+    if unsafe { pm1a_control.read() } & SCI_EN != 0 || fadt.smi_command == 0 {
+        return;
+    }
+    let mut smi_command: Port<u8> = Port::new(fadt.smi_command);
+    unsafe { smi_command.write(fadt.acpi_enable) };
+    for _ in 0..300 {
+        if unsafe { pm1a_control.read() } & SCI_EN != 0 {
+            return;
+        }
+        apic::delay_us(1000);
+    }
+}
+
+/// Turns the machine off through the ACPI soft-off state.
+///
+/// Only returns if that failed.
+pub fn shutdown() -> PowerError {
+    let fadt = match acpi::fadt() {
+        Ok(fadt) => fadt,
+        Err(error) => return PowerError::Acpi(error),
+    };
+    let dsdt = match acpi::dsdt(&fadt) {
+        Ok(dsdt) => dsdt,
+        Err(error) => return PowerError::Acpi(error),
+    };
+    let (slp_typ_a, slp_typ_b) = match parse_s5(acpi::aml(dsdt)) {
+        Some(types) => types,
+        None => return PowerError::NoSoftOff,
+    };
+
+    enable_acpi(&fadt);
+    interrupts::disable();
+    unsafe {
+        Port::new(fadt.pm1a_control).write(slp_typ_a << 10 | SLP_EN);
+        if fadt.pm1b_control != 0 {
+            Port::new(fadt.pm1b_control).write(slp_typ_b << 10 | SLP_EN);
+        }
+    }
+    apic::delay_us(100_000);
+    interrupts::enable();
+    PowerError::StillRunning
+}
+
+/// Restarts the machine.
+///
+/// Tries the ACPI reset register, then the keyboard controller, and finally forces a
+/// triple fault.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Ok(Fadt {
+        reset_register: Some(register),
+        reset_value,
+        ..
+    }) = acpi::fadt()
+    {
+        write_reset_register(register, reset_value);
+        apic::delay_us(10_000);
+    }
+
+    let mut command: Port<u8> = Port::new(KEYBOARD_CONTROLLER_COMMAND);
+    unsafe {
+        for _ in 0..1000 {
+            if command.read() & KEYBOARD_CONTROLLER_INPUT_FULL == 0 {
+                break;
+            }
+            apic::delay_us(10);
+        }
+        command.write(KEYBOARD_CONTROLLER_RESET);
+    }
+    apic::delay_us(10_000);
+
+    triple_fault()
+}
+
+fn write_reset_register(register: GenericAddress, value: u8) {
+    match register.address_space {
+        acpi::ADDRESS_SPACE_IO => unsafe { Port::new(register.address as u16).write(value) },
+        acpi::ADDRESS_SPACE_MEMORY => {
+            let addr = memory::phys_to_virt(PhysAddr::new(register.address));
+            unsafe { addr.as_mut_ptr::<u8>().write_volatile(value) };
+        }
+        // PCI configuration space is not supported
+        _ => {}
+    }
+}
+
+/// Loads an empty IDT and raises an exception, which the processor cannot deliver.
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::{lidt, DescriptorTablePointer};
+
+    let empty = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        lidt(&empty);
+        asm!("int3" :::: "volatile");
+    }
+    unreachable!("triple fault did not reset the machine");
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Request {
+    Shutdown,
+    Reboot,
+}
+
+#[cfg(test)]
+lazy_static! {
+    /// The requests `act` was called with, since tests have to keep running.
+    static ref ACTED: IrqMutex<Vec<Request>> = IrqMutex::new("POWER REQUESTS", Vec::new());
+}
+
+/// Watches the `#power` table.
+///
+/// Writing `"shutdown"` or `"reboot"` to its only cell triggers the action. Returns the
+/// watcher, so it can be removed.
+pub fn init() -> WatcherId {
+    watch::watch(POWER.id(), power_table_changed)
+}
+
+fn power_table_changed(cells: &[ChangedCell]) {
+    let request = match cells.iter().rev().find(|cell| cell.row == 1 && cell.column == 1) {
+        Some(cell) => &cell.value,
+        None => return,
+    };
+    let request = if *request == Value::from_str("shutdown") {
+        Request::Shutdown
+    } else if *request == Value::from_str("reboot") {
+        Request::Reboot
+    } else {
+        return;
+    };
+
+    // clear the request, so that a failed action is not retried forever
+    systables::set(vec![POWER.set_str(1, "request", "")]);
+    match request {
+        Request::Shutdown => println!("shutting down"),
+        Request::Reboot => println!("rebooting"),
+    }
+    act(request);
+}
+
+/// Carries out a request.
+#[cfg(not(test))]
+fn act(request: Request) {
+    match request {
+        Request::Shutdown => println!("shutdown failed: {:?}", shutdown()),
+        Request::Reboot => reboot(),
+    }
+}
+
+/// Records a request, so that tests keep running.
+#[cfg(test)]
+fn act(request: Request) {
+    ACTED.lock().push(request);
+}
+
+#[test_case]
+fn test_parse_s5() {
+    serial_print!("test_parse_s5... ");
+    // Name (\_S5_, Package (0x04) { 0x05, Zero, Zero, Zero })
+    let aml = [
+        0x10, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05, 0x00, 0x00,
+        0x00,
+    ];
+    assert_eq!(parse_s5(&aml), Some((5, 0)));
+    // a method named _S5_ is no sleep state object
+    let aml = [0x14, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x01, 0x01];
+    assert_eq!(parse_s5(&aml), None);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_power_requests() {
+    serial_print!("test_power_requests... ");
+    let watcher = init();
+    systables::set(vec![POWER.set_str(1, "request", "reboot")]);
+    watch::dispatch();
+    assert_eq!(*ACTED.lock(), vec![Request::Reboot]);
+    assert_eq!(POWER.get(1, "request"), Some(Value::from_str("")));
+    // other values are no requests
+    systables::set(vec![POWER.set_str(1, "request", "nap")]);
+    watch::dispatch();
+    assert_eq!(ACTED.lock().len(), 1);
+    assert!(watch::unwatch(watcher));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,630 @@
+//! Mech programs: source compiled into blocks of `HiveCore`, which run whenever a table
+//! they read changes.
+//!
+//! The Mech compiler of mech-syntax needs std, so the kernel parses a subset of Mech itself
+//! and lowers it to the constraints of `mech_core` blocks. A program is a literate document:
+//! indented lines are code, other lines are prose, and consecutive code lines form a block.
+//! A block is a list of statements:
+//!
+//! - `#table = expression` sets the single cell of `#table`, creating the table if needed;
+//! - `#table{row, column} := expression` sets a cell of an existing table;
+//! - `name = expression` defines a name local to the block.
+//!
+//! Expressions combine numbers like `12` or `0.5`, `"strings"`, cells like `#table{2, 1}`,
+//! tables like `#table` standing for their first cell, and local names with `+ - * /` and
+//! parentheses. Columns of the tables in `systables` may also be named, like
+//! `#timer{1, ticks}`. Identifiers may contain `-` and `/`, so operators need spaces around them.
+//! Arithmetic is that of mech-core quantities, so `7 / 2` is `3.5`.
+//!
+//! Blocks are checked when they are loaded: cells set with `:=` must be in an existing table,
+//! names must be defined before they are used, operators only apply to numbers, and cells of
+//! system tables only get values of the type of their column. They are then registered with
+//! `HiveCore` through `persist::register`. mech-core runs a block once, and then within
+//! every transaction changing a table it reads, in the order the blocks depend on each other.
+//! The cells blocks set are not journaled: they follow from the tables the blocks read.
+
+use crate::persist;
+use crate::systables::{self, ColumnType};
+use crate::tablefs;
+use crate::vfs::{self, FileType, OpenMode, VfsError};
+use crate::{println, HiveCore};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::iter::Peekable;
+use core::str::CharIndices;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mech_core::{make_quantity, Constraint, Function, Index, Parameter, TableId, Value};
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+#[cfg(test)]
+use mech_core::Hasher;
+
+/// The directory `init` loads programs from, and the extension of their files.
+pub const PROGRAM_DIRECTORY: &str = "/programs";
+pub const PROGRAM_EXTENSION: &str = ".mec";
+
+/// The capacity of the tables a block keeps its intermediate values in.
+const BLOCK_TABLE_CAPACITY: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramError {
+    /// The line of the program that could not be parsed.
+    Syntax(usize),
+    /// Reading the program failed.
+    File(VfsError),
+    /// A table a block sets with `:=` does not exist.
+    NoTable(u64),
+    /// A cell outside its table.
+    OutOfRange,
+    /// A local name used before it is defined.
+    Undefined,
+    /// An operator applied to values other than numbers.
+    Type,
+    /// A value that the column of a system table does not admit.
+    Schema,
+}
+
+impl From<VfsError> for ProgramError {
+    fn from(error: VfsError) -> Self {
+        ProgramError::File(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+impl Operator {
+    fn function(self) -> Function {
+        match self {
+            Operator::Add => Function::Add,
+            Operator::Subtract => Function::Subtract,
+            Operator::Multiply => Function::Multiply,
+            Operator::Divide => Function::Divide,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    Constant(Value),
+    Cell { table: u64, row: u64, column: u64 },
+    Local(String),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    /// `#table =`, the first cell of a table that is created if needed.
+    Table(u64),
+    /// `#table{row, column} :=`
+    Cell { table: u64, row: u64, column: u64 },
+    Local(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Statement {
+    target: Target,
+    expression: Expression,
+}
+
+/// A parsed block, lowered to a block of mech-core when it is loaded.
+struct Block {
+    /// The first line of the block, to name it in messages.
+    name: String,
+    /// The code lines of the block.
+    text: String,
+    statements: Vec<Statement>,
+}
+
+/// What `blocks` reports about a block registered with `HiveCore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockInfo {
+    pub name: String,
+    /// The number of errors mech-core reported for the block.
+    pub errors: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Table(u64),
+    Name(String),
+    Number(u64),
+    /// A number with a fraction: its digits, and how many of them follow the point.
+    Decimal(u64, u32),
+    Text(String),
+    Symbol(char),
+    /// `:=`
+    Set,
+}
+
+fn is_name_character(character: char) -> bool {
+    character.is_ascii_alphanumeric() || character == '_' || character == '-' || character == '/'
+}
+
+/// Advances past the characters before byte `end`.
+fn skip_to(characters: &mut Peekable<CharIndices>, end: usize) {
+    while characters.peek().map_or(false, |&(next, _)| next < end) {
+        characters.next();
+    }
+}
+
+fn tokenize(line: &str, number: usize) -> Result<Vec<Token>, ProgramError> {
+    let mut tokens = Vec::new();
+    let mut characters = line.char_indices().peekable();
+    let name_end = |start: usize| {
+        line[start..]
+            .find(|character: char| !is_name_character(character))
+            .map_or(line.len(), |length| start + length)
+    };
+    while let Some((position, character)) = characters.next() {
+        match character {
+            ' ' | '\t' => {}
+            '#' => {
+                let end = name_end(position + 1);
+                if end == position + 1 {
+                    return Err(ProgramError::Syntax(number));
+                }
+                // the table files of `/tables` are listed by the names programs use
+                tokens.push(Token::Table(tablefs::register_name(&line[position + 1..end])));
+                skip_to(&mut characters, end);
+            }
+            '"' => {
+                let length = line[position + 1..].find('"').ok_or(ProgramError::Syntax(number))?;
+                let end = position + 1 + length;
+                tokens.push(Token::Text(String::from(&line[position + 1..end])));
+                skip_to(&mut characters, end + 1);
+            }
+            ':' if characters.peek().map(|&(_, next)| next) == Some('=') => {
+                characters.next();
+                tokens.push(Token::Set);
+            }
+            '0'..='9' => {
+                let digits_end = |start: usize| {
+                    line[start..]
+                        .find(|character: char| !character.is_ascii_digit())
+                        .map_or(line.len(), |length| start + length)
+                };
+                let end = digits_end(position);
+                let fraction = line[end..].starts_with('.') && line[end + 1..].starts_with(|character: char| character.is_ascii_digit());
+                if fraction {
+                    let fraction_end = digits_end(end + 1);
+                    let digits = format!("{}{}", &line[position..end], &line[end + 1..fraction_end]);
+                    let mantissa = digits.parse().map_err(|_| ProgramError::Syntax(number))?;
+                    tokens.push(Token::Decimal(mantissa, (fraction_end - end - 1) as u32));
+                    skip_to(&mut characters, fraction_end);
+                } else {
+                    let value = line[position..end].parse().map_err(|_| ProgramError::Syntax(number))?;
+                    tokens.push(Token::Number(value));
+                    skip_to(&mut characters, end);
+                }
+            }
+            '+' | '-' | '*' | '/' | '(' | ')' | '{' | '}' | ',' | '=' => tokens.push(Token::Symbol(character)),
+            character if character.is_ascii_alphabetic() || character == '_' => {
+                let end = name_end(position);
+                tokens.push(Token::Name(String::from(&line[position..end])));
+                skip_to(&mut characters, end);
+            }
+            _ => return Err(ProgramError::Syntax(number)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Parses the tokens of one statement.
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<&'a Token, ProgramError> {
+        let token = self.tokens.get(self.position).ok_or(ProgramError::Syntax(self.line))?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, symbol: char) -> Result<(), ProgramError> {
+        match self.next()? {
+            Token::Symbol(found) if *found == symbol => Ok(()),
+            _ => Err(ProgramError::Syntax(self.line)),
+        }
+    }
+
+    /// Parses `{row, column}` after a table, or nothing for the first cell.
+    fn indices(&mut self, table: u64) -> Result<(u64, u64), ProgramError> {
+        if self.peek() != Some(&Token::Symbol('{')) {
+            return Ok((1, 1));
+        }
+        self.position += 1;
+        let row = self.index()?;
+        self.expect(',')?;
+        let column = match self.peek() {
+            Some(Token::Name(name)) => {
+                self.position += 1;
+                let column = systables::find(table).and_then(|table| table.column(name));
+                column.ok_or(ProgramError::Syntax(self.line))? as u64
+            }
+            _ => self.index()?,
+        };
+        self.expect('}')?;
+        Ok((row, column))
+    }
+
+    fn index(&mut self) -> Result<u64, ProgramError> {
+        match self.next()? {
+            Token::Number(index) if *index > 0 => Ok(*index),
+            _ => Err(ProgramError::Syntax(self.line)),
+        }
+    }
+
+    fn statement(&mut self) -> Result<Statement, ProgramError> {
+        let target = match self.next()? {
+            Token::Table(table) => {
+                let explicit = self.peek() == Some(&Token::Symbol('{'));
+                let (row, column) = self.indices(*table)?;
+                match self.next()? {
+                    Token::Symbol('=') if !explicit => Target::Table(*table),
+                    Token::Set => Target::Cell { table: *table, row, column },
+                    _ => return Err(ProgramError::Syntax(self.line)),
+                }
+            }
+            Token::Name(name) => {
+                self.expect('=')?;
+                Target::Local(name.clone())
+            }
+            _ => return Err(ProgramError::Syntax(self.line)),
+        };
+        let expression = self.sum()?;
+        if self.position != self.tokens.len() {
+            return Err(ProgramError::Syntax(self.line));
+        }
+        Ok(Statement { target, expression })
+    }
+
+    fn sum(&mut self) -> Result<Expression, ProgramError> {
+        let mut expression = self.product()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol('+')) => Operator::Add,
+                Some(Token::Symbol('-')) => Operator::Subtract,
+                _ => return Ok(expression),
+            };
+            self.position += 1;
+            expression = Expression::Binary(operator, Box::new(expression), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expression, ProgramError> {
+        let mut expression = self.term()?;
+        loop {
+            let operator = match self.peek() {
+                Some(Token::Symbol('*')) => Operator::Multiply,
+                Some(Token::Symbol('/')) => Operator::Divide,
+                _ => return Ok(expression),
+            };
+            self.position += 1;
+            expression = Expression::Binary(operator, Box::new(expression), Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expression, ProgramError> {
+        Ok(match self.next()? {
+            Token::Number(number) => Expression::Constant(Value::from_u64(*number)),
+            Token::Decimal(mantissa, places) => {
+                let quantity = make_quantity(*mantissa as i64, -(*places as i64), 0);
+                Expression::Constant(Value::Number(quantity))
+            }
+            Token::Text(text) => Expression::Constant(Value::from_str(text)),
+            Token::Name(name) => Expression::Local(name.clone()),
+            Token::Table(table) => {
+                let (row, column) = self.indices(*table)?;
+                Expression::Cell { table: *table, row, column }
+            }
+            Token::Symbol('(') => {
+                let expression = self.sum()?;
+                self.expect(')')?;
+                expression
+            }
+            _ => return Err(ProgramError::Syntax(self.line)),
+        })
+    }
+}
+
+
+/// Compiles the blocks of a program.
+fn compile(source: &str) -> Result<Vec<Block>, ProgramError> {
+    let mut blocks = Vec::new();
+    let mut current: Option<Block> = None;
+    for (index, line) in source.lines().enumerate() {
+        let code = line.starts_with("  ") || line.starts_with('\t');
+        if !code || line.trim().is_empty() {
+            blocks.extend(current.take());
+            continue;
+        }
+        let tokens = tokenize(line, index + 1)?;
+        let statement = Parser { tokens: &tokens, position: 0, line: index + 1 }.statement()?;
+        let block = current.get_or_insert_with(|| Block {
+            name: String::from(line.trim()),
+            text: String::new(),
+            statements: Vec::new(),
+        });
+        block.text.push_str(line.trim());
+        block.text.push('\n');
+        block.statements.push(statement);
+    }
+    blocks.extend(current.take());
+    Ok(blocks)
+}
+
+fn index(index: u64) -> Option<Parameter> {
+    Some(Parameter::Index(Index::Index(index as _)))
+}
+
+/// Lowers the statements of a block to constraints of mech-core. Every value is kept in a
+/// table local to the block, with one cell.
+struct Lowering<'a> {
+    core: &'a mech_core::Core,
+    constraints: Vec<Constraint>,
+    /// The local table of each name, and the type of its value if it is known before the
+    /// block runs.
+    locals: BTreeMap<String, (u64, Option<ColumnType>)>,
+    /// The tables the block creates.
+    created: BTreeSet<u64>,
+    next_local: u64,
+}
+
+impl<'a> Lowering<'a> {
+    fn local(&mut self) -> u64 {
+        self.next_local += 1;
+        self.constraints.push(Constraint::NewTable{ id: TableId::Local(self.next_local), rows: 1, columns: 1 });
+        self.next_local
+    }
+
+    /// Checks that a cell is inside its table, if the table exists already.
+    fn check_range(&self, table: u64, row: u64, column: u64) -> Result<(), ProgramError> {
+        match self.core.store.get_table(table) {
+            Some(existing) if row > existing.rows as u64 || column > existing.columns as u64 => Err(ProgramError::OutOfRange),
+            _ => Ok(()),
+        }
+    }
+
+    /// Lowers an expression. Returns the local table holding its value, and the type of the
+    /// value if it is known before the block runs.
+    fn expression(&mut self, expression: &Expression) -> Result<(u64, Option<ColumnType>), ProgramError> {
+        match expression {
+            Expression::Constant(value) => {
+                let table = self.local();
+                let (row, column) = (Index::Index(1), Index::Index(1));
+                let (constraint, column_type) = match value {
+                    Value::Number(quantity) => (
+                        Constraint::Constant{ table: TableId::Local(table), row, column, value: *quantity, unit: None },
+                        ColumnType::Number,
+                    ),
+                    Value::String(text) => (
+                        Constraint::String{ table: TableId::Local(table), row, column, value: text.clone() },
+                        ColumnType::String,
+                    ),
+                    // the parser only makes numbers and strings
+                    _ => return Err(ProgramError::Type),
+                };
+                self.constraints.push(constraint);
+                Ok((table, Some(column_type)))
+            }
+            Expression::Cell { table, row, column } => {
+                self.check_range(*table, *row, *column)?;
+                let output = self.local();
+                self.constraints.push(Constraint::Scan{
+                    table: TableId::Global(*table),
+                    indices: vec![index(*row), index(*column)],
+                    output: TableId::Local(output),
+                });
+                let column_type = systables::find(*table)
+                    .and_then(|system| system.columns.get(*column as usize - 1))
+                    .map(|column| column.column_type);
+                Ok((output, column_type))
+            }
+            Expression::Local(name) => self.locals.get(name).cloned().ok_or(ProgramError::Undefined),
+            Expression::Binary(operator, left, right) => {
+                let (left, left_type) = self.expression(left)?;
+                let (right, right_type) = self.expression(right)?;
+                let numbers = |column_type: Option<ColumnType>| column_type.map_or(true, |column_type| column_type == ColumnType::Number);
+                if !numbers(left_type) || !numbers(right_type) {
+                    return Err(ProgramError::Type);
+                }
+                let output = self.local();
+                self.constraints.push(Constraint::Function{
+                    operation: operator.function(),
+                    parameters: vec![(TableId::Local(left), None, None), (TableId::Local(right), None, None)],
+                    output: vec![TableId::Local(output)],
+                });
+                Ok((output, Some(ColumnType::Number)))
+            }
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), ProgramError> {
+        let (value, value_type) = self.expression(&statement.expression)?;
+        let (table, row, column) = match statement.target {
+            Target::Local(ref name) => {
+                self.locals.insert(name.clone(), (value, value_type));
+                return Ok(());
+            }
+            Target::Table(table) => {
+                if self.core.store.get_table(table).is_none() && self.created.insert(table) {
+                    self.constraints.push(Constraint::NewTable{ id: TableId::Global(table), rows: 1, columns: 1 });
+                }
+                (table, 1, 1)
+            }
+            Target::Cell { table, row, column } => {
+                self.core.store.get_table(table).ok_or(ProgramError::NoTable(table))?;
+                self.check_range(table, row, column)?;
+                (table, row, column)
+            }
+        };
+        // mech-core sets the cells of blocks without asking, so the schema is checked now
+        if let Some(system) = systables::find(table) {
+            if value_type != Some(system.columns[column as usize - 1].column_type) {
+                return Err(ProgramError::Schema);
+            }
+        }
+        self.constraints.push(Constraint::Insert{
+            from: (TableId::Local(value), None, None),
+            to: (TableId::Global(table), index(row), index(column)),
+        });
+        Ok(())
+    }
+}
+
+/// Checks a block against the tables of `core` and lowers it to a block of mech-core.
+fn lower(block: &Block, core: &mech_core::Core) -> Result<mech_core::Block, ProgramError> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+    let mut lowering = Lowering {
+        core,
+        constraints: Vec::new(),
+        locals: BTreeMap::new(),
+        created: BTreeSet::new(),
+        next_local: 0,
+    };
+    for statement in block.statements.iter() {
+        lowering.statement(statement)?;
+    }
+    let mut lowered = mech_core::Block::new(BLOCK_TABLE_CAPACITY);
+    // the runtime keeps blocks by id, so ids stay unique across programs
+    lowered.id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    lowered.name = block.name.clone();
+    lowered.text = block.text.clone();
+    lowered.add_constraints((block.text.clone(), lowering.constraints));
+    Ok(lowered)
+}
+
+/// Compiles a program and registers its blocks, which run once right away. Returns the
+/// number of blocks. Nothing is registered if a block fails to compile.
+pub fn load(source: &str) -> Result<usize, ProgramError> {
+    let blocks = compile(source)?;
+    let lowered = {
+        let core = HiveCore.lock();
+        blocks.iter().map(|block| lower(block, &core)).collect::<Result<Vec<_>, _>>()?
+    };
+    let count = lowered.len();
+    persist::register(lowered);
+    Ok(count)
+}
+
+/// Loads the program in a file.
+pub fn load_file(path: &str) -> Result<usize, ProgramError> {
+    let source = vfs::open(path, OpenMode::Read)?.read_to_end()?;
+    let source = core::str::from_utf8(&source).map_err(|_| ProgramError::Syntax(0))?;
+    load(source)
+}
+
+/// Loads every program in `PROGRAM_DIRECTORY`. Returns the number of blocks loaded;
+/// programs that fail to compile are reported and skipped.
+pub fn init() -> usize {
+    let entries = match vfs::readdir(PROGRAM_DIRECTORY) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    let mut count = 0;
+    for entry in entries {
+        if entry.file_type != FileType::File || !entry.name.ends_with(PROGRAM_EXTENSION) {
+            continue;
+        }
+        let path = format!("{}/{}", PROGRAM_DIRECTORY, entry.name);
+        match load_file(&path) {
+            Ok(blocks) => count += blocks,
+            Err(error) => println!("loading {} failed: {:?}", path, error),
+        }
+    }
+    count
+}
+
+/// Lists the blocks registered with `HiveCore`, in the order they were loaded.
+pub fn blocks() -> Vec<BlockInfo> {
+    let core = HiveCore.lock();
+    let mut blocks: Vec<&mech_core::Block> = core.runtime.blocks.values().collect();
+    blocks.sort_by_key(|block| block.id);
+    blocks
+        .into_iter()
+        .map(|block| BlockInfo {
+            name: block.name.clone(),
+            errors: block.errors.len(),
+        })
+        .collect()
+}
+
+#[test_case]
+fn test_compile() {
+    serial_print!("test_compile... ");
+    let source = "# Doubling\nProse is skipped.\n  double = #timer{1, 1} * 2\n  #doubled = (double + 1) - 1\n\n  #out{2, 1} := \"bees\"\n";
+    let blocks = compile(source).expect("compiling failed");
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(blocks[0].name, "double = #timer{1, 1} * 2");
+    assert_eq!(blocks[0].statements.len(), 2);
+    assert_eq!(blocks[0].text, "double = #timer{1, 1} * 2\n#doubled = (double + 1) - 1\n");
+    assert_eq!(blocks[1].statements[0].target, Target::Cell { table: Hasher::hash_str("out"), row: 2, column: 1 });
+    assert_eq!(compile("  #a = 1 +").err(), Some(ProgramError::Syntax(1)));
+    assert_eq!(compile("\n  #a{1, 1} = 2").err(), Some(ProgramError::Syntax(2)));
+    assert_eq!(compile("  #a = $").err(), Some(ProgramError::Syntax(1)));
+    // only system tables have named columns
+    let blocks = compile("  #pci-vendor = #pci{1, vendor-id}").expect("compiling failed");
+    assert_eq!(blocks[0].statements[0].expression, Expression::Cell { table: Hasher::hash_str("pci"), row: 1, column: 4 });
+    assert_eq!(compile("  #a = #pci{1, vendor}").err(), Some(ProgramError::Syntax(1)));
+    assert_eq!(compile("  #a = #b{1, vendor-id}").err(), Some(ProgramError::Syntax(1)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_tokenize_names() {
+    serial_print!("test_tokenize_names... ");
+    let tokens = tokenize("#hive/stats = tick-count - 1", 1).expect("tokenizing failed");
+    assert_eq!(tokens, vec![
+        Token::Table(Hasher::hash_str("hive/stats")),
+        Token::Symbol('='),
+        Token::Name(String::from("tick-count")),
+        Token::Symbol('-'),
+        Token::Number(1),
+    ]);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_tokenize_decimals() {
+    serial_print!("test_tokenize_decimals... ");
+    let tokens = tokenize("x = 12.05 * 3.", 1);
+    assert_eq!(tokens.err(), Some(ProgramError::Syntax(1)));
+    let tokens = tokenize("x = 12.05 * 3", 1).expect("tokenizing failed");
+    assert_eq!(tokens[2], Token::Decimal(1205, 2));
+    assert_eq!(tokens[4], Token::Number(3));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_check_blocks() {
+    serial_print!("test_check_blocks... ");
+    let check = |source: &str| {
+        let blocks = compile(source).expect("compiling failed");
+        lower(&blocks[0], &HiveCore.lock()).err()
+    };
+    assert_eq!(check("  #a = b + 1"), Some(ProgramError::Undefined));
+    assert_eq!(check("  #a = \"bees\" * 2"), Some(ProgramError::Type));
+    assert_eq!(check("  #missing{1, 1} := 1"), Some(ProgramError::NoTable(Hasher::hash_str("missing"))));
+    assert_eq!(check("  #a = #timer{2, 1}"), Some(ProgramError::OutOfRange));
+    assert_eq!(check("  #timer{1, ticks} := \"soon\""), Some(ProgramError::Schema));
+    assert_eq!(check("  #power = 5"), Some(ProgramError::Schema));
+    assert_eq!(check("  #power = \"reboot\""), None);
+    assert_eq!(check("  half = #timer{1, ticks} / 2\n  #timer/half = half"), None);
+    serial_println!("[ok]");
+}
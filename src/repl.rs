@@ -0,0 +1,107 @@
+//! A command prompt on the keyboard and the screen.
+//!
+//! The keyboard task feeds typed characters to a `Repl`, which echoes them and runs the line
+//! as a command when enter is pressed. A line is a command name and its arguments,
+//! separated by spaces.
+
+use crate::{print, println, stats};
+use alloc::string::String;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+const PROMPT: &str = "hive> ";
+const BACKSPACE: char = '\u{8}';
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&str),
+}
+
+static COMMANDS: [Command; 2] = [
+    Command {
+        name: "help",
+        help: "lists the commands",
+        run: help,
+    },
+    Command {
+        name: "stats",
+        help: "prints the counters of #hive/stats",
+        run: print_stats,
+    },
+];
+
+fn help(_arguments: &str) {
+    for command in COMMANDS.iter() {
+        println!("{:8}{}", command.name, command.help);
+    }
+}
+
+fn print_stats(_arguments: &str) {
+    stats::print();
+}
+
+fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Runs a command line. Empty lines do nothing.
+pub fn execute(line: &str) {
+    let line = line.trim();
+    if line.is_empty() {
+        return;
+    }
+    let (name, arguments) = match line.find(' ') {
+        Some(space) => (&line[..space], line[space + 1..].trim()),
+        None => (line, ""),
+    };
+    match find(name) {
+        Some(command) => (command.run)(arguments),
+        None => println!("unknown command {}, try help", name),
+    }
+}
+
+/// The line being typed.
+pub struct Repl {
+    line: String,
+}
+
+impl Repl {
+    /// Prints the prompt.
+    pub fn new() -> Repl {
+        print!("{}", PROMPT);
+        Repl { line: String::new() }
+    }
+
+    /// Echoes a typed character, and runs the line and prompts again on enter.
+    pub fn key(&mut self, character: char) {
+        match character {
+            '\n' => {
+                println!();
+                execute(&self.line);
+                self.line.clear();
+                print!("{}", PROMPT);
+            }
+            BACKSPACE => {
+                if self.line.pop().is_some() {
+                    print!("{}", BACKSPACE);
+                }
+            }
+            character if !character.is_control() => {
+                self.line.push(character);
+                print!("{}", character);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[test_case]
+fn test_find_commands() {
+    serial_print!("test_find_commands... ");
+    assert!(find("stats").is_some());
+    assert!(find("help").is_some());
+    assert!(find("bees").is_none());
+    serial_println!("[ok]");
+}
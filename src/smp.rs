@@ -0,0 +1,357 @@
+//! Startup of the application processors.
+//!
+//! Every processor listed in the MADT is started with the INIT-SIPI-SIPI sequence. It
+//! begins in real mode in a trampoline copied below 1MiB, switches straight to long mode
+//! with the boot processor's page tables and control registers and then continues in
+//! `ap_entry`. Application processors record their APIC id in the `#cpus` table and then
+//! idle, woken only by inter-processor interrupts. Threads and tasks keep running on the
+//! boot processor.
+
+use crate::acpi::{self, AcpiError, Madt};
+use crate::interrupts::{self, InterruptIndex};
+use crate::memory::{self, FRAME_ALLOCATOR, LOW_MEMORY_END, MAPPER};
+use crate::percpu::{self, Cpu};
+use crate::sync::IrqMutex;
+use crate::systables;
+use crate::{apic, cpu, gdt, hlt_loop, println};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use lazy_static::lazy_static;
+use x86_64::registers::control::{Cr0, Cr3};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The maximum number of processors, including the boot processor.
+pub const MAX_CPUS: usize = 16;
+
+/// The size of the stack every application processor runs on.
+const AP_STACK_SIZE: usize = 64 * 1024;
+
+/// How long to wait for a started processor to come online, in milliseconds.
+const STARTUP_TIMEOUT_MS: u64 = 100;
+
+const IA32_EFER: u32 = 0xc000_0080;
+const EFER_LONG_MODE_ACTIVE: u64 = 1 << 10;
+const CR4_PCID_ENABLE: u64 = 1 << 17;
+
+global_asm!(
+    "
+    .pushsection .text.hivemind_trampoline, \"ax\"
+    .global hivemind_trampoline_start
+    .global hivemind_trampoline_end
+    .global hivemind_trampoline_gdt
+    .global hivemind_trampoline_gdt_pointer
+    .global hivemind_trampoline_far_pointer
+    .global hivemind_trampoline_long_mode
+    .global hivemind_trampoline_registers
+    .global hivemind_trampoline_stack
+
+    // Entered in real mode with `cs` pointing to the page the trampoline was copied to.
+    // Offsets relative to the start are valid in both modes, since the page is identity
+    // mapped.
+    .code16
+    hivemind_trampoline_start:
+        cli
+        cld
+        mov %cs, %ax
+        mov %ax, %ds
+        lgdtl (hivemind_trampoline_gdt_pointer - hivemind_trampoline_start)
+        movl (hivemind_trampoline_registers+8 - hivemind_trampoline_start), %eax
+        mov %eax, %cr4
+        movl (hivemind_trampoline_registers+16 - hivemind_trampoline_start), %eax
+        mov %eax, %cr3
+        movl $0xc0000080, %ecx
+        movl (hivemind_trampoline_registers+24 - hivemind_trampoline_start), %eax
+        xorl %edx, %edx
+        wrmsr
+        // enables protection and paging at once, which activates long mode
+        movl (hivemind_trampoline_registers - hivemind_trampoline_start), %eax
+        mov %eax, %cr0
+        ljmpl *(hivemind_trampoline_far_pointer - hivemind_trampoline_start)
+
+    .code64
+    hivemind_trampoline_long_mode:
+        xor %ax, %ax
+        mov %ax, %ds
+        mov %ax, %es
+        mov %ax, %ss
+        mov hivemind_trampoline_stack(%rip), %rsp
+        mov hivemind_trampoline_stack+16(%rip), %rdi
+        call *hivemind_trampoline_stack+8(%rip)
+        ud2
+
+    .align 16
+    hivemind_trampoline_gdt:
+        .quad 0
+        // 64-bit kernel code segment
+        .quad 0x00209a0000000000
+    hivemind_trampoline_gdt_pointer:
+        .word 15
+        .long 0
+    .align 8
+    hivemind_trampoline_far_pointer:
+        .long 0
+        .word 0x08
+    .align 8
+    // cr0, cr4, cr3 and efer of the boot processor
+    hivemind_trampoline_registers:
+        .quad 0, 0, 0, 0
+    // stack pointer, entry point and its argument
+    hivemind_trampoline_stack:
+        .quad 0, 0, 0
+    hivemind_trampoline_end:
+    .popsection
+    "
+);
+
+extern "C" {
+    static hivemind_trampoline_start: u8;
+    static hivemind_trampoline_end: u8;
+    static hivemind_trampoline_gdt: u8;
+    static hivemind_trampoline_gdt_pointer: u8;
+    static hivemind_trampoline_far_pointer: u8;
+    static hivemind_trampoline_long_mode: u8;
+    static hivemind_trampoline_registers: u8;
+    static hivemind_trampoline_stack: u8;
+}
+
+lazy_static! {
+    /// The processors that are online, in the order they were started.
+    static ref CPUS: IrqMutex<Vec<&'static Cpu>> = IrqMutex::new("CPUS", Vec::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    /// The MADT could not be read.
+    Acpi(AcpiError),
+    /// The APICs are not in use, so there is no way to send startup interrupts.
+    NoApic,
+    /// No free page below 1MiB for the trampoline.
+    NoLowMemory,
+    /// The trampoline page could not be identity mapped.
+    MapFailed,
+    /// The page tables lie above 4GiB, out of reach of the trampoline.
+    PageTableTooHigh,
+}
+
+/// Offsets of the trampoline's symbols from its start.
+struct Layout {
+    size: usize,
+    gdt: usize,
+    gdt_pointer: usize,
+    far_pointer: usize,
+    long_mode: usize,
+    registers: usize,
+    stack: usize,
+}
+
+impl Layout {
+    fn get() -> Layout {
+        unsafe {
+            let start = &hivemind_trampoline_start as *const u8 as usize;
+            let offset = |symbol: &u8| symbol as *const u8 as usize - start;
+            Layout {
+                size: offset(&hivemind_trampoline_end),
+                gdt: offset(&hivemind_trampoline_gdt),
+                gdt_pointer: offset(&hivemind_trampoline_gdt_pointer),
+                far_pointer: offset(&hivemind_trampoline_far_pointer),
+                long_mode: offset(&hivemind_trampoline_long_mode),
+                registers: offset(&hivemind_trampoline_registers),
+                stack: offset(&hivemind_trampoline_stack),
+            }
+        }
+    }
+}
+
+/// Returns the processors that are online, starting with the boot processor.
+pub fn cpus() -> Vec<&'static Cpu> {
+    CPUS.lock().clone()
+}
+
+/// Starts all application processors listed in the MADT.
+///
+/// Returns the number of processors online afterwards, including the boot processor.
+/// Processors that do not come up in time are reported and skipped. The APIC ids of the
+/// processors online are then written to `#cpus`. Requires the APICs, the installed memory
+/// manager and the heap.
+pub fn init(memory_map: &MemoryMap) -> Result<usize, SmpError> {
+    if !apic::is_enabled() {
+        return Err(SmpError::NoApic);
+    }
+    let madt = acpi::madt().map_err(SmpError::Acpi)?;
+    let boot_cpu = percpu::boot_cpu();
+    {
+        let mut cpus = CPUS.lock();
+        if cpus.is_empty() {
+            cpus.push(boot_cpu);
+        }
+    }
+    let started = start_application_processors(memory_map, &madt, boot_cpu);
+    let cpus = cpus();
+    record_apic_ids(&cpus);
+    started.map(|()| cpus.len())
+}
+
+fn start_application_processors(memory_map: &MemoryMap, madt: &Madt, boot_cpu: &Cpu) -> Result<(), SmpError> {
+    let frame = trampoline_frame(memory_map).ok_or(SmpError::NoLowMemory)?;
+    let mapped_here = identity_map(frame)?;
+    let result = install_trampoline(frame).map(|()| {
+        let targets = madt
+            .local_apics
+            .iter()
+            .filter(|entry| entry.enabled && entry.apic_id != boot_cpu.apic_id());
+        for entry in targets.take(MAX_CPUS - 1) {
+            start_cpu(frame, entry.apic_id);
+        }
+    });
+    if mapped_here {
+        unmap(frame);
+    }
+    result
+}
+
+/// Returns a usable page below 1MiB, other than the first one.
+///
+/// The frame allocator never hands out frames below 1MiB, so this page is free.
+fn trampoline_frame(memory_map: &MemoryMap) -> Option<PhysFrame> {
+    memory_map
+        .iter()
+        .filter(|region| region.region_type == MemoryRegionType::Usable)
+        .map(|region| (region.range.start_addr().max(0x1000), region.range.end_addr()))
+        .map(|(start, end)| ((start + 0xfff) & !0xfff, end.min(LOW_MEMORY_END)))
+        .find(|&(start, end)| start + 4096 <= end)
+        .map(|(start, _)| PhysFrame::containing_address(PhysAddr::new(start)))
+}
+
+fn identity_page(frame: PhysFrame) -> Page<Size4KiB> {
+    Page::containing_address(VirtAddr::new(frame.start_address().as_u64()))
+}
+
+/// Maps the trampoline page to itself, executable and read-only.
+///
+/// Returns whether the mapping was created here, as opposed to already existing.
+fn identity_map(frame: PhysFrame) -> Result<bool, SmpError> {
+    use x86_64::structures::paging::MapperAllSizes;
+
+    let page = identity_page(frame);
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let (mapper, frame_allocator) = match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return Err(SmpError::MapFailed),
+    };
+    if let Some(addr) = mapper.translate_addr(page.start_address()) {
+        return if addr == frame.start_address() {
+            Ok(false)
+        } else {
+            Err(SmpError::MapFailed)
+        };
+    }
+    unsafe {
+        Mapper::map_to(mapper, page, frame, PageTableFlags::PRESENT, frame_allocator)
+            .map_err(|_| SmpError::MapFailed)?
+            .flush()
+    };
+    Ok(true)
+}
+
+fn unmap(frame: PhysFrame) {
+    let page = identity_page(frame);
+    if let Some(mapper) = MAPPER.lock().as_mut() {
+        if let Ok((_, flush)) = Mapper::<Size4KiB>::unmap(mapper, page) {
+            flush.flush();
+        }
+    }
+}
+
+/// Copies the trampoline into `frame` and fills in its addresses and the boot processor's
+/// control registers.
+fn install_trampoline(frame: PhysFrame) -> Result<(), SmpError> {
+    let layout = Layout::get();
+    let base = frame.start_address().as_u64();
+    let (level_4_table, _) = Cr3::read();
+    let cr3 = level_4_table.start_address().as_u64();
+    if cr3 >= 1 << 32 {
+        return Err(SmpError::PageTableTooHigh);
+    }
+    let registers: [u64; 4] = [
+        Cr0::read().bits(),
+        unsafe { cpu::read_cr4() } & !CR4_PCID_ENABLE,
+        cr3,
+        unsafe { Msr::new(IA32_EFER).read() } & !EFER_LONG_MODE_ACTIVE,
+    ];
+
+    let copy: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe {
+        let start = &hivemind_trampoline_start as *const u8;
+        core::ptr::copy_nonoverlapping(start, copy, layout.size);
+        let write_u32 = |offset: usize, value: u64| {
+            (copy.add(offset) as *mut u32).write_unaligned(value as u32)
+        };
+        write_u32(layout.gdt_pointer + 2, base + layout.gdt as u64);
+        write_u32(layout.far_pointer, base + layout.long_mode as u64);
+        (copy.add(layout.registers) as *mut [u64; 4]).write_unaligned(registers);
+    }
+    Ok(())
+}
+
+/// Starts the processor with the given APIC id and waits until it is online.
+fn start_cpu(frame: PhysFrame, apic_id: u8) {
+    let index = CPUS.lock().len();
+    let cpu: &'static Cpu = Box::leak(Box::new(Cpu::new(index, apic_id)));
+    let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+    let stack_top = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+    let arguments: [u64; 3] = [stack_top, ap_entry as u64, cpu as *const Cpu as u64];
+    let layout = Layout::get();
+    unsafe {
+        let copy: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+        (copy.add(layout.stack) as *mut [u64; 3]).write_unaligned(arguments);
+    }
+
+    let page = (frame.start_address().as_u64() >> 12) as u8;
+    apic::send_init(apic_id);
+    apic::delay_us(10_000);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page);
+        apic::delay_us(200);
+    }
+    for _ in 0..STARTUP_TIMEOUT_MS {
+        if cpu.is_online() {
+            CPUS.lock().push(cpu);
+            return;
+        }
+        apic::delay_us(1000);
+    }
+    // the stack and data stay leaked, since the processor might still come up
+    println!("WARNING: processor with APIC id {} did not start", apic_id);
+}
+
+/// Where application processors continue in Rust, on their own stack.
+extern "C" fn ap_entry(cpu: &'static Cpu) -> ! {
+    percpu::install(cpu);
+    gdt::init_ap();
+    interrupts::init_idt();
+    apic::init_ap();
+    // the boot processor writes the id to `#cpus`: writing tables may block, and this
+    // processor has no thread to block
+    cpu.set_apic_id(apic::local_apic_id());
+    cpu.set_online();
+    x86_64::instructions::interrupts::enable();
+    hlt_loop();
+}
+
+/// Writes the APIC id of each processor to its row of `#cpus`.
+fn record_apic_ids(cpus: &[&Cpu]) {
+    let changes = cpus
+        .iter()
+        .map(|cpu| systables::CPUS.set_u64(cpu.index + 1, "apic-id", u64::from(cpu.apic_id())))
+        .collect();
+    systables::set(changes);
+}
+
+/// Sends the test vector to another processor.
+pub fn ping(cpu: &Cpu) {
+    apic::send_ipi(cpu.apic_id(), InterruptIndex::Ipi.as_u8());
+}
@@ -0,0 +1,113 @@
+//! Counters of how much work `HiveCore` does, published in `#hive/stats`.
+//!
+//! `persist` counts every transaction processed on `HiveCore`, the changes in it and the
+//! TSC cycles `process_transaction` took.
+
+use crate::allocator::{self, HEAP_SIZE};
+use crate::systables::{self, STATS};
+use crate::timer::{self, TimerId};
+use crate::{println, HiveCore};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Ticks between two updates of `#hive/stats`.
+const PUBLISH_PERIOD: u64 = 100;
+
+static TRANSACTIONS: AtomicU64 = AtomicU64::new(0);
+static CHANGES: AtomicU64 = AtomicU64::new(0);
+static CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// A snapshot of the counters and of the size of `HiveCore` and the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub transactions: u64,
+    pub changes: u64,
+    /// TSC cycles spent in `process_transaction`.
+    pub cycles: u64,
+    pub tables: u64,
+    pub cells: u64,
+    pub heap_used: u64,
+    pub heap_size: u64,
+}
+
+/// Returns the time stamp counter.
+pub fn cycles() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Counts a processed transaction.
+pub(crate) fn record(changes: usize, cycles: u64) {
+    TRANSACTIONS.fetch_add(1, Ordering::Relaxed);
+    CHANGES.fetch_add(changes as u64, Ordering::Relaxed);
+    CYCLES.fetch_add(cycles, Ordering::Relaxed);
+}
+
+/// Returns the current counters. Takes the `HiveCore` lock to count tables and cells.
+pub fn current() -> Stats {
+    let (tables, cells) = {
+        let core = HiveCore.lock();
+        let cells: u64 = core
+            .store
+            .tables
+            .values()
+            .map(|table| table.rows as u64 * table.columns as u64)
+            .sum();
+        (core.store.tables.len() as u64, cells)
+    };
+    Stats {
+        transactions: TRANSACTIONS.load(Ordering::Relaxed),
+        changes: CHANGES.load(Ordering::Relaxed),
+        cycles: CYCLES.load(Ordering::Relaxed),
+        tables,
+        cells,
+        heap_used: allocator::heap_used() as u64,
+        heap_size: HEAP_SIZE as u64,
+    }
+}
+
+/// Writes the current counters to `#hive/stats`.
+///
+/// The write is a transaction itself, so it is counted by the next one.
+pub fn publish() {
+    let stats = current();
+    systables::set(vec![
+        STATS.set_u64(1, "transactions", stats.transactions),
+        STATS.set_u64(1, "changes", stats.changes),
+        STATS.set_u64(1, "cycles", stats.cycles),
+        STATS.set_u64(1, "tables", stats.tables),
+        STATS.set_u64(1, "cells", stats.cells),
+        STATS.set_u64(1, "heap-used", stats.heap_used),
+        STATS.set_u64(1, "heap-size", stats.heap_size),
+    ]);
+}
+
+/// Updates `#hive/stats` periodically. Returns the timer, so it can be cancelled.
+pub fn publish_periodically() -> TimerId {
+    timer::every(PUBLISH_PERIOD, publish)
+}
+
+/// Prints the counters, for the `stats` command.
+pub fn print() {
+    let stats = current();
+    println!("transactions: {}", stats.transactions);
+    println!("changes:      {}", stats.changes);
+    println!("cycles:       {}", stats.cycles);
+    println!("tables:       {}", stats.tables);
+    println!("cells:        {}", stats.cells);
+    println!("heap:         {} of {} bytes", stats.heap_used, stats.heap_size);
+}
+
+#[test_case]
+fn test_record() {
+    serial_print!("test_record... ");
+    let before = current();
+    record(3, 1000);
+    let after = current();
+    assert_eq!(after.transactions, before.transactions + 1);
+    assert_eq!(after.changes, before.changes + 3);
+    assert_eq!(after.cycles, before.cycles + 1000);
+    assert!(cycles() > 0);
+    serial_println!("[ok]");
+}
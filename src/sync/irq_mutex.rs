@@ -0,0 +1,149 @@
+use super::{owner, report_deadlock, NO_HOLDER};
+use core::fmt;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// A spinlock that disables interrupts while it is held.
+///
+/// Interrupts are restored to their previous state when the guard is dropped, so nested
+/// locks behave as expected.
+pub struct IrqMutex<T> {
+    name: &'static str,
+    /// The `owner` holding the lock, `NO_HOLDER` if it is free.
+    holder: AtomicU64,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    /// Creates a lock. The name is reported when a deadlock is detected.
+    pub const fn new(name: &'static str, value: T) -> IrqMutex<T> {
+        IrqMutex {
+            name,
+            holder: AtomicU64::new(NO_HOLDER),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Disables interrupts and spins until the lock is acquired.
+    ///
+    /// In debug builds, this panics if the running thread holds the lock already.
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        loop {
+            if let Some(guard) = self.inner.try_lock() {
+                return self.guard(guard, were_enabled);
+            }
+            self.check_reentrancy();
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    /// Acquires the lock if it is free, without spinning.
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(self.guard(guard, were_enabled)),
+            None => {
+                if were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    fn guard<'a>(
+        &'a self,
+        guard: spin::MutexGuard<'a, T>,
+        were_enabled: bool,
+    ) -> IrqMutexGuard<'a, T> {
+        self.holder.store(owner(), Ordering::SeqCst);
+        IrqMutexGuard {
+            lock: self,
+            guard: ManuallyDrop::new(guard),
+            were_enabled,
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_reentrancy(&self) {
+        let holder = self.holder.load(Ordering::SeqCst);
+        if holder == owner() {
+            // unlock first, the panic message might need this lock to be printed
+            self.holder.store(NO_HOLDER, Ordering::SeqCst);
+            unsafe { self.inner.force_unlock() };
+            report_deadlock(self.name, holder);
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn check_reentrancy(&self) {}
+}
+
+impl<T: fmt::Debug> fmt::Debug for IrqMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => write!(f, "IrqMutex({:?}, {:?})", self.name, &*guard),
+            None => write!(f, "IrqMutex({:?}, <locked>)", self.name),
+        }
+    }
+}
+
+/// Releases the lock and restores the interrupt flag when dropped.
+pub struct IrqMutexGuard<'a, T> {
+    lock: &'a IrqMutex<T>,
+    guard: ManuallyDrop<spin::MutexGuard<'a, T>>,
+    were_enabled: bool,
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.holder.store(NO_HOLDER, Ordering::SeqCst);
+        // the lock must be released before interrupts are enabled again
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn test_irq_mutex_disables_interrupts() {
+    serial_print!("test_irq_mutex_disables_interrupts... ");
+    let lock = IrqMutex::new("test", 0);
+    assert!(interrupts::are_enabled());
+    {
+        let mut outer = lock.lock();
+        *outer += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(interrupts::are_enabled());
+    assert_eq!(*lock.lock(), 1);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,40 @@
+//! Locks and wait primitives.
+//!
+//! `IrqMutex` is a spinlock for state that interrupt handlers touch: it keeps interrupts
+//! disabled while held, so a handler can never spin on a lock held by the code it
+//! interrupted. `SleepMutex`, `Semaphore` and `WaitQueue` block the calling kernel thread
+//! instead of spinning and must not be used from interrupt handlers.
+//!
+//! In debug builds, the locks detect when a thread tries to take a lock it already holds
+//! and panic with the name of the lock and its holder instead of hanging.
+
+pub mod irq_mutex;
+pub mod semaphore;
+pub mod sleep_mutex;
+pub mod wait_queue;
+
+pub use self::irq_mutex::{IrqMutex, IrqMutexGuard};
+pub use self::semaphore::Semaphore;
+pub use self::sleep_mutex::{SleepMutex, SleepMutexGuard};
+pub use self::wait_queue::WaitQueue;
+
+use crate::{percpu, thread};
+
+/// Holder value of a lock that nobody holds.
+const NO_HOLDER: u64 = u64::max_value();
+
+/// Identifies the running thread across processors: the processor index in the upper 16
+/// bits and the thread id below.
+fn owner() -> u64 {
+    ((percpu::current().index as u64) << 48) | thread::current().as_u64()
+}
+
+/// Panics with a report of a lock that its holder tried to take again.
+fn report_deadlock(name: &str, holder: u64) -> ! {
+    panic!(
+        "deadlock: lock `{}` is already held by thread {} on cpu {}",
+        name,
+        holder & 0xffff_ffff_ffff,
+        holder >> 48
+    );
+}
@@ -0,0 +1,52 @@
+use super::WaitQueue;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// A counting semaphore that blocks the calling thread while no permit is available.
+///
+/// `release` may be called from interrupt handlers, `acquire` must not.
+pub struct Semaphore {
+    permits: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: AtomicUsize::new(permits),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Returns the number of available permits.
+    pub fn permits(&self) -> usize {
+        self.permits.load(Ordering::SeqCst)
+    }
+
+    /// Blocks until a permit is available and takes it.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Takes a permit if one is available, without blocking.
+    pub fn try_acquire(&self) -> bool {
+        let mut permits = self.permits.load(Ordering::SeqCst);
+        while permits > 0 {
+            match self.permits.compare_exchange(
+                permits,
+                permits - 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => permits = actual,
+            }
+        }
+        false
+    }
+
+    /// Returns a permit and wakes one waiter.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::SeqCst);
+        self.waiters.wake_one();
+    }
+}
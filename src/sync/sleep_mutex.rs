@@ -0,0 +1,88 @@
+use super::{owner, report_deadlock, WaitQueue, NO_HOLDER};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A mutex that blocks the calling thread while it waits for the lock.
+///
+/// Must not be used from interrupt handlers. Holding it with interrupts enabled is fine,
+/// so it suits long critical sections that allocate or wait for devices.
+pub struct SleepMutex<T> {
+    name: &'static str,
+    /// The `owner` holding the lock, `NO_HOLDER` if it is free.
+    holder: AtomicU64,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SleepMutex<T> {}
+unsafe impl<T: Send> Send for SleepMutex<T> {}
+
+impl<T> SleepMutex<T> {
+    /// Creates a mutex. The name is reported when a deadlock is detected.
+    pub fn new(name: &'static str, value: T) -> SleepMutex<T> {
+        SleepMutex {
+            name,
+            holder: AtomicU64::new(NO_HOLDER),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Blocks until the lock is acquired.
+    ///
+    /// In debug builds, this panics if the running thread holds the lock already.
+    pub fn lock(&self) -> SleepMutexGuard<T> {
+        let current = owner();
+        if cfg!(debug_assertions) && self.holder.load(Ordering::SeqCst) == current {
+            report_deadlock(self.name, current);
+        }
+        self.waiters.wait_until(|| self.acquire(current));
+        SleepMutexGuard { lock: self }
+    }
+
+    /// Acquires the lock if it is free, without blocking.
+    pub fn try_lock(&self) -> Option<SleepMutexGuard<T>> {
+        if self.acquire(owner()) {
+            Some(SleepMutexGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    fn acquire(&self, owner: u64) -> bool {
+        self.holder
+            .compare_exchange(NO_HOLDER, owner, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    }
+}
+
+/// Releases the lock and wakes the next waiter when dropped.
+pub struct SleepMutexGuard<'a, T> {
+    lock: &'a SleepMutex<T>,
+}
+
+impl<'a, T> Deref for SleepMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for SleepMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for SleepMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.holder.store(NO_HOLDER, Ordering::SeqCst);
+        self.lock.waiters.wake_one();
+    }
+}
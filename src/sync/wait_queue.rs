@@ -0,0 +1,65 @@
+use super::IrqMutex;
+use crate::task::queue::ArrayQueue;
+use crate::thread::{self, ThreadId};
+use x86_64::instructions::interrupts;
+
+/// A queue of kernel threads blocked until a condition holds.
+///
+/// The queue never allocates, so threads can be woken from interrupt handlers. A thread
+/// waits on at most one queue at a time, so it never holds more than `MAX_THREADS` entries.
+pub struct WaitQueue {
+    waiters: IrqMutex<ArrayQueue<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> WaitQueue {
+        WaitQueue {
+            waiters: IrqMutex::new("wait queue", ArrayQueue::new(thread::BOOT_THREAD)),
+        }
+    }
+
+    /// Blocks the running thread until `condition` returns `true`.
+    ///
+    /// The condition is checked with interrupts disabled, so a `wake_one` or `wake_all`
+    /// that follows a change of the condition cannot be lost. It is checked again after
+    /// every wakeup.
+    pub fn wait_until<F>(&self, mut condition: F)
+    where
+        F: FnMut() -> bool,
+    {
+        loop {
+            let were_enabled = interrupts::are_enabled();
+            interrupts::disable();
+            if condition() {
+                if were_enabled {
+                    interrupts::enable();
+                }
+                return;
+            }
+            self.waiters
+                .lock()
+                .push(thread::current())
+                .expect("wait queue full");
+            thread::block();
+            if were_enabled {
+                interrupts::enable();
+            }
+        }
+    }
+
+    /// Wakes the thread that waits longest. Returns `false` if no thread was waiting.
+    pub fn wake_one(&self) -> bool {
+        match self.waiters.lock().pop() {
+            Some(id) => {
+                thread::wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes all waiting threads.
+    pub fn wake_all(&self) {
+        while self.wake_one() {}
+    }
+}
@@ -0,0 +1,257 @@
+//! The tables the kernel writes itself, each defined once with its name, columns and
+//! capacity, so drivers and Mech programs agree on where things are.
+//!
+//! `init` creates them all. Creating tables allocates, so it runs once the heap is set up,
+//! right after `hivemind::init`. `persist` never restores them from the store: their writers
+//! fill them anew on every boot. Drivers build changes with the accessors of `SystemTable`,
+//! which check the row, the column and the type of the value, and write them with `set`.
+//! Writes to files in `/tables` are checked with `check`, and Mech programs setting cells of
+//! these tables when they are loaded. Mech programs may name the columns of these tables,
+//! like `#timer{1, ticks}`.
+
+use crate::persist;
+use crate::smp::MAX_CPUS;
+use crate::HiveCore;
+use alloc::vec::Vec;
+use mech_core::{Change, Hasher, Index, Value};
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// The type of the values in a column. Every column may also hold `Value::Empty`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Number,
+    String,
+    Bool,
+    Reference,
+}
+
+impl ColumnType {
+    /// Returns whether `value` may be stored in a column of this type.
+    pub fn admits(self, value: &Value) -> bool {
+        match (self, value) {
+            (_, Value::Empty) => true,
+            (ColumnType::Number, Value::Number(_)) => true,
+            (ColumnType::String, Value::String(_)) => true,
+            (ColumnType::Bool, Value::Bool(_)) => true,
+            (ColumnType::Reference, Value::Reference(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Column {
+    pub name: &'static str,
+    pub column_type: ColumnType,
+}
+
+/// A table the kernel writes. Its id is the hash of its name, like for tables of programs.
+#[derive(Debug)]
+pub struct SystemTable {
+    pub name: &'static str,
+    pub columns: &'static [Column],
+    /// The number of rows, which the table is created with.
+    pub capacity: usize,
+}
+
+impl SystemTable {
+    pub fn id(&self) -> u64 {
+        Hasher::hash_str(self.name)
+    }
+
+    /// Returns the one-based index of the column called `name`.
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name).map(|index| index + 1)
+    }
+
+    /// Returns the change creating the table.
+    pub fn create(&self) -> Change {
+        Change::NewTable{ id: self.id(), rows: self.capacity as _, columns: self.columns.len() as _ }
+    }
+
+    /// Returns the change setting a cell, with a one-based row.
+    ///
+    /// Panics if the row is beyond the capacity, the column does not exist or does not
+    /// admit the value: the schema is fixed, so that is a bug of the writer.
+    pub fn set(&self, row: usize, column: &str, value: Value) -> Change {
+        let index = match self.column(column) {
+            Some(index) => index,
+            None => panic!("#{} has no column {}", self.name, column),
+        };
+        assert!(row > 0 && row <= self.capacity, "row {} is outside #{}", row, self.name);
+        assert!(
+            self.columns[index - 1].column_type.admits(&value),
+            "{:?} does not belong in #{}{{{}, {}}}", value, self.name, row, column
+        );
+        Change::Set{table: self.id(), row: Index::Index(row as _), column: Index::Index(index as _), value}
+    }
+
+    pub fn set_u64(&self, row: usize, column: &str, value: u64) -> Change {
+        self.set(row, column, Value::from_u64(value))
+    }
+
+    pub fn set_str(&self, row: usize, column: &str, value: &str) -> Change {
+        self.set(row, column, Value::from_str(value))
+    }
+
+    /// Returns the value of a cell, or `None` if the table or the cell does not exist.
+    pub fn get(&self, row: usize, column: &str) -> Option<Value> {
+        let index = self.column(column)?;
+        let core = HiveCore.lock();
+        let table = core.store.get_table(self.id())?;
+        let value = table.data.get(index - 1)?.get(row.checked_sub(1)?)?;
+        Some(value.clone())
+    }
+
+    /// Returns the value of a cell of a `Number` column.
+    pub fn get_u64(&self, row: usize, column: &str) -> Option<u64> {
+        self.get(row, column).and_then(|value| value.as_u64())
+    }
+}
+
+macro_rules! columns {
+    ($($name:expr => $column_type:ident),*) => {
+        &[$(Column { name: $name, column_type: ColumnType::$column_type }),*]
+    };
+}
+
+/// The number of timer interrupts since boot, written by `task::ticks`.
+pub static TIMER: SystemTable = SystemTable {
+    name: "timer",
+    columns: columns!["ticks" => Number],
+    capacity: 1,
+};
+
+/// The last character typed, written by `task::keyboard`.
+pub static KEYPRESS: SystemTable = SystemTable {
+    name: "keypress",
+    columns: columns!["character" => String],
+    capacity: 1,
+};
+
+/// `"shutdown"` or `"reboot"`, written by anyone and acted on by `power`.
+pub static POWER: SystemTable = SystemTable {
+    name: "power",
+    columns: columns!["request" => String],
+    capacity: 1,
+};
+
+/// The APIC id of each processor, written by `smp` as they come up.
+pub static CPUS: SystemTable = SystemTable {
+    name: "cpus",
+    columns: columns!["apic-id" => Number],
+    capacity: MAX_CPUS,
+};
+
+/// One row per PCI function, written by `pci`.
+pub static PCI: SystemTable = SystemTable {
+    name: "pci",
+    columns: columns![
+        "bus" => Number,
+        "device" => Number,
+        "function" => Number,
+        "vendor-id" => Number,
+        "device-id" => Number,
+        "class" => Number,
+        "subclass" => Number,
+        "interrupt-line" => Number
+    ],
+    capacity: 64,
+};
+
+/// Counters of the Mech core and the heap, written by `stats`.
+pub static STATS: SystemTable = SystemTable {
+    name: "hive/stats",
+    columns: columns![
+        "transactions" => Number,
+        "changes" => Number,
+        "cycles" => Number,
+        "tables" => Number,
+        "cells" => Number,
+        "heap-used" => Number,
+        "heap-size" => Number
+    ],
+    capacity: 1,
+};
+
+/// All system tables.
+pub static TABLES: [&SystemTable; 6] = [&TIMER, &KEYPRESS, &POWER, &CPUS, &PCI, &STATS];
+
+/// Processes changes built with the accessors of `SystemTable`.
+///
+/// The changes are not journaled: system tables describe the running machine, so nothing
+/// in them is worth restoring after a reboot.
+pub fn set(changes: Vec<Change>) {
+    persist::transact_unjournaled(changes).expect("system tables are set by position");
+}
+
+/// Creates the system tables that do not exist yet and returns how many it created.
+pub fn init() -> usize {
+    let changes: Vec<Change> = {
+        let core = HiveCore.lock();
+        TABLES
+            .iter()
+            .filter(|table| core.store.get_table(table.id()).is_none())
+            .map(|table| table.create())
+            .collect()
+    };
+    let created = changes.len();
+    if created > 0 {
+        set(changes);
+    }
+    created
+}
+
+/// Returns the system table with the given id.
+pub fn find(id: u64) -> Option<&'static SystemTable> {
+    TABLES.iter().cloned().find(|table| table.id() == id)
+}
+
+/// Returns whether a change from outside the kernel fits the schema: it may not create a
+/// system table again, and a cell it sets must be within the capacity and the columns, and
+/// its column must admit the value. Changes to other tables always fit.
+pub fn check(change: &Change) -> bool {
+    match change {
+        Change::NewTable { id, .. } => find(*id).is_none(),
+        Change::Set { table, row: Index::Index(row), column: Index::Index(column), value } => {
+            match find(*table) {
+                Some(table) => {
+                    let (row, column) = (*row as usize, *column as usize);
+                    row > 0 && row <= table.capacity && column > 0 && column <= table.columns.len()
+                        && table.columns[column - 1].column_type.admits(value)
+                }
+                None => true,
+            }
+        }
+        Change::Set { table, .. } => find(*table).is_none(),
+        _ => true,
+    }
+}
+
+#[test_case]
+fn test_schema() {
+    serial_print!("test_schema... ");
+    assert_eq!(PCI.column("vendor-id"), Some(4));
+    assert_eq!(PCI.column("vendor"), None);
+    assert_eq!(find(Hasher::hash_str("cpus")).map(|table| table.name), Some("cpus"));
+    assert!(find(Hasher::hash_str("bees")).is_none());
+    assert!(ColumnType::Number.admits(&Value::Empty));
+    assert!(!ColumnType::Number.admits(&Value::from_str("1")));
+    match TIMER.set_u64(1, "ticks", 5) {
+        Change::Set{table, row: Index::Index(1), column: Index::Index(1), value} => {
+            assert_eq!(table, TIMER.id());
+            assert_eq!(value, Value::from_u64(5));
+        }
+        _ => panic!("not a change setting the tick count"),
+    }
+    assert!(check(&POWER.set_str(1, "request", "reboot")));
+    assert!(!check(&POWER.create()));
+    let set = |row: u64, column: u64, value: Value| Change::Set{table: POWER.id(), row: Index::Index(row as _), column: Index::Index(column as _), value};
+    assert!(!check(&set(1, 1, Value::from_u64(1))));
+    assert!(!check(&set(2, 1, Value::from_str("reboot"))));
+    assert!(!check(&set(1, 2, Value::from_str("reboot"))));
+    assert!(check(&Change::NewTable{ id: Hasher::hash_str("bees"), rows: 1, columns: 1 }));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,301 @@
+//! The tables of `HiveCore` as files, one per table, to be mounted at `/tables`.
+//!
+//! Reading a table file yields its cells as CSV, one line per row. Numbers are shown as
+//! integers, or as `q` and their raw quantity in hex if they are not integers. Strings are
+//! quoted, booleans shown as `true` or `false`, references as `@` and the referenced id in
+//! hex, and empty cells as nothing.
+//!
+//! Writing a table file sets cells: every line is `row,column,value`, with one-based
+//! indices and the value written like it is read. The lines of one write are applied as one
+//! transaction, which is journaled like all other transactions.
+//!
+//! Tables are named by the name their id is hashed from. `register_name` adds a name;
+//! tables without a known name are listed by their id in hex.
+
+use crate::sync::IrqMutex;
+use crate::{persist, systables};
+use crate::vfs::{DirEntry, FileSystem, FileType, Inode, Metadata, VfsError};
+use crate::{println, HiveCore};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use lazy_static::lazy_static;
+use mech_core::{Change, Hasher, Index, Value};
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+lazy_static! {
+    static ref NAMES: IrqMutex<BTreeMap<u64, String>> = IrqMutex::new("TABLE NAMES", BTreeMap::new());
+}
+
+/// Adds the name of a table, so its file is listed under that name. Returns the table id.
+pub fn register_name(name: &str) -> u64 {
+    let id = Hasher::hash_str(name);
+    NAMES.lock().entry(id).or_insert_with(|| String::from(name));
+    id
+}
+
+/// Adds the names of the system tables.
+pub fn init() {
+    for table in systables::TABLES.iter() {
+        register_name(table.name);
+    }
+}
+
+/// The file name of a table id.
+fn file_name(id: u64) -> String {
+    match NAMES.lock().get(&id) {
+        Some(name) => name.clone(),
+        None => format!("{:016x}", id),
+    }
+}
+
+/// The table id of a file name: a name hashed, or an id in hex.
+fn table_id(name: &str) -> u64 {
+    if name.len() == 16 {
+        if let Ok(id) = u64::from_str_radix(name, 16) {
+            return id;
+        }
+    }
+    Hasher::hash_str(name)
+}
+
+fn render_value(value: &Value, out: &mut String) {
+    match value {
+        Value::Empty => {}
+        Value::Number(quantity) => {
+            let _ = match value.as_u64() {
+                Some(integer) => write!(out, "{}", integer),
+                // shown exactly, so writing it back keeps the cell as it is
+                None => write!(out, "q{:016x}", quantity),
+            };
+        }
+        Value::String(string) => {
+            out.push('"');
+            for character in string.chars() {
+                if character == '"' {
+                    out.push('"');
+                }
+                out.push(character);
+            }
+            out.push('"');
+        }
+        Value::Bool(boolean) => out.push_str(if *boolean { "true" } else { "false" }),
+        Value::Reference(id) => {
+            let _ = write!(out, "@{:016x}", id);
+        }
+    }
+}
+
+/// Renders a table as CSV. `Table::data` holds one vector per column.
+fn render(id: u64) -> Result<Vec<u8>, VfsError> {
+    let core = HiveCore.lock();
+    let table = core.store.get_table(id).ok_or(VfsError::NotFound)?;
+    let mut out = String::new();
+    for row in 0..table.rows as usize {
+        for column in 0..table.columns as usize {
+            if column > 0 {
+                out.push(',');
+            }
+            if let Some(value) = table.data.get(column).and_then(|column| column.get(row)) {
+                render_value(value, &mut out);
+            }
+        }
+        out.push('\n');
+    }
+    Ok(out.into_bytes())
+}
+
+/// Parses a value written like `render_value` writes it.
+fn parse_value(text: &str) -> Option<Value> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Some(Value::Empty);
+    }
+    if text.len() >= 2 && text.starts_with('"') && text.ends_with('"') {
+        let inner = &text[1..text.len() - 1];
+        // quotes inside are doubled
+        if inner.replace("\"\"", "").contains('"') {
+            return None;
+        }
+        return Some(Value::from_str(&inner.replace("\"\"", "\"")));
+    }
+    if text.starts_with('@') {
+        return u64::from_str_radix(&text[1..], 16).ok().map(Value::Reference);
+    }
+    if text.starts_with('q') {
+        return u64::from_str_radix(&text[1..], 16).ok().map(Value::Number);
+    }
+    match text {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ => text.parse::<u64>().ok().map(Value::from_u64),
+    }
+}
+
+/// Parses a one-based index.
+fn parse_index(text: &str) -> Option<u64> {
+    match text.trim().parse::<u64>() {
+        Ok(0) | Err(_) => None,
+        Ok(index) => Some(index),
+    }
+}
+
+/// A cell to set: one-based row and column, and the value.
+type Assignment = (u64, u64, Value);
+
+/// Parses `row,column,value` lines. Empty lines are skipped.
+fn parse_assignments(text: &str) -> Option<Vec<Assignment>> {
+    let mut assignments = Vec::new();
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let mut fields = line.splitn(3, ',');
+        let row = parse_index(fields.next()?)?;
+        let column = parse_index(fields.next()?)?;
+        let value = parse_value(fields.next()?)?;
+        assignments.push((row, column, value));
+    }
+    Some(assignments)
+}
+
+/// The file system to mount at `/tables`.
+pub struct TableFs;
+
+impl FileSystem for TableFs {
+    fn name(&self) -> &str {
+        "tablefs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
+
+struct Root;
+
+impl Inode for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::Directory,
+            size: 0,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let id = table_id(name);
+        if HiveCore.lock().store.get_table(id).is_none() {
+            return Err(VfsError::NotFound);
+        }
+        Ok(Arc::new(TableFile(id)))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let ids: Vec<u64> = HiveCore.lock().store.tables.keys().cloned().collect();
+        let mut entries: Vec<DirEntry> = ids
+            .into_iter()
+            .map(|id| DirEntry {
+                name: file_name(id),
+                file_type: FileType::File,
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::Unsupported)
+    }
+}
+
+/// A table, rendered anew for every read.
+struct TableFile(u64);
+
+impl Inode for TableFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            file_type: FileType::File,
+            size: render(self.0).map_or(0, |contents| contents.len() as u64),
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let contents = render(self.0)?;
+        if offset >= contents.len() as u64 {
+            return Ok(0);
+        }
+        let rest = &contents[offset as usize..];
+        let count = rest.len().min(buffer.len());
+        buffer[..count].copy_from_slice(&rest[..count]);
+        Ok(count)
+    }
+
+    /// Applies the assignments in `buffer`, which must hold whole lines. The offset is
+    /// ignored. Nothing is set if a line is invalid, outside the table or, for a system
+    /// table, of the wrong type for its column.
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        let text = core::str::from_utf8(buffer).map_err(|_| VfsError::InvalidArgument)?;
+        let assignments = parse_assignments(text).ok_or(VfsError::InvalidArgument)?;
+        let (rows, columns) = match HiveCore.lock().store.get_table(self.0) {
+            Some(table) => (table.rows as u64, table.columns as u64),
+            None => return Err(VfsError::NotFound),
+        };
+        if assignments.iter().any(|&(row, column, _)| row > rows || column > columns) {
+            return Err(VfsError::InvalidArgument);
+        }
+        let table = self.0;
+        let changes: Vec<Change> = assignments
+            .into_iter()
+            .map(|(row, column, value)| Change::Set{table, row: Index::Index(row as _), column: Index::Index(column as _), value})
+            .collect();
+        if !changes.iter().all(systables::check) {
+            return Err(VfsError::InvalidArgument);
+        }
+        if !changes.is_empty() {
+            // the cells are set even if journaling fails, like other writers' cells
+            if let Err(error) = persist::transact(changes) {
+                println!("journaling a table write failed: {:?}", error);
+            }
+        }
+        Ok(buffer.len())
+    }
+
+    /// Tables keep their size; truncating only lets `open` callers start over.
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_values_round_trip() {
+    serial_print!("test_values_round_trip... ");
+    let values = [
+        Value::from_u64(1234),
+        Value::from_str("say \"bees\", twice"),
+        Value::Bool(false),
+        Value::Reference(0xdead_beef),
+        Value::Empty,
+    ];
+    for value in values.iter() {
+        let mut text = String::new();
+        render_value(value, &mut text);
+        assert_eq!(parse_value(&text).as_ref(), Some(value));
+    }
+    assert_eq!(parse_value("\"unbalanced\"quote\""), None);
+    assert_eq!(parse_value("twelve"), None);
+    assert_eq!(parse_value("q00000000000004d2"), Some(Value::Number(0x4d2)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn test_parse_assignments() {
+    serial_print!("test_parse_assignments... ");
+    let assignments = parse_assignments("1,2,42\n\n3,1,\"a,b\"\n").expect("parsing failed");
+    assert_eq!(assignments, vec![(1, 2, Value::from_u64(42)), (3, 1, Value::from_str("a,b"))]);
+    assert!(parse_assignments("0,1,1").is_none());
+    assert!(parse_assignments("1,1").is_none());
+    assert_eq!(table_id("timer"), Hasher::hash_str("timer"));
+    assert_eq!(table_id("00000000000000ff"), 0xff);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,140 @@
+use super::queue::ArrayQueue;
+use super::{Task, TaskId};
+use crate::println;
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+lazy_static! {
+    /// Ids of the tasks that were woken and need to be polled.
+    ///
+    /// Wakers push to this queue from interrupt handlers, so all other accesses happen with
+    /// interrupts disabled.
+    static ref READY: Mutex<ArrayQueue<TaskId>> = Mutex::new(ArrayQueue::new(TaskId(0)));
+}
+
+/// Runs kernel tasks, halting the CPU while none of them is ready.
+///
+/// All executors share a single ready queue, so there must only be one of them.
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    wakers: BTreeMap<TaskId, Waker>,
+}
+
+impl Executor {
+    pub fn new() -> Executor {
+        Executor {
+            tasks: BTreeMap::new(),
+            wakers: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a task and schedules its first poll.
+    pub fn spawn(&mut self, task: Task) {
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("task with id {:?} already spawned", id);
+        }
+        interrupts::without_interrupts(|| schedule(id));
+    }
+
+    /// Polls tasks until all of them completed.
+    pub fn run_until_complete(&mut self) {
+        while !self.tasks.is_empty() {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    /// Spawns `future` as a task and polls all tasks until it completed.
+    ///
+    /// Other tasks may still be pending afterwards.
+    pub fn block_on(&mut self, future: impl Future<Output = ()> + 'static) {
+        let task = Task::new(future);
+        let id = task.id;
+        self.spawn(task);
+        while self.tasks.contains_key(&id) {
+            self.run_ready_tasks();
+            if self.tasks.contains_key(&id) {
+                self.sleep_if_idle();
+            }
+        }
+    }
+
+    /// Polls tasks forever.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        while let Some(id) = interrupts::without_interrupts(|| READY.lock().pop()) {
+            let task = match self.tasks.get_mut(&id) {
+                Some(task) => task,
+                // the task completed after it was woken
+                None => continue,
+            };
+            let waker = self.wakers.entry(id).or_insert_with(|| task_waker(id));
+            let mut context = Context::from_waker(waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                self.tasks.remove(&id);
+                self.wakers.remove(&id);
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // disable interrupts first, so that a wakeup between the check and `hlt` is not lost
+        interrupts::disable();
+        if READY.lock().is_empty() {
+            enable_interrupts_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Atomically enables interrupts and halts until the next one arrives.
+///
+/// `sti` only takes effect after the following instruction, so no interrupt can slip in
+/// between the two.
+fn enable_interrupts_and_hlt() {
+    unsafe { asm!("sti; hlt" :::: "volatile") };
+}
+
+/// Pushes the task to the ready queue. Must be called with interrupts disabled.
+fn schedule(id: TaskId) {
+    if READY.lock().push(id).is_err() {
+        println!("WARNING: task queue full; dropping wakeup of {:?}", id);
+    }
+}
+
+/// Returns a waker that schedules the task with the given id.
+///
+/// The task id is stored directly in the data pointer, so creating, cloning and waking the
+/// waker never allocates, which makes it usable from interrupt handlers.
+fn task_waker(id: TaskId) -> Waker {
+    unsafe { Waker::from_raw(raw_waker(id.0 as *const ())) }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake_task, wake_task, drop_waker);
+
+fn raw_waker(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    raw_waker(data)
+}
+
+unsafe fn wake_task(data: *const ()) {
+    let id = TaskId(data as u64);
+    interrupts::without_interrupts(|| schedule(id));
+}
+
+unsafe fn drop_waker(_data: *const ()) {}
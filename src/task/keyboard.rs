@@ -0,0 +1,87 @@
+use super::queue::ArrayQueue;
+use super::{for_each, Stream, WakerSlot};
+use crate::repl::Repl;
+use crate::systables::KEYPRESS;
+use crate::{persist, print, println};
+use alloc::string::ToString;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, Keyboard, ScancodeSet1};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+lazy_static! {
+    static ref SCANCODES: Mutex<ArrayQueue<u8>> = Mutex::new(ArrayQueue::new(0));
+}
+
+static WAKER: WakerSlot = WakerSlot::new();
+
+/// Called by the keyboard interrupt handler.
+///
+/// Must not block or allocate.
+pub(crate) fn add_scancode(scancode: u8) {
+    if SCANCODES.lock().push(scancode).is_err() {
+        println!("WARNING: scancode queue full; dropping keyboard input");
+    } else {
+        WAKER.wake();
+    }
+}
+
+/// The scancodes received by the keyboard interrupt handler.
+///
+/// There can only be one stream at a time, since the handler wakes a single task.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> ScancodeStream {
+        static CREATED: AtomicBool = AtomicBool::new(false);
+        if CREATED.swap(true, Ordering::SeqCst) {
+            panic!("ScancodeStream::new should only be called once");
+        }
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        let pop = || interrupts::without_interrupts(|| SCANCODES.lock().pop());
+        if let Some(scancode) = pop() {
+            return Poll::Ready(Some(scancode));
+        }
+        WAKER.register(context.waker());
+        // a scancode might have arrived before the waker was registered
+        match pop() {
+            Some(scancode) => Poll::Ready(Some(scancode)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Returns a future that decodes scancodes, writes typed characters to `#keypress` and
+/// passes them to the command prompt.
+pub fn process_keypresses() -> impl Future<Output = ()> {
+    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1);
+    let mut repl = Repl::new();
+    for_each(ScancodeStream::new(), move |scancode| {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
+            if let Some(key) = keyboard.process_keyevent(key_event) {
+                match key {
+                    DecodedKey::Unicode(character) => {
+                        if let Err(error) = persist::transact(vec![KEYPRESS.set_str(1, "character", &character.to_string())]) {
+                            println!("journaling a keypress failed: {:?}", error);
+                        }
+                        repl.key(character);
+                    },
+                    DecodedKey::RawKey(key) => print!("{:?}", key),
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,121 @@
+use alloc::boxed::Box;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub mod executor;
+pub mod keyboard;
+pub mod queue;
+pub mod ticks;
+
+/// A kernel task: a pinned future that is polled by the `Executor` until it completes.
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    /// Create a task from the given future.
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    /// Returns the identifier of the task.
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> TaskId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// An asynchronous sequence of values, the async counterpart of `Iterator`.
+pub trait Stream {
+    type Item;
+
+    /// Returns the next value if it is available and registers the waker otherwise.
+    ///
+    /// `Poll::Ready(None)` means that the stream is exhausted.
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<Self::Item>>;
+}
+
+/// A future that calls a closure for every value of a stream.
+pub struct ForEach<S, F> {
+    stream: S,
+    f: F,
+}
+
+/// Returns a future that calls `f` for every value of `stream` and completes with it.
+pub fn for_each<S, F>(stream: S, f: F) -> ForEach<S, F>
+where
+    S: Stream + Unpin,
+    F: FnMut(S::Item) + Unpin,
+{
+    ForEach { stream, f }
+}
+
+impl<S, F> Future for ForEach<S, F>
+where
+    S: Stream + Unpin,
+    F: FnMut(S::Item) + Unpin,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        loop {
+            match Pin::new(&mut this.stream).poll_next(context) {
+                Poll::Ready(Some(item)) => (this.f)(item),
+                Poll::Ready(None) => return Poll::Ready(()),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Holds the waker of the task that waits on an interrupt-driven source.
+///
+/// Interrupt handlers call `wake`, so the slot is only touched with interrupts disabled
+/// from task context.
+pub struct WakerSlot {
+    waker: Mutex<Option<Waker>>,
+}
+
+impl WakerSlot {
+    pub const fn new() -> WakerSlot {
+        WakerSlot {
+            waker: Mutex::new(None),
+        }
+    }
+
+    /// Stores the waker to be woken by the next call to `wake`.
+    pub fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            *self.waker.lock() = Some(waker.clone());
+        });
+    }
+
+    /// Wakes the registered task, if any. Safe to call from interrupt handlers.
+    pub fn wake(&self) {
+        if let Some(waker) = self.waker.lock().as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+}
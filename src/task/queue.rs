@@ -0,0 +1,70 @@
+/// The number of elements an `ArrayQueue` can hold.
+pub const QUEUE_CAPACITY: usize = 128;
+
+/// A fixed-capacity FIFO queue.
+///
+/// Pushing never allocates, so the queue can be filled from interrupt handlers while the
+/// interrupted code holds the heap lock.
+pub struct ArrayQueue<T> {
+    buffer: [T; QUEUE_CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy> ArrayQueue<T> {
+    /// Create an empty queue. `fill` is only used to initialize the storage.
+    pub fn new(fill: T) -> ArrayQueue<T> {
+        ArrayQueue {
+            buffer: [fill; QUEUE_CAPACITY],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Appends a value, or returns it back if the queue is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.len == QUEUE_CAPACITY {
+            return Err(value);
+        }
+        self.buffer[(self.head + self.len) % QUEUE_CAPACITY] = value;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Removes the oldest value.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = self.buffer[self.head];
+        self.head = (self.head + 1) % QUEUE_CAPACITY;
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+#[test_case]
+fn test_queue_fifo_order() {
+    serial_print!("test_queue_fifo_order... ");
+    let mut queue = ArrayQueue::new(0u8);
+    for i in 0..QUEUE_CAPACITY {
+        assert_eq!(queue.push(i as u8), Ok(()));
+    }
+    assert_eq!(queue.push(0xff), Err(0xff));
+    for i in 0..QUEUE_CAPACITY {
+        assert_eq!(queue.pop(), Some(i as u8));
+    }
+    assert_eq!(queue.pop(), None);
+    serial_println!("[ok]");
+}
@@ -0,0 +1,68 @@
+use super::{for_each, Stream, WakerSlot};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use systables::{self, TIMER};
+use Time;
+
+static WAKER: WakerSlot = WakerSlot::new();
+
+/// Called by the timer interrupt handler after it advanced `Time`.
+///
+/// Must not block or allocate.
+pub(crate) fn notify() {
+    WAKER.wake();
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn current() -> u64 {
+    Time.lock()[0]
+}
+
+/// The tick counter, yielding its value whenever it changed since the last poll.
+///
+/// Ticks that elapse between two polls are coalesced into one value. There can only be one
+/// stream at a time, since the timer handler wakes a single task.
+pub struct TickStream {
+    last: u64,
+}
+
+impl TickStream {
+    pub fn new() -> TickStream {
+        static CREATED: AtomicBool = AtomicBool::new(false);
+        if CREATED.swap(true, Ordering::SeqCst) {
+            panic!("TickStream::new should only be called once");
+        }
+        TickStream { last: current() }
+    }
+}
+
+impl Stream for TickStream {
+    type Item = u64;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u64>> {
+        let this = self.get_mut();
+        let now = current();
+        if now != this.last {
+            this.last = now;
+            return Poll::Ready(Some(now));
+        }
+        WAKER.register(context.waker());
+        // the timer might have fired before the waker was registered
+        let now = current();
+        if now != this.last {
+            this.last = now;
+            Poll::Ready(Some(now))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Returns a future that writes the tick counter to `#timer` whenever it advances.
+pub fn record_ticks() -> impl Future<Output = ()> {
+    for_each(TickStream::new(), |time| {
+        systables::set(vec![TIMER.set_u64(1, "ticks", time)]);
+    })
+}
@@ -0,0 +1,431 @@
+use crate::percpu;
+use crate::task::ticks;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// The maximum number of threads, including the boot thread.
+///
+/// The scheduler's tables are allocated up front with this capacity, so that scheduling
+/// never allocates. It runs with interrupts disabled and could otherwise deadlock on a heap
+/// lock held by a preempted thread.
+pub const MAX_THREADS: usize = 32;
+
+/// The size of the stack of every spawned thread.
+pub const STACK_SIZE: usize = 32 * 1024;
+
+/// `RFLAGS` of a new thread: only the reserved bit 1 is set, so interrupts stay disabled
+/// until the thread entry took its closure.
+const INITIAL_RFLAGS: u64 = 0x2;
+
+global_asm!(
+    "
+    .global hivemind_switch_context
+    // Saves the callee-saved registers and flags on the current stack, stores the stack
+    // pointer to the address in `rdi` and continues on the stack in `rsi`.
+    hivemind_switch_context:
+        push %rbp
+        push %rbx
+        push %r12
+        push %r13
+        push %r14
+        push %r15
+        pushfq
+        mov %rsp, (%rdi)
+        mov %rsi, %rsp
+        popfq
+        pop %r15
+        pop %r14
+        pop %r13
+        pop %r12
+        pop %rbx
+        pop %rbp
+        ret
+    "
+);
+
+extern "C" {
+    fn hivemind_switch_context(old_stack_pointer: *mut u64, new_stack_pointer: u64);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> ThreadId {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The id of the thread that runs `kernel_main`.
+pub const BOOT_THREAD: ThreadId = ThreadId(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ready,
+    Running,
+    /// Sleeping until the tick counter reaches the given value.
+    Sleeping(u64),
+    Blocked,
+    Finished,
+}
+
+struct Thread {
+    id: ThreadId,
+    state: State,
+    stack_pointer: u64,
+    /// The stack of a spawned thread, only kept alive here. The boot thread runs on the
+    /// bootloader's stack.
+    #[allow(dead_code)]
+    stack: Option<Box<[u8]>>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// The thread blocked in `join` on this thread.
+    joiner: Option<ThreadId>,
+}
+
+struct Scheduler {
+    threads: Vec<Option<Thread>>,
+    ready: VecDeque<ThreadId>,
+    current: ThreadId,
+}
+
+impl Scheduler {
+    fn thread(&mut self, id: ThreadId) -> Option<&mut Thread> {
+        self.threads.iter_mut().flatten().find(|t| t.id == id)
+    }
+
+    fn current_thread(&mut self) -> &mut Thread {
+        let current = self.current;
+        self.thread(current).expect("current thread missing")
+    }
+
+    /// Marks the thread ready and appends it to the ready queue.
+    fn make_ready(&mut self, id: ThreadId) {
+        if let Some(thread) = self.thread(id) {
+            thread.state = State::Ready;
+            self.ready.push_back(id);
+        }
+    }
+}
+
+/// The scheduler, `None` until `init` was called. Only locked with interrupts disabled.
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// `MAX_THREADS` threads exist already.
+    TooManyThreads,
+    /// `init` was not called.
+    Uninitialized,
+}
+
+/// A handle to join a spawned thread.
+#[derive(Debug)]
+pub struct JoinHandle {
+    id: ThreadId,
+}
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Blocks until the thread has finished and frees its stack.
+    pub fn join(self) {
+        let id = self.id;
+        let reaped = loop {
+            let were_enabled = interrupts::are_enabled();
+            interrupts::disable();
+            let finished = {
+                let mut scheduler = SCHEDULER.lock();
+                let scheduler = scheduler.as_mut().expect("scheduler not initialized");
+                let current = scheduler.current;
+                let slot = scheduler
+                    .threads
+                    .iter_mut()
+                    .find(|slot| slot.as_ref().map(|t| t.id) == Some(id))
+                    .expect("joined thread missing");
+                if slot.as_ref().unwrap().state == State::Finished {
+                    Some(slot.take())
+                } else {
+                    // registering as joiner and blocking happen without a chance to be
+                    // preempted, so the wakeup from `exit` cannot be lost
+                    slot.as_mut().unwrap().joiner = Some(current);
+                    scheduler.current_thread().state = State::Blocked;
+                    None
+                }
+            };
+            if finished.is_none() {
+                wait_until_running();
+            }
+            if were_enabled {
+                interrupts::enable();
+            }
+            if let Some(thread) = finished {
+                break thread;
+            }
+        };
+        // dropped with interrupts enabled, since freeing the stack takes the heap lock
+        core::mem::drop(reaped);
+    }
+}
+
+/// Creates the scheduler and registers the calling code as the boot thread.
+///
+/// Threads only run on the boot processor. Requires the heap to be initialized.
+pub fn init() {
+    let mut threads = Vec::with_capacity(MAX_THREADS);
+    threads.push(Some(Thread {
+        id: BOOT_THREAD,
+        state: State::Running,
+        stack_pointer: 0,
+        stack: None,
+        entry: None,
+        joiner: None,
+    }));
+    for _ in 1..MAX_THREADS {
+        threads.push(None);
+    }
+    let scheduler = Scheduler {
+        threads,
+        ready: VecDeque::with_capacity(MAX_THREADS),
+        current: BOOT_THREAD,
+    };
+    interrupts::without_interrupts(|| *SCHEDULER.lock() = Some(scheduler));
+}
+
+/// Starts a new thread running `f`.
+pub fn spawn<F>(f: F) -> Result<JoinHandle, SpawnError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let entry: Box<dyn FnOnce() + Send> = Box::new(f);
+    let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+    let stack_pointer = prepare_stack(&mut stack);
+    let id = ThreadId::new();
+    let thread = Thread {
+        id,
+        state: State::Ready,
+        stack_pointer,
+        stack: Some(stack),
+        entry: Some(entry),
+        joiner: None,
+    };
+
+    // everything was allocated above, with interrupts enabled
+    let rejected = interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = match scheduler.as_mut() {
+            Some(scheduler) => scheduler,
+            None => return Some((thread, SpawnError::Uninitialized)),
+        };
+        match scheduler.threads.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(thread);
+                scheduler.ready.push_back(id);
+                None
+            }
+            None => Some((thread, SpawnError::TooManyThreads)),
+        }
+    });
+    match rejected {
+        Some((_thread, error)) => Err(error),
+        None => Ok(JoinHandle { id }),
+    }
+}
+
+/// Returns the id of the running thread.
+///
+/// Never takes a lock, so it can be called from interrupt handlers and lock code.
+pub fn current() -> ThreadId {
+    ThreadId(percpu::current().current_thread.load(Ordering::SeqCst))
+}
+
+/// Gives the CPU to the next ready thread, if there is one.
+pub fn yield_now() {
+    interrupts::without_interrupts(|| reschedule());
+}
+
+/// Blocks the running thread for at least the given number of timer ticks.
+pub fn sleep(duration: u64) {
+    let deadline = ticks::current() + duration;
+    block_current(State::Sleeping(deadline));
+}
+
+/// Called by the timer interrupt handler, after the end of interrupt was signaled.
+///
+/// Wakes the threads whose sleep is over and preempts the running thread.
+pub(crate) fn timer_tick(now: u64) {
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = match scheduler.as_mut() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        for slot in scheduler.threads.iter_mut() {
+            if let Some(thread) = slot {
+                if let State::Sleeping(deadline) = thread.state {
+                    if deadline <= now {
+                        thread.state = State::Ready;
+                        scheduler.ready.push_back(thread.id);
+                    }
+                }
+            }
+        }
+    }
+    reschedule();
+}
+
+/// Blocks the running thread until `wake` is called for it.
+///
+/// Must be called with interrupts disabled, in the same critical section that published
+/// the thread's id to its waker, so that the wakeup cannot be lost. Returns immediately if
+/// the scheduler was not initialized.
+pub(crate) fn block() {
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.current_thread().state = State::Blocked;
+    }
+    wait_until_running();
+}
+
+/// Makes a thread that was blocked by `block` ready again.
+pub(crate) fn wake(id: ThreadId) {
+    interrupts::without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            if scheduler.thread(id).map(|t| t.state) == Some(State::Blocked) {
+                scheduler.make_ready(id);
+            }
+        }
+    });
+}
+
+/// Puts the running thread into `state` and runs other threads until it is woken.
+fn block_current(state: State) {
+    let were_enabled = interrupts::are_enabled();
+    interrupts::disable();
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.current_thread().state = state;
+    }
+    wait_until_running();
+    if were_enabled {
+        interrupts::enable();
+    }
+}
+
+/// Runs other threads until the running thread is scheduled again.
+///
+/// Must be called with interrupts disabled. While no thread is ready, the CPU halts on the
+/// stack of the waiting thread.
+fn wait_until_running() {
+    let running = || match SCHEDULER.lock().as_mut() {
+        Some(scheduler) => scheduler.current_thread().state == State::Running,
+        None => true,
+    };
+    while !running() {
+        reschedule();
+        if running() {
+            break;
+        }
+        enable_interrupts_and_hlt();
+        interrupts::disable();
+    }
+}
+
+/// Marks the running thread as finished, wakes its joiner and switches away for good.
+fn exit() -> ! {
+    interrupts::disable();
+    if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+        scheduler.current_thread().state = State::Finished;
+        if let Some(joiner) = scheduler.current_thread().joiner.take() {
+            scheduler.make_ready(joiner);
+        }
+    }
+    wait_until_running();
+    unreachable!("finished thread was scheduled again");
+}
+
+/// Switches to the next ready thread. Must be called with interrupts disabled.
+///
+/// If the running thread is still runnable, it goes to the back of the ready queue.
+fn reschedule() {
+    let (old_stack_pointer, new_stack_pointer) = {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = match scheduler.as_mut() {
+            Some(scheduler) => scheduler,
+            None => return,
+        };
+        let current = scheduler.current;
+        if scheduler.current_thread().state == State::Running {
+            scheduler.make_ready(current);
+        }
+        let next = loop {
+            match scheduler.ready.pop_front() {
+                // a thread that is ready more than once in the queue is skipped
+                Some(id) => match scheduler.thread(id) {
+                    Some(thread) if thread.state == State::Ready => break id,
+                    _ => continue,
+                },
+                None => return,
+            }
+        };
+        scheduler.thread(next).unwrap().state = State::Running;
+        if next == current {
+            return;
+        }
+        scheduler.current = next;
+        percpu::current().current_thread.store(next.0, Ordering::SeqCst);
+        let new_stack_pointer = scheduler.thread(next).unwrap().stack_pointer;
+        let old_stack_pointer: *mut u64 = &mut scheduler.thread(current).unwrap().stack_pointer;
+        (old_stack_pointer, new_stack_pointer)
+    };
+    // the scheduler lock is released, but interrupts stay disabled until the switch is done
+    unsafe { hivemind_switch_context(old_stack_pointer, new_stack_pointer) };
+}
+
+/// Lays out the initial stack of a new thread and returns its stack pointer.
+///
+/// The layout matches what `hivemind_switch_context` pops: flags, the callee-saved
+/// registers and a return address pointing to `thread_entry`.
+fn prepare_stack(stack: &mut [u8]) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xf;
+    let frame: [u64; 9] = [
+        INITIAL_RFLAGS,
+        0, // r15
+        0, // r14
+        0, // r13
+        0, // r12
+        0, // rbx
+        0, // rbp
+        thread_entry as u64,
+        0, // fake return address of `thread_entry`, keeps the stack 16 byte aligned
+    ];
+    let stack_pointer = top - (frame.len() * 8) as u64;
+    unsafe { (stack_pointer as *mut [u64; 9]).write(frame) };
+    stack_pointer
+}
+
+/// The first function a spawned thread runs.
+extern "C" fn thread_entry() -> ! {
+    let entry = SCHEDULER
+        .lock()
+        .as_mut()
+        .and_then(|scheduler| scheduler.current_thread().entry.take());
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+/// Atomically enables interrupts and halts until the next one arrives.
+fn enable_interrupts_and_hlt() {
+    unsafe { asm!("sti; hlt" :::: "volatile") };
+}
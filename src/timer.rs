@@ -0,0 +1,251 @@
+use crate::task::{ticks, WakerSlot};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BinaryHeap};
+use alloc::vec::Vec;
+use core::cmp::{Ordering, Reverse};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{self, AtomicU64};
+use core::task::{Context, Poll, Waker};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// The earliest pending deadline, `u64::max_value()` if there is none.
+///
+/// The timer interrupt handler only looks at this value, so it never has to take a lock.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::max_value());
+
+/// Wakes the task running `process_timers`.
+static WAKER: WakerSlot = WakerSlot::new();
+
+lazy_static! {
+    static ref TIMERS: Mutex<Timers> = Mutex::new(Timers {
+        deadlines: BinaryHeap::new(),
+        callbacks: BTreeMap::new(),
+        running: None,
+        next_sequence: 0,
+    });
+}
+
+/// Identifies a callback registered with `after` or `every`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+enum Action {
+    Wake(Waker),
+    Callback(TimerId),
+}
+
+enum Callback {
+    Once(Box<dyn FnOnce() + Send>),
+    Periodic(u64, Box<dyn FnMut() + Send>),
+}
+
+/// A pending deadline. Entries with equal deadlines fire in the order they were added.
+struct Entry {
+    deadline: u64,
+    sequence: u64,
+    action: Action,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Entry) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Entry) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Entry) -> Ordering {
+        (self.deadline, self.sequence).cmp(&(other.deadline, other.sequence))
+    }
+}
+
+struct Timers {
+    /// Min-heap of pending deadlines.
+    deadlines: BinaryHeap<Reverse<Entry>>,
+    callbacks: BTreeMap<TimerId, Callback>,
+    /// The periodic callback that is running, and whether it was cancelled meanwhile.
+    running: Option<(TimerId, bool)>,
+    next_sequence: u64,
+}
+
+impl Timers {
+    fn push(&mut self, deadline: u64, action: Action) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.deadlines.push(Reverse(Entry {
+            deadline,
+            sequence,
+            action,
+        }));
+        self.update_next_deadline();
+    }
+
+    fn update_next_deadline(&self) {
+        let next = self
+            .deadlines
+            .peek()
+            .map(|entry| (entry.0).deadline)
+            .unwrap_or(u64::max_value());
+        NEXT_DEADLINE.store(next, atomic::Ordering::SeqCst);
+    }
+}
+
+/// Called by the timer interrupt handler with the new tick count.
+///
+/// Must not block or allocate.
+pub(crate) fn tick(now: u64) {
+    if now >= NEXT_DEADLINE.load(atomic::Ordering::SeqCst) {
+        WAKER.wake();
+    }
+}
+
+/// Returns a future that completes once `duration` ticks have passed.
+pub fn sleep(duration: u64) -> Sleep {
+    Sleep {
+        deadline: ticks::current() + duration,
+        registered: false,
+    }
+}
+
+/// Future returned by `sleep`.
+pub struct Sleep {
+    deadline: u64,
+    registered: bool,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        if ticks::current() >= this.deadline {
+            return Poll::Ready(());
+        }
+        if !this.registered {
+            let waker = context.waker().clone();
+            TIMERS.lock().push(this.deadline, Action::Wake(waker));
+            this.registered = true;
+        }
+        Poll::Pending
+    }
+}
+
+/// Calls `f` once after `delay` ticks.
+pub fn after<F>(delay: u64, f: F) -> TimerId
+where
+    F: FnOnce() + Send + 'static,
+{
+    add_callback(delay, Callback::Once(Box::new(f)))
+}
+
+/// Calls `f` every `period` ticks until the timer is cancelled.
+pub fn every<F>(period: u64, f: F) -> TimerId
+where
+    F: FnMut() + Send + 'static,
+{
+    assert!(period > 0, "period must be at least one tick");
+    add_callback(period, Callback::Periodic(period, Box::new(f)))
+}
+
+fn add_callback(delay: u64, callback: Callback) -> TimerId {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = TimerId(NEXT_ID.fetch_add(1, atomic::Ordering::Relaxed));
+    let deadline = ticks::current() + delay;
+    let mut timers = TIMERS.lock();
+    timers.callbacks.insert(id, callback);
+    timers.push(deadline, Action::Callback(id));
+    id
+}
+
+/// Cancels a callback. Returns `false` if it already fired or was cancelled before.
+pub fn cancel(id: TimerId) -> bool {
+    let mut timers = TIMERS.lock();
+    if let Some((running, ref mut cancelled)) = timers.running {
+        if running == id && !*cancelled {
+            *cancelled = true;
+            return true;
+        }
+    }
+    // the deadline stays in the heap and is skipped once it expires
+    timers.callbacks.remove(&id).is_some()
+}
+
+/// Returns a future that wakes sleepers and runs callbacks whose deadline has passed.
+///
+/// Callbacks run in the context of this task, never in the interrupt handler, so they may
+/// allocate, take locks and add or cancel timers.
+pub fn process_timers() -> ProcessTimers {
+    ProcessTimers { _private: () }
+}
+
+/// Future returned by `process_timers`.
+pub struct ProcessTimers {
+    _private: (),
+}
+
+impl Future for ProcessTimers {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        WAKER.register(context.waker());
+        loop {
+            let now = ticks::current();
+            let expired = expired_entries(now);
+            if expired.is_empty() {
+                return Poll::Pending;
+            }
+            for entry in expired {
+                fire(entry, now);
+            }
+        }
+    }
+}
+
+/// Removes all entries whose deadline is not after `now` from the heap.
+fn expired_entries(now: u64) -> Vec<Entry> {
+    let mut timers = TIMERS.lock();
+    let mut expired = Vec::new();
+    while timers
+        .deadlines
+        .peek()
+        .map_or(false, |entry| (entry.0).deadline <= now)
+    {
+        expired.push(timers.deadlines.pop().unwrap().0);
+    }
+    timers.update_next_deadline();
+    expired
+}
+
+/// Wakes the sleeper or runs the callback of an expired entry.
+///
+/// The callback is taken out of the table while it runs, so it can use the timer API.
+fn fire(entry: Entry, now: u64) {
+    let id = match entry.action {
+        Action::Wake(waker) => return waker.wake(),
+        Action::Callback(id) => id,
+    };
+    let callback = TIMERS.lock().callbacks.remove(&id);
+    match callback {
+        Some(Callback::Once(f)) => f(),
+        Some(Callback::Periodic(period, mut f)) => {
+            TIMERS.lock().running = Some((id, false));
+            f();
+            let mut timers = TIMERS.lock();
+            if let Some((_, false)) = timers.running.take() {
+                timers.callbacks.insert(id, Callback::Periodic(period, f));
+                timers.push(now + period, Action::Callback(id));
+            }
+        }
+        // cancelled
+        None => {}
+    }
+}
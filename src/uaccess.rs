@@ -0,0 +1,53 @@
+use crate::cpu;
+use x86_64::VirtAddr;
+
+/// The first address above the lower, user-mode half of the address space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserAccessError {
+    /// The range is not completely inside user space.
+    BadAddress,
+}
+
+/// Returns an error unless `len` bytes starting at `addr` lie inside user space.
+fn check_range(addr: VirtAddr, len: usize) -> Result<(), UserAccessError> {
+    match addr.as_u64().checked_add(len as u64) {
+        Some(end) if end <= USER_SPACE_END => Ok(()),
+        _ => Err(UserAccessError::BadAddress),
+    }
+}
+
+/// Runs `f` with supervisor access to user pages allowed.
+fn with_user_access<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    cpu::stac();
+    let result = f();
+    cpu::clac();
+    result
+}
+
+/// Copies `dst.len()` bytes from user memory at `src` into `dst`.
+///
+/// The user range must be mapped; there is no fixup for faults on missing user pages yet.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserAccessError> {
+    check_range(src, dst.len())?;
+    with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr(), dst.len())
+    });
+    Ok(())
+}
+
+/// Copies `src` into user memory at `dst`.
+///
+/// The user range must be mapped writable; there is no fixup for faults on missing user
+/// pages yet.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserAccessError> {
+    check_range(dst, src.len())?;
+    with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst.as_mut_ptr::<u8>(), src.len())
+    });
+    Ok(())
+}
@@ -0,0 +1,393 @@
+//! The virtual file system, which joins file systems into one tree of paths.
+//!
+//! File systems implement `FileSystem`, whose root and every file and directory below it
+//! are `Inode`s. They are mounted at absolute paths; a path is resolved in the file system
+//! mounted at its longest prefix, walking its directories from the root with `lookup`.
+//! Mount points need not exist in the file system they hide, they are listed anyway.
+
+use crate::block::BlockError;
+use crate::sync::IrqMutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+lazy_static! {
+    static ref MOUNTS: IrqMutex<Vec<Mount>> = IrqMutex::new("MOUNTS", Vec::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    /// A name in the path does not exist.
+    NotFound,
+    /// A name in the path, other than the last, is not a directory.
+    NotADirectory,
+    /// The operation needs a file, but the path names a directory.
+    IsADirectory,
+    /// The path is not absolute, or leaves the root with `..`.
+    InvalidPath,
+    /// The name exists already.
+    Exists,
+    /// A file system is mounted at the path already.
+    AlreadyMounted,
+    /// No file system is mounted at the path.
+    NotMounted,
+    /// The file was not opened for the operation.
+    PermissionDenied,
+    /// The file system or device does not accept writes.
+    ReadOnly,
+    /// The file system does not implement the operation.
+    Unsupported,
+    /// A seek before the start of the file, or a similar argument out of range.
+    InvalidArgument,
+    /// The file system has no room left.
+    NoSpace,
+    /// The data on the device cannot be interpreted.
+    Corrupted,
+    Block(BlockError),
+}
+
+impl From<BlockError> for VfsError {
+    fn from(error: BlockError) -> VfsError {
+        VfsError::Block(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    /// A device read and written as a stream, ignoring the file offset.
+    CharDevice,
+    /// A disk, read and written at any offset.
+    BlockDevice,
+}
+
+/// What `stat` returns about a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    /// The size in bytes, zero for directories and character devices.
+    pub size: u64,
+}
+
+/// A name in a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub file_type: FileType,
+}
+
+/// A file or directory of a file system.
+///
+/// Methods take `&self`, implementations synchronize internally. The defaults fail as fits
+/// a file, for directory operations, or a directory, for file operations.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Returns the child with the given name of a directory.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Lists a directory, without `.` and `..`.
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Creates an empty file or directory in a directory.
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    /// Reads from `offset` into `buffer`. Returns the number of bytes read, zero at the end
+    /// of the file.
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    /// Writes `buffer` at `offset`, growing the file if needed. Returns the number of bytes
+    /// written.
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    /// Shrinks or grows a file to `size` bytes.
+    fn truncate(&self, _size: u64) -> Result<(), VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    /// Waits until written data reached the device.
+    fn sync(&self) -> Result<(), VfsError> {
+        Ok(())
+    }
+}
+
+/// A tree of files that can be mounted.
+pub trait FileSystem: Send + Sync {
+    /// The kind of file system, like `initrd` or `devfs`.
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+struct Mount {
+    /// The normalized names of the mount point.
+    names: Vec<String>,
+    file_system: Arc<dyn FileSystem>,
+}
+
+/// Splits an absolute path into names, resolving `.` and `..`.
+pub fn normalize(path: &str) -> Result<Vec<String>, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut names: Vec<String> = Vec::new();
+    for name in path.split('/') {
+        match name {
+            "" | "." => {}
+            ".." => {
+                names.pop().ok_or(VfsError::InvalidPath)?;
+            }
+            name => names.push(String::from(name)),
+        }
+    }
+    Ok(names)
+}
+
+/// Joins normalized names into an absolute path.
+fn join(names: &[String]) -> String {
+    let mut path = String::new();
+    for name in names {
+        path.push('/');
+        path.push_str(name);
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+/// Mounts a file system at an absolute path.
+pub fn mount(path: &str, file_system: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+    let names = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.names == names) {
+        return Err(VfsError::AlreadyMounted);
+    }
+    mounts.push(Mount { names, file_system });
+    Ok(())
+}
+
+/// Removes the file system mounted at a path. Files opened in it stay usable.
+pub fn unmount(path: &str) -> Result<Arc<dyn FileSystem>, VfsError> {
+    let names = normalize(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.names == names)
+        .ok_or(VfsError::NotMounted)?;
+    Ok(mounts.remove(index).file_system)
+}
+
+/// Returns the mount points and the names of their file systems.
+pub fn mounts() -> Vec<(String, String)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (join(&mount.names), String::from(mount.file_system.name())))
+        .collect()
+}
+
+/// Finds the file system mounted at the longest prefix of a path. Returns its root and the
+/// number of names the prefix covers.
+fn mount_of(names: &[String]) -> Result<(Arc<dyn Inode>, usize), VfsError> {
+    let file_system = {
+        let mounts = MOUNTS.lock();
+        mounts
+            .iter()
+            .filter(|mount| names.starts_with(&mount.names))
+            .max_by_key(|mount| mount.names.len())
+            .map(|mount| (mount.file_system.clone(), mount.names.len()))
+    };
+    // the root is fetched without the lock, file systems may block
+    let (file_system, depth) = file_system.ok_or(VfsError::NotFound)?;
+    Ok((file_system.root(), depth))
+}
+
+/// Returns the inode at an absolute path.
+pub fn resolve(path: &str) -> Result<Arc<dyn Inode>, VfsError> {
+    resolve_names(&normalize(path)?)
+}
+
+fn resolve_names(names: &[String]) -> Result<Arc<dyn Inode>, VfsError> {
+    let (mut inode, depth) = mount_of(names)?;
+    for name in names[depth..].iter() {
+        inode = inode.lookup(name)?;
+    }
+    Ok(inode)
+}
+
+/// Returns the metadata of the file or directory at a path.
+pub fn stat(path: &str) -> Result<Metadata, VfsError> {
+    Ok(resolve(path)?.metadata())
+}
+
+/// Lists a directory, including the mount points directly inside it.
+pub fn readdir(path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    let names = normalize(path)?;
+    let mut entries = resolve_names(&names)?.read_dir()?;
+    for mount in MOUNTS.lock().iter() {
+        if mount.names.len() != names.len() + 1 || !mount.names.starts_with(&names) {
+            continue;
+        }
+        let name = &mount.names[names.len()];
+        if !entries.iter().any(|entry| entry.name == *name) {
+            entries.push(DirEntry {
+                name: name.clone(),
+                file_type: FileType::Directory,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Creates an empty file or directory. Its parent must exist.
+pub fn create(path: &str, file_type: FileType) -> Result<(), VfsError> {
+    let names = normalize(path)?;
+    let (name, parent) = names.split_last().ok_or(VfsError::Exists)?;
+    let parent = resolve_names(parent)?;
+    match parent.lookup(name) {
+        Ok(_) => Err(VfsError::Exists),
+        Err(VfsError::NotFound) => parent.create(name, file_type).map(|_| ()),
+        Err(error) => Err(error),
+    }
+}
+
+/// How a file is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenMode {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl OpenMode {
+    fn can_read(self) -> bool {
+        self != OpenMode::Write
+    }
+
+    fn can_write(self) -> bool {
+        self != OpenMode::Read
+    }
+}
+
+/// The position `File::seek` moves to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file with its own offset.
+pub struct File {
+    inode: Arc<dyn Inode>,
+    mode: OpenMode,
+    offset: u64,
+}
+
+/// Opens the file at a path.
+pub fn open(path: &str, mode: OpenMode) -> Result<File, VfsError> {
+    let inode = resolve(path)?;
+    if inode.metadata().file_type == FileType::Directory {
+        return Err(VfsError::IsADirectory);
+    }
+    Ok(File {
+        inode,
+        mode,
+        offset: 0,
+    })
+}
+
+impl File {
+    /// Reads from the offset of the file and advances it.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        if !self.mode.can_read() {
+            return Err(VfsError::PermissionDenied);
+        }
+        let count = self.inode.read_at(self.offset, buffer)?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+
+    /// Reads from the offset of the file until its end.
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, VfsError> {
+        let mut data = Vec::new();
+        let mut chunk = [0; 512];
+        loop {
+            match self.read(&mut chunk)? {
+                0 => return Ok(data),
+                count => data.extend_from_slice(&chunk[..count]),
+            }
+        }
+    }
+
+    /// Writes at the offset of the file and advances it.
+    pub fn write(&mut self, buffer: &[u8]) -> Result<usize, VfsError> {
+        if !self.mode.can_write() {
+            return Err(VfsError::PermissionDenied);
+        }
+        let count = self.inode.write_at(self.offset, buffer)?;
+        self.offset += count as u64;
+        Ok(count)
+    }
+
+    /// Moves the offset of the file. Returns the new offset.
+    pub fn seek(&mut self, position: SeekFrom) -> Result<u64, VfsError> {
+        let (base, delta) = match position {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (self.offset, delta),
+            SeekFrom::End(delta) => (self.inode.metadata().size, delta),
+        };
+        let offset = if delta < 0 {
+            base.checked_sub(delta.wrapping_neg() as u64)
+        } else {
+            base.checked_add(delta as u64)
+        };
+        self.offset = offset.ok_or(VfsError::InvalidArgument)?;
+        Ok(self.offset)
+    }
+
+    /// Shrinks or grows the file to `size` bytes.
+    pub fn truncate(&mut self, size: u64) -> Result<(), VfsError> {
+        if !self.mode.can_write() {
+            return Err(VfsError::PermissionDenied);
+        }
+        self.inode.truncate(size)
+    }
+
+    /// Waits until written data reached the device.
+    pub fn sync(&self) -> Result<(), VfsError> {
+        self.inode.sync()
+    }
+
+    pub fn stat(&self) -> Metadata {
+        self.inode.metadata()
+    }
+}
+
+#[test_case]
+fn test_normalize() {
+    serial_print!("test_normalize... ");
+    assert_eq!(normalize("/"), Ok(Vec::new()));
+    assert_eq!(normalize("/dev//./serial0"), Ok(vec![String::from("dev"), String::from("serial0")]));
+    assert_eq!(normalize("/etc/../dev"), Ok(vec![String::from("dev")]));
+    assert_eq!(normalize("/.."), Err(VfsError::InvalidPath));
+    assert_eq!(normalize("etc"), Err(VfsError::InvalidPath));
+    assert_eq!(join(&normalize("/a/b/").unwrap()), "/a/b");
+    serial_println!("[ok]");
+}
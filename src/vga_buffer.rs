@@ -1,6 +1,6 @@
+use crate::sync::IrqMutex;
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use volatile::Volatile;
 
 #[cfg(test)]
@@ -10,7 +10,7 @@ lazy_static! {
     /// A global `Writer` instance that can be used for printing to the VGA text buffer.
     ///
     /// Used by the `print!` and `println!` macros.
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqMutex<Writer> = IrqMutex::new("WRITER", Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -112,16 +112,36 @@ impl Writer {
     /// support strings with non-ASCII characters, since they can't be printed in the VGA text
     /// mode.
     fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
+        self.write_ascii(s.as_bytes());
+    }
+
+    /// Writes bytes to the buffer, showing bytes other than printable ASCII, newlines and
+    /// backspaces as `■`.
+    pub fn write_ascii(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
             match byte {
                 // printable ASCII byte or newline
                 0x20..=0x7e | b'\n' => self.write_byte(byte),
+                0x08 => self.backspace(),
                 // not part of printable ASCII range
                 _ => self.write_byte(0xfe),
             }
         }
     }
 
+    /// Clears the character before the cursor and moves the cursor back onto it. Stops at
+    /// the start of the line.
+    fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+            let blank = ScreenChar {
+                ascii_character: b' ',
+                color_code: self.color_code,
+            };
+            self.buffer.chars[BUFFER_HEIGHT - 1][self.column_position].write(blank);
+        }
+    }
+
     /// Shifts all lines one line up and clears the last row.
     fn new_line(&mut self) {
         for row in 1..BUFFER_HEIGHT {
@@ -171,11 +191,8 @@ macro_rules! println {
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+    WRITER.lock().write_fmt(args).unwrap();
 }
 
 #[test_case]
@@ -197,19 +214,17 @@ fn test_println_many() {
 #[test_case]
 fn test_println_output() {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     serial_print!("test_println_output... ");
 
     let s = "Some test string that fits on a single line";
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
-        }
-    });
+    // the lock keeps interrupts disabled, so nothing else can print in between
+    let mut writer = WRITER.lock();
+    writeln!(writer, "\n{}", s).expect("writeln failed");
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(char::from(screen_char.ascii_character), c);
+    }
 
     serial_println!("[ok]");
 }
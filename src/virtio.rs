@@ -0,0 +1,483 @@
+//! Virtio devices on the PCI bus.
+//!
+//! `VirtioPci` drives the modern transport, whose registers are described by vendor
+//! capabilities in memory BARs, and falls back to the legacy transport in I/O BAR 0.
+//! `Virtqueue` implements the split virtqueue both transports use.
+
+use crate::memory::{self, DEVICE_MEMORY_END, FRAME_ALLOCATOR};
+use crate::pci::{Bar, PciDevice};
+use core::sync::atomic::{self, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+pub const STATUS_ACKNOWLEDGE: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 128;
+
+/// Offered by devices that implement the modern interface, and required by it.
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// Set in the ISR status when a queue has used buffers.
+pub const ISR_QUEUE: u8 = 1;
+
+const CAPABILITY_VENDOR: u8 = 0x09;
+const CAPABILITY_COMMON: u8 = 1;
+const CAPABILITY_NOTIFY: u8 = 2;
+const CAPABILITY_ISR: u8 = 3;
+const CAPABILITY_DEVICE: u8 = 4;
+
+// offsets in the modern common configuration
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// Written as MSI-X vector to use the legacy interrupt line.
+const NO_MSIX_VECTOR: u16 = 0xffff;
+
+// offsets in the legacy I/O BAR
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0c;
+const LEGACY_QUEUE_SELECT: u16 = 0x0e;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+/// The device configuration follows the common registers if MSI-X is disabled.
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+/// The legacy interface requires this alignment of the used ring and takes queue
+/// addresses in units of it.
+const LEGACY_QUEUE_ALIGN: usize = 4096;
+
+/// The largest queue the driver sets up, if the device lets it choose.
+const MAX_QUEUE_SIZE: u16 = 256;
+
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+
+enum Registers {
+    Legacy {
+        port: u16,
+    },
+    Modern {
+        common: VirtAddr,
+        notify: VirtAddr,
+        notify_multiplier: u32,
+        isr: VirtAddr,
+        device: VirtAddr,
+    },
+}
+
+/// The transport of a virtio device on the PCI bus.
+pub struct VirtioPci {
+    registers: Registers,
+}
+
+fn read_volatile<T>(address: VirtAddr) -> T {
+    unsafe { address.as_ptr::<T>().read_volatile() }
+}
+
+fn write_volatile<T>(address: VirtAddr, value: T) {
+    unsafe { address.as_mut_ptr::<T>().write_volatile(value) }
+}
+
+/// Writes a 64-bit register as two 32-bit halves, which every device supports.
+fn write_u64_halves(address: VirtAddr, value: u64) {
+    write_volatile(address, value as u32);
+    write_volatile(address + 4u64, (value >> 32) as u32);
+}
+
+impl VirtioPci {
+    /// Finds the registers of a virtio device and enables its BARs and bus mastering.
+    ///
+    /// The modern interface is used if the device offers it in memory below 4GiB.
+    pub fn new(device: &PciDevice) -> Option<VirtioPci> {
+        let registers = modern_registers(device).or_else(|| match device.bars[0] {
+            Bar::Io { port, .. } => Some(Registers::Legacy { port }),
+            _ => None,
+        })?;
+        device.address.enable();
+        Some(VirtioPci { registers })
+    }
+
+    pub fn is_modern(&self) -> bool {
+        match self.registers {
+            Registers::Modern { .. } => true,
+            Registers::Legacy { .. } => false,
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        match self.registers {
+            Registers::Legacy { port } => unsafe { Port::new(port + LEGACY_DEVICE_STATUS).read() },
+            Registers::Modern { common, .. } => read_volatile(common + COMMON_DEVICE_STATUS),
+        }
+    }
+
+    /// Sets the device status. Writing 0 resets the device.
+    pub fn set_status(&self, status: u8) {
+        match self.registers {
+            Registers::Legacy { port } => unsafe {
+                Port::new(port + LEGACY_DEVICE_STATUS).write(status)
+            },
+            Registers::Modern { common, .. } => write_volatile(common + COMMON_DEVICE_STATUS, status),
+        }
+    }
+
+    pub fn add_status(&self, status: u8) {
+        self.set_status(self.status() | status);
+    }
+
+    /// Returns the features the device offers. The legacy interface only has 32 of them.
+    pub fn device_features(&self) -> u64 {
+        match self.registers {
+            Registers::Legacy { port } => unsafe {
+                u64::from(Port::<u32>::new(port + LEGACY_DEVICE_FEATURES).read())
+            },
+            Registers::Modern { common, .. } => {
+                let mut features = 0;
+                for select in 0..2u32 {
+                    write_volatile(common + COMMON_DEVICE_FEATURE_SELECT, select);
+                    let half: u32 = read_volatile(common + COMMON_DEVICE_FEATURE);
+                    features |= u64::from(half) << (32 * select);
+                }
+                features
+            }
+        }
+    }
+
+    /// Accepts the given features.
+    ///
+    /// On the modern interface, returns whether the device accepted them, too.
+    pub fn negotiate_features(&self, features: u64) -> bool {
+        match self.registers {
+            Registers::Legacy { port } => {
+                unsafe { Port::<u32>::new(port + LEGACY_DRIVER_FEATURES).write(features as u32) };
+                true
+            }
+            Registers::Modern { common, .. } => {
+                for select in 0..2u32 {
+                    write_volatile(common + COMMON_DRIVER_FEATURE_SELECT, select);
+                    write_volatile(common + COMMON_DRIVER_FEATURE, (features >> (32 * select)) as u32);
+                }
+                self.add_status(STATUS_FEATURES_OK);
+                self.status() & STATUS_FEATURES_OK != 0
+            }
+        }
+    }
+
+    /// Allocates the memory of queue `index` and passes it to the device.
+    ///
+    /// Returns `None` if the device has no such queue or no memory is left.
+    pub fn setup_queue(&self, index: u16) -> Option<Virtqueue> {
+        match self.registers {
+            Registers::Legacy { port } => {
+                let size = unsafe {
+                    Port::<u16>::new(port + LEGACY_QUEUE_SELECT).write(index);
+                    Port::<u16>::new(port + LEGACY_QUEUE_SIZE).read()
+                };
+                if size == 0 {
+                    return None;
+                }
+                // the legacy interface has no way to choose a smaller queue
+                let queue = Virtqueue::new(index, size, 0)?;
+                let frame = (queue.descriptor_address().as_u64() / LEGACY_QUEUE_ALIGN as u64) as u32;
+                unsafe { Port::<u32>::new(port + LEGACY_QUEUE_ADDRESS).write(frame) };
+                Some(queue)
+            }
+            Registers::Modern {
+                common,
+                notify_multiplier,
+                ..
+            } => {
+                write_volatile(common + COMMON_QUEUE_SELECT, index);
+                let size: u16 = read_volatile(common + COMMON_QUEUE_SIZE);
+                if size == 0 {
+                    return None;
+                }
+                let size = size.min(MAX_QUEUE_SIZE);
+                let notify_offset: u16 = read_volatile(common + COMMON_QUEUE_NOTIFY_OFF);
+                let queue = Virtqueue::new(index, size, u32::from(notify_offset) * notify_multiplier)?;
+                write_volatile(common + COMMON_QUEUE_SIZE, size);
+                write_volatile(common + COMMON_QUEUE_MSIX_VECTOR, NO_MSIX_VECTOR);
+                write_u64_halves(common + COMMON_QUEUE_DESC, queue.descriptor_address().as_u64());
+                write_u64_halves(common + COMMON_QUEUE_DRIVER, queue.available_address().as_u64());
+                write_u64_halves(common + COMMON_QUEUE_DEVICE, queue.used_address().as_u64());
+                write_volatile(common + COMMON_QUEUE_ENABLE, 1u16);
+                Some(queue)
+            }
+        }
+    }
+
+    /// Tells the device that the queue has new available buffers.
+    pub fn notify(&self, queue: &Virtqueue) {
+        match self.registers {
+            Registers::Legacy { port } => unsafe {
+                Port::<u16>::new(port + LEGACY_QUEUE_NOTIFY).write(queue.index)
+            },
+            Registers::Modern { notify, .. } => {
+                write_volatile(notify + u64::from(queue.notify_offset), queue.index)
+            }
+        }
+    }
+
+    /// Reads and thereby clears the interrupt status.
+    pub fn interrupt_status(&self) -> u8 {
+        match self.registers {
+            Registers::Legacy { port } => unsafe { Port::new(port + LEGACY_ISR_STATUS).read() },
+            Registers::Modern { isr, .. } => read_volatile(isr),
+        }
+    }
+
+    /// Reads a 32-bit field of the device specific configuration.
+    pub fn read_config_u32(&self, offset: usize) -> u32 {
+        match self.registers {
+            Registers::Legacy { port } => unsafe {
+                Port::new(port + LEGACY_DEVICE_CONFIG + offset as u16).read()
+            },
+            Registers::Modern { device, .. } => read_volatile(device + offset as u64),
+        }
+    }
+
+    /// Reads a 64-bit field of the device specific configuration as two halves.
+    pub fn read_config_u64(&self, offset: usize) -> u64 {
+        u64::from(self.read_config_u32(offset)) | u64::from(self.read_config_u32(offset + 4)) << 32
+    }
+}
+
+/// Collects the register locations from the virtio vendor capabilities. Structures in BARs
+/// that `phys_to_virt` does not cover are ignored.
+fn modern_registers(device: &PciDevice) -> Option<Registers> {
+    let address = device.address;
+    let mut common = None;
+    let mut notify = None;
+    let mut isr = None;
+    let mut config = None;
+    for (id, offset) in address.capabilities() {
+        if id != CAPABILITY_VENDOR {
+            continue;
+        }
+        let kind = (address.read(offset) >> 24) as u8;
+        let bar = address.read(offset + 4) as u8;
+        let structure_offset = u64::from(address.read(offset + 8));
+        let base = match device.bars.get(usize::from(bar)) {
+            Some(&Bar::Memory { address, .. }) if address + structure_offset < DEVICE_MEMORY_END => {
+                memory::phys_to_virt(PhysAddr::new(address + structure_offset))
+            }
+            _ => continue,
+        };
+        // the first structure of each kind is the preferred one
+        match kind {
+            CAPABILITY_COMMON if common.is_none() => common = Some(base),
+            CAPABILITY_NOTIFY if notify.is_none() => {
+                notify = Some((base, address.read(offset + 16)))
+            }
+            CAPABILITY_ISR if isr.is_none() => isr = Some(base),
+            CAPABILITY_DEVICE if config.is_none() => config = Some(base),
+            _ => {}
+        }
+    }
+    let (notify, notify_multiplier) = notify?;
+    Some(Registers::Modern {
+        common: common?,
+        notify,
+        notify_multiplier,
+        isr: isr?,
+        device: config?,
+    })
+}
+
+/// Allocates zeroed, physically contiguous memory that devices can access.
+pub fn allocate_dma(frames: usize) -> Option<PhysAddr> {
+    let start = FRAME_ALLOCATOR.lock().as_mut()?.allocate_frames(frames)?.start_address();
+    let bytes = memory::phys_to_virt(start).as_mut_ptr::<u8>();
+    unsafe { core::ptr::write_bytes(bytes, 0, frames * 4096) };
+    Some(start)
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A buffer handed to the device, as part of a chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    /// Whether the device writes to the buffer instead of reading it.
+    pub device_writes: bool,
+}
+
+/// A split virtqueue: a descriptor table, the ring of available descriptor chains the
+/// driver fills and the ring of used chains the device returns.
+///
+/// The three parts lie in one physically contiguous allocation, laid out as the legacy
+/// interface expects.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    notify_offset: u32,
+    memory: PhysAddr,
+    /// The first unused descriptor; free descriptors are linked through `next`.
+    free_head: u16,
+    free_count: u16,
+    next_available: u16,
+    last_used: u16,
+}
+
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16, notify_offset: u32) -> Option<Virtqueue> {
+        let size_usize = usize::from(size);
+        let used_offset = align_up(16 * size_usize + 6 + 2 * size_usize, LEGACY_QUEUE_ALIGN);
+        let length = used_offset + align_up(6 + 8 * size_usize, LEGACY_QUEUE_ALIGN);
+        let memory = allocate_dma(length / 4096)?;
+        let queue = Virtqueue {
+            index,
+            size,
+            notify_offset,
+            memory,
+            free_head: 0,
+            free_count: size,
+            next_available: 0,
+            last_used: 0,
+        };
+        for i in 0..size {
+            let mut descriptor = queue.descriptor(i);
+            descriptor.next = i.wrapping_add(1);
+            queue.set_descriptor(i, descriptor);
+        }
+        Some(queue)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn descriptor_address(&self) -> PhysAddr {
+        self.memory
+    }
+
+    fn available_address(&self) -> PhysAddr {
+        self.memory + 16 * u64::from(self.size)
+    }
+
+    fn used_address(&self) -> PhysAddr {
+        let size = usize::from(self.size);
+        self.memory + align_up(16 * size + 6 + 2 * size, LEGACY_QUEUE_ALIGN) as u64
+    }
+
+    fn descriptor_pointer(&self, index: u16) -> *mut Descriptor {
+        let table = memory::phys_to_virt(self.descriptor_address()).as_mut_ptr::<Descriptor>();
+        unsafe { table.add(usize::from(index)) }
+    }
+
+    fn descriptor(&self, index: u16) -> Descriptor {
+        unsafe { self.descriptor_pointer(index).read_volatile() }
+    }
+
+    fn set_descriptor(&self, index: u16, descriptor: Descriptor) {
+        unsafe { self.descriptor_pointer(index).write_volatile(descriptor) }
+    }
+
+    /// Returns the address of a 16-bit field of the available ring.
+    fn available(&self, field: usize) -> *mut u16 {
+        let ring = memory::phys_to_virt(self.available_address()).as_mut_ptr::<u16>();
+        unsafe { ring.add(field) }
+    }
+
+    /// Hands a chain of buffers to the device. Call `VirtioPci::notify` afterwards.
+    ///
+    /// Returns the id of the chain, or `None` if not enough descriptors are free.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return None;
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let last = i + 1 == buffers.len();
+            let next = self.descriptor(index).next;
+            let write = if buffer.device_writes { DESCRIPTOR_WRITE } else { 0 };
+            self.set_descriptor(index, Descriptor {
+                address: buffer.address.as_u64(),
+                length: buffer.length,
+                flags: write | if last { 0 } else { DESCRIPTOR_NEXT },
+                next,
+            });
+            if last {
+                self.free_head = next;
+            } else {
+                index = next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        let slot = 2 + usize::from(self.next_available % self.size);
+        unsafe { self.available(slot).write_volatile(head) };
+        self.next_available = self.next_available.wrapping_add(1);
+        // the device must see the ring entry before the new index
+        atomic::fence(Ordering::SeqCst);
+        unsafe { self.available(1).write_volatile(self.next_available) };
+        atomic::fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// Takes the next chain the device is done with and frees its descriptors.
+    ///
+    /// Returns its id and the number of bytes the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = memory::phys_to_virt(self.used_address());
+        let device_index: u16 = read_volatile(used + 2u64);
+        if device_index == self.last_used {
+            return None;
+        }
+        atomic::fence(Ordering::SeqCst);
+        let element = used + (4 + 8 * u64::from(self.last_used % self.size));
+        let id = read_volatile::<u32>(element) as u16;
+        let length: u32 = read_volatile(element + 4u64);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let mut index = id;
+        let mut freed = 1;
+        while self.descriptor(index).flags & DESCRIPTOR_NEXT != 0 {
+            index = self.descriptor(index).next;
+            freed += 1;
+        }
+        let mut last = self.descriptor(index);
+        last.next = self.free_head;
+        self.set_descriptor(index, last);
+        self.free_head = id;
+        self.free_count += freed;
+        Some((id, length))
+    }
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) / alignment * alignment
+}
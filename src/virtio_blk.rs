@@ -0,0 +1,269 @@
+//! Virtio block devices.
+//!
+//! Requests are copied through a DMA buffer owned by each disk, so callers can pass any
+//! memory. Only one request per disk is in flight; its completion is signaled by the
+//! device's interrupt line, or polled if that cannot be routed.
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::interrupts::InterruptIndex;
+use crate::pci::{self, Driver, Match, PciDevice};
+use crate::sync::{IrqMutex, SleepMutex, WaitQueue};
+use crate::virtio::{self, Buffer, VirtioPci, Virtqueue};
+use crate::{acpi, apic, memory, percpu};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use x86_64::PhysAddr;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+/// The capacity in sectors, in the device configuration.
+const CONFIG_CAPACITY: usize = 0;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+/// Written to the status byte before a request, the device overwrites it.
+const STATUS_PENDING: u8 = 0xff;
+
+/// The DMA buffer holds the request header and status in its first frame, the data in the
+/// others.
+const DMA_FRAMES: usize = 17;
+const HEADER_LENGTH: u32 = 16;
+const STATUS_OFFSET: u64 = 16;
+const DATA_OFFSET: u64 = 4096;
+const MAX_TRANSFER: usize = (DMA_FRAMES - 1) * 4096;
+
+/// The interrupt line value of functions without one.
+const NO_INTERRUPT_LINE: u8 = 0xff;
+
+static VIRTIO_BLK_DRIVER: Driver = Driver {
+    name: "virtio-blk",
+    matches: &[
+        // transitional and modern-only devices
+        Match::Id { vendor: virtio::VENDOR_ID, device: 0x1001 },
+        Match::Id { vendor: virtio::VENDOR_ID, device: 0x1042 },
+    ],
+    probe: probe,
+};
+
+static NEXT_DISK: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref DISKS: IrqMutex<Vec<Arc<VirtioBlk>>> = IrqMutex::new("DISKS", Vec::new());
+}
+
+/// A virtio block device.
+pub struct VirtioBlk {
+    name: String,
+    transport: VirtioPci,
+    sectors: u64,
+    read_only: bool,
+    can_flush: bool,
+    uses_interrupts: bool,
+    queue: IrqMutex<Virtqueue>,
+    waiters: WaitQueue,
+    /// The DMA buffer, held for the whole duration of a request.
+    dma: SleepMutex<PhysAddr>,
+}
+
+impl VirtioBlk {
+    /// Resets and configures the device. Returns `None` and marks the device failed if it
+    /// cannot be used.
+    fn new(device: &PciDevice) -> Option<VirtioBlk> {
+        let transport = VirtioPci::new(device)?;
+        transport.set_status(0);
+        transport.add_status(virtio::STATUS_ACKNOWLEDGE | virtio::STATUS_DRIVER);
+
+        let mut wanted = FEATURE_READ_ONLY | FEATURE_FLUSH;
+        if transport.is_modern() {
+            wanted |= virtio::FEATURE_VERSION_1;
+        }
+        let features = transport.device_features() & wanted;
+        let dma = virtio::allocate_dma(DMA_FRAMES);
+        let queue = match dma {
+            Some(_) if transport.negotiate_features(features) => transport.setup_queue(0),
+            _ => None,
+        };
+        let (dma, queue) = match (dma, queue) {
+            (Some(dma), Some(queue)) => (dma, queue),
+            _ => {
+                transport.add_status(virtio::STATUS_FAILED);
+                return None;
+            }
+        };
+        let uses_interrupts = route_interrupt(device);
+        transport.add_status(virtio::STATUS_DRIVER_OK);
+        Some(VirtioBlk {
+            name: format!("virtio{}", NEXT_DISK.fetch_add(1, Ordering::SeqCst)),
+            sectors: transport.read_config_u64(CONFIG_CAPACITY),
+            read_only: features & FEATURE_READ_ONLY != 0,
+            can_flush: features & FEATURE_FLUSH != 0,
+            uses_interrupts,
+            transport,
+            queue: IrqMutex::new("virtqueue", queue),
+            waiters: WaitQueue::new(),
+            dma: SleepMutex::new("virtio-blk dma", dma),
+        })
+    }
+
+    /// Whether the device is driven through the modern virtio interface.
+    pub fn is_modern(&self) -> bool {
+        self.transport.is_modern()
+    }
+
+    /// Runs one request and waits for its completion. `length` bytes of data are
+    /// transferred through the data part of the DMA buffer.
+    fn request(&self, dma: PhysAddr, kind: u32, sector: u64, length: usize) -> Result<(), BlockError> {
+        let header = memory::phys_to_virt(dma);
+        let status = memory::phys_to_virt(dma + STATUS_OFFSET).as_mut_ptr::<u8>();
+        unsafe {
+            header.as_mut_ptr::<u32>().write_volatile(kind);
+            header.as_mut_ptr::<u32>().add(1).write_volatile(0);
+            header.as_mut_ptr::<u64>().add(1).write_volatile(sector);
+            status.write_volatile(STATUS_PENDING);
+        }
+
+        let mut buffers = Vec::with_capacity(3);
+        buffers.push(Buffer { address: dma, length: HEADER_LENGTH, device_writes: false });
+        if length > 0 {
+            buffers.push(Buffer {
+                address: dma + DATA_OFFSET,
+                length: length as u32,
+                device_writes: kind == REQUEST_IN,
+            });
+        }
+        buffers.push(Buffer { address: dma + STATUS_OFFSET, length: 1, device_writes: true });
+        {
+            let mut queue = self.queue.lock();
+            queue.add(&buffers).expect("virtqueue full with a single request in flight");
+            self.transport.notify(&queue);
+        }
+
+        if self.uses_interrupts {
+            self.waiters.wait_until(|| self.queue.lock().pop_used().is_some());
+        } else {
+            while self.queue.lock().pop_used().is_none() {
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+        match unsafe { status.read_volatile() } {
+            STATUS_OK => Ok(()),
+            error => Err(BlockError::Device(error)),
+        }
+    }
+
+    fn data(dma: PhysAddr) -> *mut u8 {
+        memory::phys_to_virt(dma + DATA_OFFSET).as_mut_ptr()
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&self, sector: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buffer.len())?;
+        let dma = self.dma.lock();
+        let mut sector = sector;
+        for chunk in buffer.chunks_mut(MAX_TRANSFER) {
+            self.request(*dma, REQUEST_IN, sector, chunk.len())?;
+            unsafe {
+                core::ptr::copy_nonoverlapping(VirtioBlk::data(*dma), chunk.as_mut_ptr(), chunk.len())
+            };
+            sector += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    fn write(&self, sector: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_request(self, sector, buffer.len())?;
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        let dma = self.dma.lock();
+        let mut sector = sector;
+        for chunk in buffer.chunks(MAX_TRANSFER) {
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), VirtioBlk::data(*dma), chunk.len()) };
+            self.request(*dma, REQUEST_OUT, sector, chunk.len())?;
+            sector += (chunk.len() / SECTOR_SIZE) as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        if !self.can_flush {
+            // without the feature, the device writes through
+            return Ok(());
+        }
+        let dma = self.dma.lock();
+        self.request(*dma, REQUEST_FLUSH, 0, 0)
+    }
+}
+
+/// Routes the legacy interrupt line of the device to the boot processor, where the
+/// waiting threads run. The line is level-triggered; `handle_interrupt` deasserts it.
+fn route_interrupt(device: &PciDevice) -> bool {
+    let line = device.interrupt_line;
+    if device.interrupt_pin == 0 || line == NO_INTERRUPT_LINE || !apic::is_enabled() {
+        return false;
+    }
+    match acpi::madt() {
+        Ok(madt) => apic::route_pci_irq(
+            &madt,
+            line,
+            InterruptIndex::Virtio.as_u8(),
+            percpu::boot_cpu().apic_id(),
+        ),
+        Err(_) => false,
+    }
+}
+
+/// Called by the interrupt handler shared by all virtio devices.
+pub(crate) fn handle_interrupt() {
+    for disk in DISKS.lock().iter() {
+        // reading the status acknowledges the interrupt
+        if disk.transport.interrupt_status() & virtio::ISR_QUEUE != 0 {
+            disk.waiters.wake_all();
+        }
+    }
+}
+
+/// Registers the driver of virtio block devices. The disks it finds are named
+/// `virtio<n>` and registered as block devices.
+pub fn init() {
+    pci::register_driver(&VIRTIO_BLK_DRIVER);
+}
+
+/// Returns the virtio disks, in the order they were found.
+pub fn disks() -> Vec<Arc<VirtioBlk>> {
+    DISKS.lock().clone()
+}
+
+fn probe(device: &PciDevice) -> bool {
+    match VirtioBlk::new(device) {
+        Some(disk) => {
+            let disk = Arc::new(disk);
+            DISKS.lock().push(disk.clone());
+            block::register(disk);
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,169 @@
+use crate::memory::{self, FRAME_ALLOCATOR, MAPPER};
+use spin::Mutex;
+use x86_64::{
+    structures::idt::PageFaultErrorCode,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame,
+        Size2MiB, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// The maximum number of regions that can be registered at the same time.
+///
+/// The regions live in a fixed-size table so that the page fault handler never has to
+/// touch the heap, which might itself be the reason for the fault.
+pub const MAX_REGIONS: usize = 32;
+
+/// A reserved range of virtual memory whose pages are mapped on first access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+    /// Back the region with 2MiB pages where the region covers a whole aligned 2MiB range.
+    pub huge_pages: bool,
+}
+
+impl Region {
+    /// Create a region of `size` bytes starting at `start`, mapped with `flags` on demand.
+    pub fn new(name: &'static str, start: VirtAddr, size: u64, flags: PageTableFlags) -> Region {
+        Region {
+            name,
+            start,
+            size,
+            flags,
+            huge_pages: false,
+        }
+    }
+
+    /// Returns the same region, but backed by 2MiB pages wherever possible.
+    ///
+    /// This reduces TLB pressure for large buffers such as Mech table storage.
+    pub fn with_huge_pages(mut self) -> Region {
+        self.huge_pages = true;
+        self
+    }
+
+    /// Returns the first address after the region.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Returns whether the given address lies inside the region.
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end()
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start < other.end() && other.start < self.end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The start address or size is not a multiple of the page size.
+    Unaligned,
+    /// The region overlaps a region that is already registered.
+    Overlap(&'static str),
+    /// All `MAX_REGIONS` slots are in use.
+    TableFull,
+}
+
+static REGIONS: Mutex<[Option<Region>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// Registers a region, so that page faults inside it are resolved by mapping a zeroed frame.
+pub fn register(region: Region) -> Result<(), RegionError> {
+    if region.start.as_u64() % 4096 != 0 || region.size % 4096 != 0 || region.size == 0 {
+        return Err(RegionError::Unaligned);
+    }
+    let mut regions = REGIONS.lock();
+    if let Some(other) = regions.iter().flatten().find(|r| r.overlaps(&region)) {
+        return Err(RegionError::Overlap(other.name));
+    }
+    let slot = regions
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .ok_or(RegionError::TableFull)?;
+    *slot = Some(region);
+    Ok(())
+}
+
+/// Removes the region starting at `start`.
+///
+/// Pages of the region that were already backed stay mapped.
+pub fn unregister(start: VirtAddr) -> Option<Region> {
+    let mut regions = REGIONS.lock();
+    let slot = regions
+        .iter_mut()
+        .find(|slot| slot.map(|r| r.start) == Some(start))?;
+    slot.take()
+}
+
+/// Returns the region containing the given address, if any.
+pub fn find(addr: VirtAddr) -> Option<Region> {
+    REGIONS.lock().iter().flatten().find(|r| r.contains(addr)).cloned()
+}
+
+/// Tries to resolve a page fault at `addr` by backing the faulting page with a zeroed frame.
+///
+/// Returns `true` if the page was mapped and the faulting instruction can be retried. Faults
+/// on present pages, outside of any region, or while the memory locks are held by the
+/// interrupted code are left to the caller.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return false;
+    }
+    let region = match REGIONS.try_lock() {
+        Some(regions) => match regions.iter().flatten().find(|r| r.contains(addr)) {
+            Some(region) => *region,
+            None => return false,
+        },
+        None => return false,
+    };
+    let (mut mapper, mut frame_allocator) = match (MAPPER.try_lock(), FRAME_ALLOCATOR.try_lock()) {
+        (Some(mapper), Some(frame_allocator)) => (mapper, frame_allocator),
+        _ => return false,
+    };
+    match (mapper.as_mut(), frame_allocator.as_mut()) {
+        (Some(mapper), Some(frame_allocator)) => {
+            if region.huge_pages {
+                let huge_page = Page::<Size2MiB>::containing_address(addr);
+                let huge_page_end = huge_page.start_address() + (Size2MiB::SIZE - 1);
+                if region.contains(huge_page.start_address())
+                    && region.contains(huge_page_end)
+                    && map_zeroed(huge_page, region.flags, mapper, frame_allocator).is_ok()
+                {
+                    return true;
+                }
+            }
+            let page = Page::<Size4KiB>::containing_address(addr);
+            map_zeroed(page, region.flags, mapper, frame_allocator).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Maps the given page to a freshly allocated, zeroed frame of the same size.
+fn map_zeroed<S: PageSize, A>(
+    page: Page<S>,
+    flags: PageTableFlags,
+    mapper: &mut impl Mapper<S>,
+    frame_allocator: &mut A,
+) -> Result<(), MapToError>
+where
+    A: FrameAllocator<S> + FrameAllocator<Size4KiB>,
+{
+    let frame: PhysFrame<S> = FrameAllocator::<S>::allocate_frame(frame_allocator)
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    // zero the frame through the physical memory mapping before it becomes visible
+    let frame_ptr: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe { frame_ptr.write_bytes(0, S::SIZE as usize) };
+    let mut flags = flags | PageTableFlags::PRESENT;
+    if S::SIZE != Size4KiB::SIZE {
+        flags |= PageTableFlags::HUGE_PAGE;
+    }
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    Ok(())
+}
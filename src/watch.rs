@@ -0,0 +1,214 @@
+//! Rust callbacks that run when cells of a table change.
+//!
+//! `persist::transact` and `persist::transact_unjournaled`, through which every transaction
+//! on `HiveCore` goes, queue the cells each transaction set in watched tables, including
+//! the cells set by the Mech blocks it ran. The task running `process_watchers` then calls
+//! the watchers of each table once per transaction, so watchers never run in interrupt
+//! handlers and may transact themselves.
+
+use crate::sync::IrqMutex;
+use crate::task::WakerSlot;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use lazy_static::lazy_static;
+use mech_core::{Change, Core, Index, Value};
+
+#[cfg(test)]
+use crate::{serial_print, serial_println};
+
+/// Wakes the task running `process_watchers`.
+static WAKER: WakerSlot = WakerSlot::new();
+
+/// Identifies a callback registered with `watch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WatcherId(u64);
+
+/// A cell set by a transaction, with one-based indices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangedCell {
+    pub row: u64,
+    pub column: u64,
+    pub value: Value,
+}
+
+type Callback = Arc<dyn Fn(&[ChangedCell]) + Send + Sync>;
+
+/// The cells one transaction set in a watched table.
+pub(crate) struct Notification {
+    table: u64,
+    cells: Vec<ChangedCell>,
+}
+
+lazy_static! {
+    static ref WATCHERS: IrqMutex<BTreeMap<WatcherId, (u64, Callback)>> = IrqMutex::new("WATCHERS", BTreeMap::new());
+    static ref QUEUE: IrqMutex<Vec<Notification>> = IrqMutex::new("WATCH QUEUE", Vec::new());
+}
+
+/// Calls `f` with the changed cells after every transaction that set cells of `table`.
+pub fn watch<F>(table: u64, f: F) -> WatcherId
+where
+    F: Fn(&[ChangedCell]) + Send + Sync + 'static,
+{
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    let id = WatcherId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    WATCHERS.lock().insert(id, (table, Arc::new(f)));
+    id
+}
+
+/// Removes a watcher. Returns `false` if it was removed before. Notifications queued
+/// already are not delivered to it.
+pub fn unwatch(id: WatcherId) -> bool {
+    WATCHERS.lock().remove(&id).is_some()
+}
+
+/// Collects the cells a transaction sets in watched tables, before it is processed.
+///
+/// `persist` rejects transactions setting cells by name, so every cell has its position.
+pub(crate) fn collect(changes: &[Change]) -> Vec<Notification> {
+    let mut tables: BTreeMap<u64, Vec<ChangedCell>> = {
+        let watchers = WATCHERS.lock();
+        watchers.values().map(|&(table, _)| (table, Vec::new())).collect()
+    };
+    for change in changes {
+        if let Change::Set { table, row: Index::Index(row), column: Index::Index(column), value } = change {
+            if let Some(cells) = tables.get_mut(table) {
+                cells.push(ChangedCell {
+                    row: *row as u64,
+                    column: *column as u64,
+                    value: value.clone(),
+                });
+            }
+        }
+    }
+    tables
+        .into_iter()
+        .filter(|(_, cells)| !cells.is_empty())
+        .map(|(table, cells)| Notification { table, cells })
+        .collect()
+}
+
+/// The cells of the watched tables before a transaction is processed.
+pub(crate) struct Cells(BTreeMap<u64, Vec<Vec<Value>>>);
+
+/// Copies the cells of the watched tables, to find the cells blocks set while a transaction
+/// is processed. Only watched tables are copied, so this is cheap while few are watched.
+pub(crate) fn cells(core: &Core) -> Cells {
+    let watchers = WATCHERS.lock();
+    Cells(
+        watchers
+            .values()
+            .map(|&(table, _)| (table, core.store.get_table(table).map_or(Vec::new(), |table| table.data.clone())))
+            .collect(),
+    )
+}
+
+/// Adds the cells of watched tables that changed since `before` to the notifications of a
+/// processed transaction, unless the transaction set them itself.
+pub(crate) fn add_derived(notifications: &mut Vec<Notification>, before: Cells, core: &Core) {
+    for (id, old) in before.0 {
+        let table = match core.store.get_table(id) {
+            Some(table) => table,
+            None => continue,
+        };
+        let mut cells = Vec::new();
+        for (column, values) in table.data.iter().enumerate() {
+            for (row, value) in values.iter().enumerate() {
+                let previous = old.get(column).and_then(|values| values.get(row));
+                if previous.map_or(*value == Value::Empty, |previous| previous == value) {
+                    continue;
+                }
+                cells.push(ChangedCell { row: row as u64 + 1, column: column as u64 + 1, value: value.clone() });
+            }
+        }
+        match notifications.iter_mut().find(|notification| notification.table == id) {
+            Some(notification) => {
+                cells.retain(|cell| !notification.cells.iter().any(|set| set.row == cell.row && set.column == cell.column));
+                notification.cells.extend(cells);
+            }
+            None if !cells.is_empty() => notifications.push(Notification { table: id, cells }),
+            None => {}
+        }
+    }
+}
+
+/// Queues the notifications of a processed transaction.
+pub(crate) fn queue(notifications: Vec<Notification>) {
+    if notifications.is_empty() {
+        return;
+    }
+    QUEUE.lock().extend(notifications);
+    WAKER.wake();
+}
+
+/// Calls the watchers of all queued notifications. Returns the number of calls.
+///
+/// Notifications queued by the watchers are delivered by the same call.
+pub fn dispatch() -> usize {
+    let mut calls = 0;
+    loop {
+        let queued = core::mem::replace(&mut *QUEUE.lock(), Vec::new());
+        if queued.is_empty() {
+            return calls;
+        }
+        for notification in queued {
+            // the callbacks run without the lock, so they can watch and unwatch
+            let callbacks: Vec<Callback> = WATCHERS
+                .lock()
+                .values()
+                .filter(|&&(table, _)| table == notification.table)
+                .map(|(_, callback)| callback.clone())
+                .collect();
+            for callback in callbacks {
+                callback(&notification.cells);
+                calls += 1;
+            }
+        }
+    }
+}
+
+/// Returns a future that calls watchers whenever notifications are queued.
+pub fn process_watchers() -> ProcessWatchers {
+    ProcessWatchers { _private: () }
+}
+
+/// Future returned by `process_watchers`.
+pub struct ProcessWatchers {
+    _private: (),
+}
+
+impl Future for ProcessWatchers {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        WAKER.register(context.waker());
+        dispatch();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn test_collect_groups_cells_by_table() {
+    use core::sync::atomic::AtomicUsize;
+
+    serial_print!("test_collect_groups_cells_by_table... ");
+    let (watched, other) = (0x5eed_0001, 0x5eed_0002);
+    let cells = Arc::new(AtomicUsize::new(0));
+    let counter = cells.clone();
+    let id = watch(watched, move |changed| {
+        counter.fetch_add(changed.len(), Ordering::SeqCst);
+    });
+    let set = |table, row| Change::Set{table, row: Index::Index(row), column: Index::Index(1), value: Value::from_u64(1)};
+    queue(collect(&[set(watched, 1), set(other, 1), set(watched, 2)]));
+    assert_eq!(dispatch(), 1);
+    assert_eq!(cells.load(Ordering::SeqCst), 2);
+    assert!(unwatch(id));
+    queue(collect(&[set(watched, 1)]));
+    assert_eq!(dispatch(), 0);
+    assert!(!unwatch(id));
+    serial_println!("[ok]");
+}
@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+extern crate x86_64;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::task::ticks;
+use hivemind::{acpi, apic};
+use hivemind::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    apic::init().expect("APIC initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn madt_describes_interrupt_controllers() {
+    serial_print!("madt_describes_interrupt_controllers... ");
+    let madt = acpi::madt().expect("no MADT");
+    assert!(madt.local_apics.iter().any(|entry| entry.enabled));
+    assert!(!madt.io_apics.is_empty());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn apic_is_enabled() {
+    serial_print!("apic_is_enabled... ");
+    assert!(apic::is_enabled());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn local_apic_timer_ticks() {
+    serial_print!("local_apic_timer_ticks... ");
+    let start = ticks::current();
+    while ticks::current() < start + 3 {
+        x86_64::instructions::hlt();
+    }
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
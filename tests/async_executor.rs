@@ -0,0 +1,112 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use bootloader::{entry_point, BootInfo};
+use core::cell::Cell;
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use hivemind::task::executor::Executor;
+use hivemind::task::{ticks, Stream, Task};
+use hivemind::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+/// A future that returns `Pending` a number of times, waking itself each time.
+struct YieldTimes {
+    remaining: usize,
+    polls: Rc<Cell<usize>>,
+}
+
+impl Future for YieldTimes {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        self.polls.set(self.polls.get() + 1);
+        if self.remaining == 0 {
+            return Poll::Ready(());
+        }
+        self.remaining -= 1;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[test_case]
+fn tasks_run_to_completion() {
+    serial_print!("tasks_run_to_completion... ");
+    let polls = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(YieldTimes { remaining: 3, polls: polls.clone() }));
+    executor.spawn(Task::new(YieldTimes { remaining: 0, polls: polls.clone() }));
+    executor.run_until_complete();
+    assert_eq!(polls.get(), 5);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn tick_stream_is_woken_by_timer() {
+    serial_print!("tick_stream_is_woken_by_timer... ");
+    let start = ticks::current();
+    let last = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(TakeTicks {
+        stream: ticks::TickStream::new(),
+        remaining: 3,
+        last: last.clone(),
+    }));
+    executor.run_until_complete();
+    assert!(last.get() >= start + 3);
+    serial_println!("[ok]");
+}
+
+/// A future that takes a number of values from a tick stream.
+struct TakeTicks {
+    stream: ticks::TickStream,
+    remaining: usize,
+    last: Rc<Cell<u64>>,
+}
+
+impl Future for TakeTicks {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        while this.remaining > 0 {
+            match Pin::new(&mut this.stream).poll_next(context) {
+                Poll::Ready(Some(tick)) => {
+                    this.last.set(tick);
+                    this.remaining -= 1;
+                }
+                Poll::Ready(None) => break,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
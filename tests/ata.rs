@@ -0,0 +1,118 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::block::{self, BlockDevice, BlockError};
+use hivemind::{apic, ata, pci, systables, thread};
+use hivemind::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
+    apic::init().expect("APIC initialization failed");
+    pci::init();
+    ata::init();
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+/// The image `build.rs` creates, attached as the primary slave.
+const TEST_DISK: &str = "ata0.1";
+const TEST_DISK_SECTORS: u64 = 2048;
+
+fn test_disk() -> Arc<dyn BlockDevice> {
+    block::find(TEST_DISK).expect("test disk not found")
+}
+
+/// Checks the content `build.rs` wrote to a sector.
+fn assert_pattern(sector: u64, data: &[u8]) {
+    let mut number = [0; 8];
+    number.copy_from_slice(&data[..8]);
+    assert_eq!(u64::from_le_bytes(number), sector);
+    assert!(data[8..].iter().all(|&byte| byte == sector as u8));
+}
+
+#[test_case]
+fn disks_are_identified() {
+    serial_print!("disks_are_identified... ");
+    // the boot image is the primary master
+    assert!(block::find("ata0.0").is_some());
+    let disk = test_disk();
+    assert_eq!(disk.sector_size(), ata::SECTOR_SIZE);
+    assert_eq!(disk.sector_count(), TEST_DISK_SECTORS);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn read_single_sector() {
+    serial_print!("read_single_sector... ");
+    let mut data = [0; ata::SECTOR_SIZE];
+    test_disk().read(7, &mut data).expect("read failed");
+    assert_pattern(7, &data);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn read_more_sectors_than_lba28_allows() {
+    serial_print!("read_more_sectors_than_lba28_allows... ");
+    let mut data = vec![0; 300 * ata::SECTOR_SIZE];
+    test_disk().read(10, &mut data).expect("read failed");
+    for (i, sector) in data.chunks(ata::SECTOR_SIZE).enumerate() {
+        assert_pattern(10 + i as u64, sector);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn write_and_read_back() {
+    serial_print!("write_and_read_back... ");
+    let disk = test_disk();
+    let mut original = vec![0; 2 * ata::SECTOR_SIZE];
+    disk.read(1000, &mut original).expect("read failed");
+
+    let written: alloc::vec::Vec<u8> = (0..original.len()).map(|i| (i * 7) as u8).collect();
+    disk.write(1000, &written).expect("write failed");
+    disk.flush().expect("flush failed");
+    let mut read = vec![0; written.len()];
+    disk.read(1000, &mut read).expect("read failed");
+    assert_eq!(read, written);
+
+    disk.write(1000, &original).expect("write failed");
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn requests_are_checked() {
+    serial_print!("requests_are_checked... ");
+    let disk = test_disk();
+    let mut data = [0; 2 * ata::SECTOR_SIZE];
+    assert_eq!(disk.read(TEST_DISK_SECTORS - 1, &mut data), Err(BlockError::OutOfRange));
+    assert_eq!(disk.read(0, &mut data[..100]), Err(BlockError::BadBufferLength));
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,120 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate x86_64;
+
+use hivemind::cow;
+use hivemind::vma::{self, Region};
+use hivemind::{serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::ptr::{read_volatile, write_volatile};
+use x86_64::structures::paging::{MapperAllSizes, Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+const REGION_START: u64 = 0x_5555_0000_0000;
+const REGION_SIZE: u64 = 16 * 4096;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    vma::register(Region::new("test", VirtAddr::new(REGION_START), REGION_SIZE, flags))
+        .expect("region registration failed");
+
+    test_main();
+    loop {}
+}
+
+/// Returns the address of the `n`th page of the test region.
+fn page_addr(n: u64) -> u64 {
+    REGION_START + n * 4096
+}
+
+fn frame_of(addr: u64) -> PhysFrame {
+    let mapper = hivemind::memory::MAPPER.lock();
+    let phys: PhysAddr = mapper
+        .as_ref()
+        .unwrap()
+        .translate_addr(VirtAddr::new(addr))
+        .expect("page not mapped");
+    PhysFrame::containing_address(phys)
+}
+
+/// Backs the `src`th page with a frame containing `value` and shares it with the `dst`th page.
+fn share(src: u64, dst: u64, value: u64) {
+    unsafe { write_volatile(page_addr(src) as *mut u64, value) };
+    let src_page = Page::containing_address(VirtAddr::new(page_addr(src)));
+    let dst_page = Page::containing_address(VirtAddr::new(page_addr(dst)));
+    cow::share_page(src_page, dst_page).expect("share_page failed");
+}
+
+#[test_case]
+fn shared_page_reads_same_data() {
+    serial_print!("shared_page_reads_same_data... ");
+    share(0, 1, 42);
+    assert_eq!(frame_of(page_addr(0)), frame_of(page_addr(1)));
+    assert_eq!(cow::reference_count(frame_of(page_addr(0))), 2);
+    assert_eq!(unsafe { read_volatile(page_addr(1) as *const u64) }, 42);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn write_to_shared_page_is_isolated() {
+    serial_print!("write_to_shared_page_is_isolated... ");
+    share(2, 3, 7);
+    let shared = frame_of(page_addr(2));
+    unsafe { write_volatile(page_addr(3) as *mut u64, 8) };
+    assert_eq!(unsafe { read_volatile(page_addr(2) as *const u64) }, 7);
+    assert_eq!(unsafe { read_volatile(page_addr(3) as *const u64) }, 8);
+    assert_eq!(frame_of(page_addr(2)), shared);
+    assert_ne!(frame_of(page_addr(3)), shared);
+    assert_eq!(cow::reference_count(shared), 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn last_owner_keeps_frame() {
+    serial_print!("last_owner_keeps_frame... ");
+    share(4, 5, 1);
+    let shared = frame_of(page_addr(4));
+    unsafe { write_volatile(page_addr(5) as *mut u64, 2) };
+    unsafe { write_volatile(page_addr(4) as *mut u64, 3) };
+    assert_eq!(frame_of(page_addr(4)), shared);
+    assert_eq!(unsafe { read_volatile(page_addr(4) as *const u64) }, 3);
+    assert_eq!(unsafe { read_volatile(page_addr(5) as *const u64) }, 2);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn shared_by_three_pages() {
+    serial_print!("shared_by_three_pages... ");
+    share(6, 7, 99);
+    let src = Page::containing_address(VirtAddr::new(page_addr(6)));
+    let dst = Page::containing_address(VirtAddr::new(page_addr(8)));
+    cow::share_page(src, dst).expect("share_page failed");
+    let shared = frame_of(page_addr(6));
+    assert_eq!(cow::reference_count(shared), 3);
+    unsafe { write_volatile(page_addr(7) as *mut u64, 100) };
+    assert_eq!(cow::reference_count(shared), 2);
+    assert_eq!(unsafe { read_volatile(page_addr(6) as *const u64) }, 99);
+    assert_eq!(unsafe { read_volatile(page_addr(8) as *const u64) }, 99);
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
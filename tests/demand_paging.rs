@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate x86_64;
+
+use hivemind::vma::{self, Region, RegionError};
+use hivemind::{serial_print, serial_println};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::paging::{MapperAllSizes, PageTableFlags};
+use x86_64::VirtAddr;
+
+const REGION_START: u64 = 0x_5555_0000_0000;
+const REGION_SIZE: u64 = 16 * 4096;
+const HUGE_REGION_START: u64 = 0x_5555_4000_0000;
+const HUGE_REGION_SIZE: u64 = 2 * 1024 * 1024;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    vma::register(Region::new("test", VirtAddr::new(REGION_START), REGION_SIZE, flags))
+        .expect("region registration failed");
+    let huge_region = Region::new(
+        "huge",
+        VirtAddr::new(HUGE_REGION_START),
+        HUGE_REGION_SIZE,
+        flags,
+    );
+    vma::register(huge_region.with_huge_pages()).expect("region registration failed");
+
+    test_main();
+    loop {}
+}
+
+fn is_mapped(addr: u64) -> bool {
+    let mapper = hivemind::memory::MAPPER.lock();
+    mapper.as_ref().unwrap().translate_addr(VirtAddr::new(addr)).is_some()
+}
+
+#[test_case]
+fn page_mapped_on_first_access() {
+    serial_print!("page_mapped_on_first_access... ");
+    let addr = REGION_START + 3 * 4096;
+    assert!(!is_mapped(addr));
+    let value = unsafe { core::ptr::read_volatile(addr as *const u64) };
+    assert_eq!(value, 0);
+    assert!(is_mapped(addr));
+    assert!(!is_mapped(addr + 4096));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn lazily_mapped_page_is_writable() {
+    serial_print!("lazily_mapped_page_is_writable... ");
+    let ptr = (REGION_START + REGION_SIZE - 8) as *mut u64;
+    unsafe {
+        core::ptr::write_volatile(ptr, 0xdead_beef);
+        assert_eq!(core::ptr::read_volatile(ptr), 0xdead_beef);
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn huge_region_mapped_at_once() {
+    serial_print!("huge_region_mapped_at_once... ");
+    let value = unsafe { core::ptr::read_volatile(HUGE_REGION_START as *const u64) };
+    assert_eq!(value, 0);
+    assert!(is_mapped(HUGE_REGION_START + HUGE_REGION_SIZE - 4096));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn overlapping_region_rejected() {
+    serial_print!("overlapping_region_rejected... ");
+    let region = Region::new(
+        "overlap",
+        VirtAddr::new(REGION_START + 4096),
+        4096,
+        PageTableFlags::PRESENT,
+    );
+    assert_eq!(vma::register(region), Err(RegionError::Overlap("test")));
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,238 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::block::{self, BlockDevice};
+use hivemind::fat::{self, FatFs, FatKind};
+use hivemind::vfs::{self, FileType, OpenMode, SeekFrom, VfsError};
+use hivemind::{apic, ata, pci, systables, thread};
+use hivemind::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
+    apic::init().expect("APIC initialization failed");
+    pci::init();
+    ata::init();
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+/// The disk `scripts/fat-images.sh` creates, attached as the secondary slave.
+const FAT_DISK: &str = "ata1.1";
+/// The FAT32 and FAT16 partitions of the disk, and the labels the script gives them.
+const VOLUMES: [(&str, FatKind, &str); 2] = [
+    ("ata1.1p1", FatKind::Fat32, "HIVEFAT32"),
+    ("ata1.1p2", FatKind::Fat16, "HIVEFAT16"),
+];
+
+fn mount_point(partition: &str) -> String {
+    format!("/mnt/{}", partition)
+}
+
+fn read(path: &str) -> Result<Vec<u8>, VfsError> {
+    vfs::open(path, OpenMode::Read)?.read_to_end()
+}
+
+/// The contents of `big.bin`: `yes 0123456789abcdef | head -c 20000`.
+fn big_file() -> Vec<u8> {
+    b"0123456789abcdef\n".iter().cloned().cycle().take(20000).collect()
+}
+
+/// A pattern longer than a cluster of either volume, so writes span several clusters.
+fn pattern(length: usize, seed: u8) -> Vec<u8> {
+    (0..length).map(|i| (i % 251) as u8 ^ seed).collect()
+}
+
+/// Creates a file or directory; it may be left over from an earlier run on the same disk.
+fn create(path: &str, file_type: FileType) {
+    match vfs::create(path, file_type) {
+        Ok(()) | Err(VfsError::Exists) => {}
+        Err(error) => panic!("creating {} failed: {:?}", path, error),
+    }
+}
+
+#[test_case]
+fn partitions_are_registered() {
+    serial_print!("partitions_are_registered... ");
+    let disk = block::find(FAT_DISK).expect("FAT disk not found");
+    let partitions = block::mbr_partitions(&disk).expect("reading the partition table failed");
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(partitions[0].start(), 2048);
+    assert_eq!(partitions[1].start(), partitions[0].start() + partitions[0].sector_count());
+
+    block::scan_partitions();
+    assert_eq!(block::scan_partitions(), 0);
+    for &(name, _, _) in VOLUMES.iter() {
+        block::find(name).expect("partition not registered");
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn volumes_are_recognized() {
+    serial_print!("volumes_are_recognized... ");
+    for &(name, kind, label) in VOLUMES.iter() {
+        let partition = block::find(name).expect("partition not found");
+        let volume = FatFs::new(partition).expect("not a FAT volume");
+        assert_eq!(volume.kind(), kind);
+        assert_eq!(volume.label(), label);
+    }
+    // the whole disk starts with a partition table, not a boot sector
+    assert!(FatFs::new(block::find(FAT_DISK).expect("FAT disk not found")).is_err());
+    assert!(fat::mount_all() >= VOLUMES.len());
+    let mounts = vfs::mounts();
+    assert!(mounts.contains(&(mount_point(VOLUMES[0].0), String::from("fat32"))));
+    assert!(mounts.contains(&(mount_point(VOLUMES[1].0), String::from("fat16"))));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn files_from_the_host_are_read() {
+    serial_print!("files_from_the_host_are_read... ");
+    for &(name, _, _) in VOLUMES.iter() {
+        let root = mount_point(name);
+        assert_eq!(read(&format!("{}/README.TXT", root)), Ok(b"Hello from a FAT volume.\n".to_vec()));
+        // names are compared ignoring case
+        assert_eq!(read(&format!("{}/readme.txt", root)), Ok(b"Hello from a FAT volume.\n".to_vec()));
+        assert_eq!(
+            read(&format!("{}/A long file name.txt", root)),
+            Ok(b"This file has a long name.\n".to_vec())
+        );
+        assert_eq!(read(&format!("{}/docs/nested/deep.txt", root)), Ok(b"Three levels down.\n".to_vec()));
+        assert_eq!(read(&format!("{}/big.bin", root)), Ok(big_file()));
+        assert_eq!(read(&format!("{}/missing.txt", root)), Err(VfsError::NotFound));
+        assert_eq!(read(&format!("{}/docs", root)).err(), Some(VfsError::IsADirectory));
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn directories_list_long_names() {
+    serial_print!("directories_list_long_names... ");
+    for &(name, _, _) in VOLUMES.iter() {
+        let entries = vfs::readdir(&mount_point(name)).expect("readdir failed");
+        let find = |entry_name: &str| {
+            entries.iter().find(|entry| entry.name == entry_name).map(|entry| entry.file_type)
+        };
+        assert_eq!(find("A long file name.txt"), Some(FileType::File));
+        assert_eq!(find("big.bin"), Some(FileType::File));
+        assert_eq!(find("docs"), Some(FileType::Directory));
+        // neither the volume label nor `.` and `..` are listed
+        assert_eq!(find("HIVEFAT32"), None);
+        let docs = vfs::readdir(&format!("{}/docs", mount_point(name))).expect("readdir failed");
+        assert_eq!(docs.len(), 1);
+        assert_eq!(docs[0].name, "nested");
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn files_are_created_and_written() {
+    serial_print!("files_are_created_and_written... ");
+    for (seed, &(name, _, _)) in VOLUMES.iter().enumerate() {
+        let path = format!("{}/Written by the kernel.bin", mount_point(name));
+        create(&path, FileType::File);
+        let mut file = vfs::open(&path, OpenMode::ReadWrite).expect("open failed");
+        file.truncate(0).expect("truncate failed");
+        let data = pattern(10000, seed as u8);
+        assert_eq!(file.write(&data), Ok(data.len()));
+        assert_eq!(file.stat().size, data.len() as u64);
+        file.sync().expect("sync failed");
+        assert_eq!(read(&path), Ok(data.clone()));
+
+        // shrinking frees clusters, growing fills the gap with zeros
+        file.truncate(100).expect("truncate failed");
+        file.truncate(5000).expect("truncate failed");
+        let contents = read(&path).expect("read failed");
+        assert_eq!(&contents[..100], &data[..100]);
+        assert!(contents[100..].iter().all(|&byte| byte == 0));
+        assert_eq!(contents.len(), 5000);
+        file.truncate(0).expect("truncate failed");
+        assert_eq!(file.seek(SeekFrom::Start(0)), Ok(0));
+        assert_eq!(file.write(&data), Ok(data.len()));
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn directories_are_created() {
+    serial_print!("directories_are_created... ");
+    for &(name, _, _) in VOLUMES.iter() {
+        let root = mount_point(name);
+        create(&format!("{}/New directory", root), FileType::Directory);
+        create(&format!("{}/New directory/INNER", root), FileType::Directory);
+        let path = format!("{}/New directory/INNER/note.txt", root);
+        create(&path, FileType::File);
+        let mut file = vfs::open(&path, OpenMode::ReadWrite).expect("open failed");
+        file.truncate(0).expect("truncate failed");
+        file.write(b"made by mkdir").expect("write failed");
+        let inner = vfs::stat(&format!("{}/new directory/inner", root)).expect("stat failed");
+        assert_eq!(inner.file_type, FileType::Directory);
+        assert_eq!(vfs::create(&format!("{}/NEW DIRECTORY", root), FileType::Directory), Err(VfsError::Exists));
+        assert_eq!(vfs::create(&format!("{}/bad:name", root), FileType::File), Err(VfsError::InvalidPath));
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn many_entries_grow_a_directory() {
+    serial_print!("many_entries_grow_a_directory... ");
+    // a 512-byte cluster holds 16 entries, each long name here takes three
+    let (name, _, _) = VOLUMES[0];
+    let dir = format!("{}/Crowded directory", mount_point(name));
+    create(&dir, FileType::Directory);
+    for i in 0..20 {
+        create(&format!("{}/A file with a long name {}", dir, i), FileType::File);
+    }
+    let entries = vfs::readdir(&dir).expect("readdir failed");
+    assert_eq!(entries.len(), 20);
+    for i in 0..20 {
+        vfs::stat(&format!("{}/a file with a long name {}", dir, i)).expect("file not found");
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn changes_survive_a_remount() {
+    serial_print!("changes_survive_a_remount... ");
+    for (seed, &(name, _, _)) in VOLUMES.iter().enumerate() {
+        let root = mount_point(name);
+        vfs::unmount(&root).expect("unmount failed");
+        let partition = block::find(name).expect("partition not found");
+        let volume = FatFs::new(partition).expect("not a FAT volume");
+        vfs::mount(&root, Arc::new(volume)).expect("mount failed");
+        assert_eq!(read(&format!("{}/Written by the kernel.bin", root)), Ok(pattern(10000, seed as u8)));
+        assert_eq!(read(&format!("{}/New directory/INNER/note.txt", root)), Ok(b"made by mkdir".to_vec()));
+        assert_eq!(read(&format!("{}/big.bin", root)), Ok(big_file()));
+    }
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
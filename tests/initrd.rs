@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[macro_use]
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::initrd::{self, InitrdError};
+use hivemind::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn linked_archive_is_parsed() {
+    serial_print!("linked_archive_is_parsed... ");
+    assert!(initrd::init().expect("parsing the initrd failed") >= 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn files_match_the_initrd_directory() {
+    serial_print!("files_match_the_initrd_directory... ");
+    assert_eq!(initrd::read("/etc/motd"), Ok(&include_bytes!("../initrd/etc/motd")[..]));
+    assert_eq!(initrd::read("etc/./motd"), initrd::read("/etc/motd"));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn directories_are_listed() {
+    serial_print!("directories_are_listed... ");
+    let ramdisk = initrd::ramdisk().expect("parsing the initrd failed");
+    assert!(ramdisk.list("/").expect("listing the root failed").contains(&"etc"));
+    assert_eq!(ramdisk.list("etc"), Ok(vec!["motd"]));
+    assert_eq!(ramdisk.list("etc/motd"), Err(InitrdError::NotADirectory));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn missing_files_are_not_found() {
+    serial_print!("missing_files_are_not_found... ");
+    assert_eq!(initrd::read("etc/missing"), Err(InitrdError::NotFound));
+    assert_eq!(initrd::read("etc/motd/child"), Err(InitrdError::NotFound));
+    assert_eq!(initrd::read("etc"), Err(InitrdError::NotAFile));
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+extern crate hivemind;
+extern crate lazy_static;
+extern crate spin;
+extern crate x86_64;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+/// The heap address the test jumps to.
+static TARGET: Mutex<Option<VirtAddr>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    serial_print!("no_execute_heap... ");
+
+    hivemind::gdt::init();
+    hivemind::cpu::enable_no_execute();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::protect_kernel(&boot_info.memory_map, &mut mapper);
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    init_test_idt();
+
+    // a single `ret` instruction on the heap
+    let code = Box::new([0xc3u8]);
+    *TARGET.lock() = Some(VirtAddr::from_ptr(code.as_ptr()));
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code.as_ptr()) };
+    function();
+
+    panic!("Execution from the heap did not fault");
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH;
+    if error_code != expected {
+        serial_println!("[failed]");
+        serial_println!("unexpected error code: {:?}", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    } else if Some(Cr2::read()) != *TARGET.lock() {
+        serial_println!("[failed]");
+        serial_println!("unexpected fault address: {:?}", Cr2::read());
+        exit_qemu(QemuExitCode::Failed);
+    } else {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+extern crate mech_core;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use hivemind::pci::{self, Driver, Match, PciDevice};
+use hivemind::systables;
+use hivemind::{serial_print, serial_println, HiveCore};
+use mech_core::{Hasher, Value};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
+    pci::init();
+
+    test_main();
+    loop {}
+}
+
+/// The i440FX host bridge QEMU emulates by default.
+const HOST_BRIDGE: Match = Match::Id { vendor: 0x8086, device: 0x1237 };
+
+#[test_case]
+fn host_bridge_is_found() {
+    serial_print!("host_bridge_is_found... ");
+    let devices = pci::devices();
+    let bridge = devices
+        .iter()
+        .find(|device| HOST_BRIDGE.matches(device))
+        .expect("no host bridge");
+    assert_eq!((bridge.address.bus, bridge.address.device, bridge.address.function), (0, 0, 0));
+    assert_eq!(bridge.class, 0x06);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn pci_table_lists_the_host_bridge_first() {
+    serial_print!("pci_table_lists_the_host_bridge_first... ");
+    let pci = Hasher::hash_str("pci");
+    let core = HiveCore.lock();
+    let table = core.store.get_table(pci).expect("no #pci table");
+    // the bus number of 00:00.0
+    assert!(table.data[0][0] == Value::from_u64(0));
+    serial_println!("[ok]");
+}
+
+static PROBES: AtomicUsize = AtomicUsize::new(0);
+
+fn probe_bridge(_device: &PciDevice) -> bool {
+    PROBES.fetch_add(1, Ordering::SeqCst);
+    true
+}
+
+static BRIDGE_DRIVER: Driver = Driver {
+    name: "test-host-bridge",
+    matches: &[HOST_BRIDGE],
+    probe: probe_bridge,
+};
+
+static SECOND_DRIVER: Driver = Driver {
+    name: "test-host-bridge-class",
+    matches: &[Match::Class { class: 0x06, subclass: 0x00 }],
+    probe: probe_bridge,
+};
+
+#[test_case]
+fn driver_is_bound_once() {
+    serial_print!("driver_is_bound_once... ");
+    pci::register_driver(&BRIDGE_DRIVER);
+    assert_eq!(PROBES.load(Ordering::SeqCst), 1);
+    let bridge = pci::devices()
+        .into_iter()
+        .find(|device| HOST_BRIDGE.matches(device))
+        .expect("no host bridge");
+    assert_eq!(pci::driver_of(bridge.address), Some("test-host-bridge"));
+
+    // the bridge already has a driver, so it is not offered again
+    pci::register_driver(&SECOND_DRIVER);
+    assert_eq!(PROBES.load(Ordering::SeqCst), 1);
+    assert_eq!(pci::driver_of(bridge.address), Some("test-host-bridge"));
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,235 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[macro_use]
+extern crate alloc;
+extern crate mech_core;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::block::{self, BlockDevice};
+use hivemind::persist::{self, PersistError, Restored, Slot};
+use hivemind::{apic, ata, pci, program, systables, thread, HiveCore};
+use hivemind::{serial_print, serial_println};
+use mech_core::{Change, Hasher, Index, Value};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
+    apic::init().expect("APIC initialization failed");
+    pci::init();
+    ata::init();
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+/// The blank disk `build.rs` creates for these tests, attached as the secondary master.
+const STORE_DISK: &str = "ata1.0";
+
+fn store_disk() -> Arc<dyn BlockDevice> {
+    block::find(STORE_DISK).expect("store disk not found")
+}
+
+/// The first sector of snapshot slot B, a quarter into the disk.
+fn slot_b_start(disk: &dyn BlockDevice) -> u64 {
+    disk.sector_count() / 4
+}
+
+/// The first record of the journal, which starts halfway into the disk.
+fn first_record(disk: &dyn BlockDevice) -> u64 {
+    disk.sector_count() / 4 * 2 + 1
+}
+
+/// Formats the store disk and opens it, so a test starts with an empty store.
+fn fresh_store() -> Arc<dyn BlockDevice> {
+    let disk = store_disk();
+    persist::format(&*disk).expect("format failed");
+    assert_eq!(persist::open(disk.clone()), Ok(Restored { tables: 0, transactions: 0 }));
+    disk
+}
+
+fn create_test_table() -> u64 {
+    let id = Hasher::hash_str("persist-test");
+    persist::transact(vec![
+        Change::NewTable{ id, rows: 2, columns: 1 },
+        Change::Set{table: id, row: Index::Index(1), column: Index::Index(1), value: Value::from_u64(1234)},
+        Change::Set{table: id, row: Index::Index(2), column: Index::Index(1), value: Value::from_str("bees")},
+    ]).expect("journaling failed");
+    id
+}
+
+#[test_case]
+fn format_writes_an_empty_store() {
+    serial_print!("format_writes_an_empty_store... ");
+    let disk = store_disk();
+    persist::format(&*disk).expect("format failed");
+    let contents = persist::read_store(&*disk).expect("reading the store failed");
+    assert_eq!(contents.slot, Slot::A);
+    assert_eq!(contents.image.sequence, 0);
+    assert!(contents.image.tables.is_empty());
+    assert!(contents.records.is_empty());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn open_restores_an_empty_store() {
+    serial_print!("open_restores_an_empty_store... ");
+    persist::format(&*store_disk()).expect("format failed");
+    let restored = persist::open(store_disk()).expect("open failed");
+    assert_eq!(restored, Restored { tables: 0, transactions: 0 });
+    assert_eq!(persist::store().expect("no store").name(), STORE_DISK);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn transactions_are_journaled() {
+    serial_print!("transactions_are_journaled... ");
+    let disk = fresh_store();
+    let id = create_test_table();
+    assert!(HiveCore.lock().store.get_table(id).expect("table not created").data[0][0] == Value::from_u64(1234));
+
+    let contents = persist::read_store(&*disk).expect("reading the store failed");
+    assert!(contents.image.tables.is_empty());
+    assert_eq!(contents.records.len(), 1);
+    assert_eq!(contents.records[0].len(), 3);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn journaled_tables_are_not_replaced() {
+    serial_print!("journaled_tables_are_not_replaced... ");
+    fresh_store();
+    create_test_table();
+    // the table exists already, so replaying the journal must keep it as it is
+    let restored = persist::open(store_disk()).expect("open failed");
+    assert_eq!(restored, Restored { tables: 0, transactions: 1 });
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn compaction_empties_the_journal() {
+    serial_print!("compaction_empties_the_journal... ");
+    let disk = fresh_store();
+    create_test_table();
+    persist::compact().expect("compaction failed");
+    let contents = persist::read_store(&*disk).expect("reading the store failed");
+    assert_eq!(contents.slot, Slot::B);
+    assert_eq!(contents.image.sequence, 1);
+    assert_eq!(contents.image.tables, persist::snapshot(&HiveCore.lock()));
+    assert!(contents.records.is_empty());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn torn_record_is_dropped() {
+    serial_print!("torn_record_is_dropped... ");
+    let disk = fresh_store();
+    create_test_table();
+    assert_eq!(persist::read_store(&*disk).expect("reading the store failed").records.len(), 1);
+
+    let mut original = vec![0; disk.sector_size()];
+    disk.read(first_record(&*disk), &mut original).expect("read failed");
+    let mut torn = original.clone();
+    torn[20] ^= 0x40;
+    disk.write(first_record(&*disk), &torn).expect("write failed");
+    assert!(persist::read_store(&*disk).expect("reading the store failed").records.is_empty());
+
+    disk.write(first_record(&*disk), &original).expect("write failed");
+    assert_eq!(persist::read_store(&*disk).expect("reading the store failed").records.len(), 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn corrupted_slot_falls_back_to_the_other() {
+    serial_print!("corrupted_slot_falls_back_to_the_other... ");
+    // slot B is newer than slot A, and the journal continues it
+    let disk = fresh_store();
+    persist::compact().expect("compaction failed");
+    create_test_table();
+    let mut original = vec![0; disk.sector_size()];
+    disk.read(slot_b_start(&*disk), &mut original).expect("read failed");
+    let mut corrupted = original.clone();
+    corrupted[12] ^= 0x01;
+    disk.write(slot_b_start(&*disk), &corrupted).expect("write failed");
+    assert_eq!(persist::read_image(&*disk, Slot::B), Err(PersistError::Corrupted));
+
+    // the journal continues slot B, so it is not replayed on top of slot A
+    let contents = persist::read_store(&*disk).expect("reading the store failed");
+    assert_eq!(contents.slot, Slot::A);
+    assert_eq!(contents.image.sequence, 0);
+    assert!(contents.records.is_empty());
+
+    disk.write(slot_b_start(&*disk), &original).expect("write failed");
+    assert_eq!(persist::read_store(&*disk).expect("reading the store failed").slot, Slot::B);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn blank_disk_has_no_store() {
+    serial_print!("blank_disk_has_no_store... ");
+    // the first sector of the ATA test disk is all zeros
+    let disk = block::find("ata0.1").expect("test disk not found");
+    assert_eq!(persist::read_image(&*disk, Slot::A), Err(PersistError::NoImage));
+    assert!(persist::read_store(&*disk).is_err());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn init_chooses_the_disk_with_a_store() {
+    serial_print!("init_chooses_the_disk_with_a_store... ");
+    fresh_store();
+    create_test_table();
+    assert_eq!(persist::init(), Ok(Restored { tables: 0, transactions: 1 }));
+    assert_eq!(persist::store().expect("no store").name(), STORE_DISK);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn system_tables_are_not_journaled() {
+    serial_print!("system_tables_are_not_journaled... ");
+    let disk = fresh_store();
+    assert_eq!(systables::KEYPRESS.get(1, "character"), Some(Value::Empty));
+    systables::set(vec![systables::KEYPRESS.set_str(1, "character", "b")]);
+    assert_eq!(persist::open(disk), Ok(Restored { tables: 0, transactions: 0 }));
+    assert_eq!(systables::KEYPRESS.get(1, "character"), Some(Value::from_str("b")));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn cells_set_by_blocks_are_not_journaled() {
+    serial_print!("cells_set_by_blocks_are_not_journaled... ");
+    let disk = fresh_store();
+    let input = Hasher::hash_str("persist-input");
+    assert_eq!(program::load("  #persist-output = #persist-input + 1\n"), Ok(1));
+    persist::transact(vec![
+        Change::NewTable{ id: input, rows: 1, columns: 1 },
+        Change::Set{table: input, row: Index::Index(1), column: Index::Index(1), value: Value::from_u64(200)},
+    ]).expect("journaling failed");
+    let output = HiveCore.lock().store.get_table(Hasher::hash_str("persist-output")).expect("no table").data[0][0].clone();
+    assert_eq!(output, Value::from_u64(201));
+    // only the transaction setting the input is journaled
+    assert_eq!(persist::open(disk), Ok(Restored { tables: 0, transactions: 1 }));
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::{acpi, power};
+use hivemind::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn fadt_has_power_management_registers() {
+    serial_print!("fadt_has_power_management_registers... ");
+    let fadt = acpi::fadt().expect("no FADT");
+    assert_ne!(fadt.pm1a_control, 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn dsdt_defines_soft_off() {
+    serial_print!("dsdt_defines_soft_off... ");
+    let fadt = acpi::fadt().expect("no FADT");
+    let dsdt = acpi::dsdt(&fadt).expect("no DSDT");
+    assert_eq!(&dsdt[..4], b"DSDT");
+    assert!(power::parse_s5(acpi::aml(dsdt)).is_some());
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[macro_use]
+extern crate alloc;
+extern crate mech_core;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::initrd::InitrdFs;
+use hivemind::program::{self, ProgramError};
+use hivemind::sync::IrqMutex;
+use hivemind::{persist, systables, vfs, watch, HiveCore};
+use hivemind::{serial_print, serial_println};
+use mech_core::{Change, Hasher, Value};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
+    vfs::mount("/", Arc::new(InitrdFs::new().expect("initrd unusable"))).expect("mounting / failed");
+
+    test_main();
+    loop {}
+}
+
+/// Writes a tick count to `#timer` like the timer task does.
+fn set_timer(ticks: u64) {
+    systables::set(vec![systables::TIMER.set_u64(1, "ticks", ticks)]);
+}
+
+fn cell(table: &str, row: usize, column: usize) -> Option<Value> {
+    let core = HiveCore.lock();
+    let table = core.store.get_table(Hasher::hash_str(table))?;
+    table.data.get(column - 1).and_then(|column| column.get(row - 1)).cloned()
+}
+
+#[test_case]
+fn programs_load_from_the_initrd() {
+    serial_print!("programs_load_from_the_initrd... ");
+    assert_eq!(program::init(), 1);
+    assert_eq!(program::blocks()[0].name, "#timer/hundreds = #timer{1, ticks} / 100");
+    set_timer(1200);
+    assert_eq!(cell("timer/hundreds", 1, 1), Some(Value::from_u64(12)));
+    assert_eq!(program::blocks()[0].errors, 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn blocks_react_to_the_timer() {
+    serial_print!("blocks_react_to_the_timer... ");
+    let source = "# Derived\n\n  ticks = #timer\n  #derived = ticks * 2 + 1\n\n  #derived/half = (#derived - 1) / 2\n";
+    assert_eq!(program::load(source), Ok(2));
+    assert_eq!(cell("derived", 1, 1), Some(Value::from_u64(2401)));
+    assert_eq!(cell("derived/half", 1, 1), Some(Value::from_u64(1200)));
+    set_timer(2000);
+    assert_eq!(cell("derived", 1, 1), Some(Value::from_u64(4001)));
+    assert_eq!(cell("timer/hundreds", 1, 1), Some(Value::from_u64(20)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn cells_are_set_in_existing_tables() {
+    serial_print!("cells_are_set_in_existing_tables... ");
+    let counts = Hasher::hash_str("counts");
+    persist::transact(vec![Change::NewTable{ id: counts, rows: 10, columns: 10 }]).expect("transaction failed");
+    assert_eq!(program::load("  #counts{2, 3} := \"seen\"\n  #counts{11, 1} := 0\n"), Err(ProgramError::OutOfRange));
+    // the block is rejected when it is loaded, so it sets nothing
+    assert_eq!(cell("counts", 2, 3), Some(Value::Empty));
+    assert_eq!(program::load("  #counts{2, 3} := #timer/hundreds\n  #counts{1, 1} := #timer{1, ticks}\n"), Ok(1));
+    assert_eq!(cell("counts", 2, 3), Some(Value::from_u64(20)));
+    assert_eq!(cell("counts", 1, 1), Some(Value::from_u64(2000)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn blocks_run_in_dependency_order() {
+    serial_print!("blocks_run_in_dependency_order... ");
+    // the first block reads a table only the second one creates
+    assert_eq!(program::load("  #chain/b = #chain/a * 2\n\n  #chain/a = 21\n"), Ok(2));
+    assert_eq!(cell("chain/a", 1, 1), Some(Value::from_u64(21)));
+    assert_eq!(cell("chain/b", 1, 1), Some(Value::from_u64(42)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn numbers_may_have_fractions() {
+    serial_print!("numbers_may_have_fractions... ");
+    assert_eq!(program::load("  #fractions = 2.5 * 4 + 7 / 2 * 2\n"), Ok(1));
+    assert_eq!(cell("fractions", 1, 1), Some(Value::from_u64(17)));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn system_tables_keep_their_schema() {
+    serial_print!("system_tables_keep_their_schema... ");
+    let blocks = program::blocks().len();
+    assert_eq!(program::load("  #power{1, request} := 5\n"), Err(ProgramError::Schema));
+    assert_eq!(program::blocks().len(), blocks);
+    assert_eq!(systables::POWER.get(1, "request"), Some(Value::Empty));
+    assert_eq!(program::load("  #power{1, request} := \"nap\"\n"), Ok(1));
+    assert_eq!(systables::POWER.get(1, "request"), Some(Value::from_str("nap")));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn invalid_programs_are_rejected() {
+    serial_print!("invalid_programs_are_rejected... ");
+    let blocks = program::blocks().len();
+    assert_eq!(program::load("# Broken\n\n  #a = 1 +\n"), Err(ProgramError::Syntax(3)));
+    assert_eq!(program::load_file("/programs/missing.mec"), Err(ProgramError::File(vfs::VfsError::NotFound)));
+    assert_eq!(program::blocks().len(), blocks);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn watchers_see_cells_set_by_blocks() {
+    serial_print!("watchers_see_cells_set_by_blocks... ");
+    let seen = Arc::new(IrqMutex::new("SEEN", Vec::new()));
+    let recorder = seen.clone();
+    let watcher = watch::watch(Hasher::hash_str("timer/hundreds"), move |cells| {
+        recorder.lock().extend(cells.iter().map(|cell| cell.value.clone()));
+    });
+    set_timer(2500);
+    assert_eq!(watch::dispatch(), 1);
+    assert_eq!(*seen.lock(), vec![Value::from_u64(25)]);
+    assert!(watch::unwatch(watcher));
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
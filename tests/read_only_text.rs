@@ -0,0 +1,76 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate hivemind;
+extern crate lazy_static;
+extern crate x86_64;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::memory;
+
+    serial_print!("read_only_text... ");
+
+    hivemind::gdt::init();
+    hivemind::cpu::enable_no_execute();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    memory::protect_kernel(&boot_info.memory_map, &mut mapper);
+    init_test_idt();
+
+    // overwrite the first instruction of a function in `.text`
+    let target = target_function as *const () as *mut u8;
+    unsafe { core::ptr::write_volatile(target, 0xc3) };
+
+    panic!("Writing to .text did not fault");
+}
+
+fn target_function() {
+    serial_println!("target_function was called");
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE;
+    if error_code != expected {
+        serial_println!("[failed]");
+        serial_println!("unexpected error code: {:?}", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    } else if Cr2::read() != VirtAddr::new(target_function as *const () as u64) {
+        serial_println!("[failed]");
+        serial_println!("unexpected fault address: {:?}", Cr2::read());
+        exit_qemu(QemuExitCode::Failed);
+    } else {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
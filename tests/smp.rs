@@ -0,0 +1,94 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+extern crate mech_core;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::task::ticks;
+use hivemind::{acpi, apic, percpu, smp, systables};
+use hivemind::{serial_print, serial_println};
+use mech_core::Value;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
+    apic::init().expect("APIC initialization failed");
+    smp::init(&boot_info.memory_map).expect("SMP initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn all_processors_online() {
+    serial_print!("all_processors_online... ");
+    let madt = acpi::madt().expect("no MADT");
+    let expected = madt.local_apics.iter().filter(|entry| entry.enabled).count();
+    let cpus = smp::cpus();
+    assert!(expected > 1, "run with more than one processor");
+    assert_eq!(cpus.len(), expected);
+    for (index, cpu) in cpus.iter().enumerate() {
+        assert_eq!(cpu.index, index);
+        assert!(cpu.is_online());
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn processors_have_distinct_apic_ids() {
+    serial_print!("processors_have_distinct_apic_ids... ");
+    let cpus = smp::cpus();
+    for (n, cpu) in cpus.iter().enumerate() {
+        assert!(cpus[..n].iter().all(|other| other.apic_id() != cpu.apic_id()));
+    }
+    assert_eq!(percpu::current().index, 0);
+    assert_eq!(percpu::current().apic_id(), apic::local_apic_id());
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn ipi_reaches_every_processor() {
+    serial_print!("ipi_reaches_every_processor... ");
+    for cpu in smp::cpus().into_iter().skip(1) {
+        let before = cpu.ipis();
+        smp::ping(cpu);
+        let start = ticks::current();
+        while cpu.ipis() == before {
+            assert!(ticks::current() < start + 10, "no IPI on cpu {}", cpu.index);
+        }
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn cpus_table_lists_apic_ids() {
+    serial_print!("cpus_table_lists_apic_ids... ");
+    let cpus = smp::cpus();
+    for cpu in cpus.iter() {
+        assert_eq!(systables::CPUS.get_u64(cpu.index + 1, "apic-id"), Some(u64::from(cpu.apic_id())));
+    }
+    // rows of processors that are not there stay empty
+    assert_eq!(systables::CPUS.get(cpus.len() + 1, "apic-id"), Some(Value::Empty));
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,86 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[macro_use]
+extern crate alloc;
+extern crate mech_core;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::allocator::{heap_used, HEAP_SIZE};
+use hivemind::systables::STATS;
+use hivemind::{persist, stats, systables};
+use hivemind::{serial_print, serial_println};
+use mech_core::{Change, Hasher, Index, Value};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn transactions_are_counted() {
+    serial_print!("transactions_are_counted... ");
+    let before = stats::current();
+    let bees = Hasher::hash_str("counted-bees");
+    persist::transact(vec![
+        Change::NewTable{ id: bees, rows: 3, columns: 4 },
+        Change::Set{table: bees, row: Index::Index(1), column: Index::Index(1), value: Value::from_u64(1)},
+    ]).expect("transaction failed");
+    let after = stats::current();
+    assert_eq!(after.transactions, before.transactions + 1);
+    assert_eq!(after.changes, before.changes + 2);
+    assert!(after.cycles > before.cycles);
+    assert_eq!(after.tables, before.tables + 1);
+    assert_eq!(after.cells, before.cells + 12);
+    assert!(after.heap_used > 0 && after.heap_used < after.heap_size);
+    assert_eq!(after.heap_size, HEAP_SIZE as u64);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn stats_are_published() {
+    serial_print!("stats_are_published... ");
+    let published = stats::current();
+    stats::publish();
+    assert_eq!(STATS.get_u64(1, "transactions"), Some(published.transactions));
+    assert_eq!(STATS.get_u64(1, "tables"), Some(published.tables));
+    assert_eq!(STATS.get_u64(1, "heap-size"), Some(HEAP_SIZE as u64));
+    // the publishing transaction counts for the next update
+    assert_eq!(stats::current().transactions, published.transactions + 1);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn heap_use_is_counted() {
+    serial_print!("heap_use_is_counted... ");
+    let before = heap_used();
+    let bytes = vec![0u8; 4096];
+    let allocated = heap_used();
+    assert!(allocated >= before + bytes.len());
+    drop(bytes);
+    assert!(heap_used() <= allocated - 4096);
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate hivemind;
+extern crate lazy_static;
+extern crate x86_64;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::uaccess::{self, UserAccessError};
+use hivemind::{cpu, exit_qemu, serial_print, serial_println, QemuExitCode};
+use lazy_static::lazy_static;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+const USER_PAGE: u64 = 0x_1000_0000_0000;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    serial_print!("supervisor_access... ");
+
+    hivemind::gdt::init();
+    cpu::enable_supervisor_protection();
+    if !cpu::has_smap() {
+        serial_println!("[ok] (SMAP not supported, run with `-cpu qemu64,+smap`)");
+        exit_qemu(QemuExitCode::Success);
+        loop {}
+    }
+
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    let page: Page = Page::containing_address(VirtAddr::new(USER_PAGE));
+    let frame: PhysFrame = frame_allocator.allocate_frame().expect("no frame left");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, &mut frame_allocator)
+            .expect("map_to failed")
+            .flush();
+        assert!(memory::set_user_accessible(page));
+    }
+
+    // bracketed accesses succeed
+    uaccess::copy_to_user(VirtAddr::new(USER_PAGE), &[1, 2, 3]).expect("copy_to_user failed");
+    let mut buffer = [0u8; 3];
+    uaccess::copy_from_user(&mut buffer, VirtAddr::new(USER_PAGE)).expect("copy_from_user failed");
+    assert_eq!(buffer, [1, 2, 3]);
+    assert_eq!(
+        uaccess::copy_from_user(&mut buffer, VirtAddr::new(0xffff_8000_0000_0000)),
+        Err(UserAccessError::BadAddress)
+    );
+
+    // a stray access faults
+    init_test_idt();
+    unsafe { core::ptr::read_volatile(USER_PAGE as *const u8) };
+
+    panic!("Kernel access to a user page did not fault");
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: &mut InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    if error_code != PageFaultErrorCode::PROTECTION_VIOLATION {
+        serial_println!("[failed]");
+        serial_println!("unexpected error code: {:?}", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    } else if Cr2::read() != VirtAddr::new(USER_PAGE) {
+        serial_println!("[failed]");
+        serial_println!("unexpected fault address: {:?}", Cr2::read());
+        exit_qemu(QemuExitCode::Failed);
+    } else {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use hivemind::sync::{Semaphore, SleepMutex, WaitQueue};
+use hivemind::thread;
+use hivemind::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn sleep_mutex_excludes_threads() {
+    serial_print!("sleep_mutex_excludes_threads... ");
+    let counter = Arc::new(SleepMutex::new("counter", 0));
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..10 {
+                    let mut value = counter.lock();
+                    let read = *value;
+                    // give the other threads a chance to run inside the critical section
+                    thread::yield_now();
+                    *value = read + 1;
+                }
+            })
+            .expect("spawn failed")
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(*counter.lock(), 30);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn semaphore_blocks_until_released() {
+    serial_print!("semaphore_blocks_until_released... ");
+    let semaphore = Arc::new(Semaphore::new(0));
+    let acquired = Arc::new(AtomicBool::new(false));
+    let waiter = {
+        let semaphore = semaphore.clone();
+        let acquired = acquired.clone();
+        thread::spawn(move || {
+            semaphore.acquire();
+            acquired.store(true, Ordering::SeqCst);
+        })
+        .expect("spawn failed")
+    };
+    thread::sleep(3);
+    assert!(!acquired.load(Ordering::SeqCst));
+    semaphore.release();
+    waiter.join();
+    assert!(acquired.load(Ordering::SeqCst));
+    assert_eq!(semaphore.permits(), 0);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn wait_queue_wakes_all_waiters() {
+    serial_print!("wait_queue_wakes_all_waiters... ");
+    let queue = Arc::new(WaitQueue::new());
+    let open = Arc::new(AtomicBool::new(false));
+    let passed = Arc::new(AtomicUsize::new(0));
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let queue = queue.clone();
+            let open = open.clone();
+            let passed = passed.clone();
+            thread::spawn(move || {
+                queue.wait_until(|| open.load(Ordering::SeqCst));
+                passed.fetch_add(1, Ordering::SeqCst);
+            })
+            .expect("spawn failed")
+        })
+        .collect();
+    thread::sleep(3);
+    assert_eq!(passed.load(Ordering::SeqCst), 0);
+    open.store(true, Ordering::SeqCst);
+    queue.wake_all();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(passed.load(Ordering::SeqCst), 3);
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
@@ -0,0 +1,122 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[macro_use]
+extern crate alloc;
+extern crate mech_core;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::tablefs::{self, TableFs};
+use hivemind::vfs::{self, FileType, OpenMode, VfsError};
+use hivemind::{persist, systables, HiveCore};
+use hivemind::{serial_print, serial_println};
+use mech_core::{Change, Hasher, Index, Value};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
+    tablefs::init();
+    vfs::mount("/tables", Arc::new(TableFs)).expect("mounting /tables failed");
+
+    test_main();
+    loop {}
+}
+
+/// Creates `#bees`, two rows of a number and a string, and an unnamed table.
+fn create_tables() {
+    let bees = tablefs::register_name("bees");
+    // no store disk is open, so this only processes the transaction
+    persist::transact(vec![
+        Change::NewTable{ id: bees, rows: 2, columns: 2 },
+        Change::Set{table: bees, row: Index::Index(1), column: Index::Index(1), value: Value::from_u64(7)},
+        Change::Set{table: bees, row: Index::Index(1), column: Index::Index(2), value: Value::from_str("drone")},
+        Change::Set{table: bees, row: Index::Index(2), column: Index::Index(1), value: Value::Bool(true)},
+        Change::NewTable{ id: 0xfeed, rows: 1, columns: 1 },
+    ]).expect("transaction failed");
+}
+
+fn read(path: &str) -> Result<Vec<u8>, VfsError> {
+    vfs::open(path, OpenMode::Read)?.read_to_end()
+}
+
+fn write(path: &str, data: &[u8]) -> Result<usize, VfsError> {
+    vfs::open(path, OpenMode::Write)?.write(data)
+}
+
+#[test_case]
+fn tables_are_listed_by_name() {
+    serial_print!("tables_are_listed_by_name... ");
+    create_tables();
+    let entries = vfs::readdir("/tables").expect("readdir failed");
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert!(names.contains(&"bees"));
+    assert!(names.contains(&"000000000000feed"));
+    assert!(entries.iter().all(|entry| entry.file_type == FileType::File));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn tables_read_as_csv() {
+    serial_print!("tables_read_as_csv... ");
+    assert_eq!(read("/tables/bees"), Ok(b"7,\"drone\"\ntrue,\n".to_vec()));
+    assert_eq!(vfs::stat("/tables/bees").map(|metadata| metadata.size), Ok(16));
+    assert_eq!(read("/tables/000000000000feed"), Ok(b"\n".to_vec()));
+    assert_eq!(read("/tables/wasps").err(), Some(VfsError::NotFound));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn writes_set_cells() {
+    serial_print!("writes_set_cells... ");
+    assert_eq!(write("/tables/bees", b"2,2,\"queen\"\n1,1,8\n"), Ok(18));
+    let bees = Hasher::hash_str("bees");
+    assert!(HiveCore.lock().store.get_table(bees).expect("no table").data[1][1] == Value::from_str("queen"));
+    assert_eq!(read("/tables/bees"), Ok(b"8,\"drone\"\ntrue,\"queen\"\n".to_vec()));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn invalid_writes_change_nothing() {
+    serial_print!("invalid_writes_change_nothing... ");
+    let before = read("/tables/bees").expect("read failed");
+    assert_eq!(write("/tables/bees", b"1,1,9\n3,1,9\n"), Err(VfsError::InvalidArgument));
+    assert_eq!(write("/tables/bees", b"1,1,nine\n"), Err(VfsError::InvalidArgument));
+    assert_eq!(write("/tables/bees", b"1,1\n"), Err(VfsError::InvalidArgument));
+    assert_eq!(read("/tables/bees"), Ok(before));
+    assert_eq!(vfs::create("/tables/wasps", FileType::File), Err(VfsError::Unsupported));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn system_tables_keep_their_schema() {
+    serial_print!("system_tables_keep_their_schema... ");
+    assert_eq!(read("/tables/power"), Ok(b"\n".to_vec()));
+    assert_eq!(write("/tables/power", b"1,1,5\n"), Err(VfsError::InvalidArgument));
+    assert_eq!(write("/tables/timer", b"1,1,\"soon\"\n"), Err(VfsError::InvalidArgument));
+    assert_eq!(systables::POWER.get(1, "request"), Some(Value::Empty));
+    assert_eq!(write("/tables/power", b"1,1,\"nap\"\n"), Ok(10));
+    assert_eq!(systables::POWER.get(1, "request"), Some(Value::from_str("nap")));
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
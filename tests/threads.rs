@@ -0,0 +1,114 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+extern crate spin;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use hivemind::task::ticks;
+use hivemind::thread;
+use hivemind::{serial_print, serial_println};
+use spin::Mutex;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+#[test_case]
+fn yielding_threads_interleave() {
+    serial_print!("yielding_threads_interleave... ");
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let handles: Vec<_> = (0..2)
+        .map(|n| {
+            let log = log.clone();
+            thread::spawn(move || {
+                for _ in 0..3 {
+                    log.lock().push(n);
+                    thread::yield_now();
+                }
+            })
+            .expect("spawn failed")
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    let log = log.lock();
+    assert_eq!(log.iter().filter(|&&n| n == 0).count(), 3);
+    assert_eq!(log.iter().filter(|&&n| n == 1).count(), 3);
+    // the second thread ran before the first one finished
+    assert!(log[..3].contains(&1));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn busy_thread_is_preempted() {
+    serial_print!("busy_thread_is_preempted... ");
+    let flag = Arc::new(AtomicBool::new(false));
+    let waiter = {
+        let flag = flag.clone();
+        // never yields, so only preemption lets the setter run
+        thread::spawn(move || while !flag.load(Ordering::SeqCst) {}).expect("spawn failed")
+    };
+    let setter = {
+        let flag = flag.clone();
+        thread::spawn(move || flag.store(true, Ordering::SeqCst)).expect("spawn failed")
+    };
+    waiter.join();
+    setter.join();
+    assert!(flag.load(Ordering::SeqCst));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn sleeping_thread_wakes_on_time() {
+    serial_print!("sleeping_thread_wakes_on_time... ");
+    let woke_at = Arc::new(Mutex::new(None));
+    let start = ticks::current();
+    let sleeper = {
+        let woke_at = woke_at.clone();
+        thread::spawn(move || {
+            thread::sleep(5);
+            *woke_at.lock() = Some(ticks::current());
+        })
+        .expect("spawn failed")
+    };
+    sleeper.join();
+    let woke_at = woke_at.lock().expect("sleeper did not run");
+    assert!(woke_at >= start + 5);
+    assert!(woke_at <= start + 7);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn boot_thread_can_sleep() {
+    serial_print!("boot_thread_can_sleep... ");
+    let start = ticks::current();
+    thread::sleep(3);
+    assert!(ticks::current() >= start + 3);
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
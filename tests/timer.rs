@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use hivemind::task::executor::Executor;
+use hivemind::task::{ticks, Task};
+use hivemind::{serial_print, serial_println, timer};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+/// Returns an executor that runs the timer task.
+fn timer_executor() -> Executor {
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(timer::process_timers()));
+    executor
+}
+
+#[test_case]
+fn sleep_waits_for_ticks() {
+    serial_print!("sleep_waits_for_ticks... ");
+    let start = ticks::current();
+    timer_executor().block_on(timer::sleep(3));
+    assert!(ticks::current() >= start + 3);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn one_shot_callback_fires_once() {
+    serial_print!("one_shot_callback_fires_once... ");
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let mut executor = timer_executor();
+    let id = timer::after(2, move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    executor.block_on(timer::sleep(5));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+    assert!(!timer::cancel(id));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn periodic_callback_stops_when_cancelled() {
+    serial_print!("periodic_callback_stops_when_cancelled... ");
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let mut executor = timer_executor();
+    let id = timer::every(1, move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    executor.block_on(timer::sleep(5));
+    assert!(timer::cancel(id));
+    let fired = calls.load(Ordering::SeqCst);
+    assert!(fired >= 2);
+    executor.block_on(timer::sleep(3));
+    assert_eq!(calls.load(Ordering::SeqCst), fired);
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn cancelled_callback_never_fires() {
+    serial_print!("cancelled_callback_never_fires... ");
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    let mut executor = timer_executor();
+    let id = timer::after(2, move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    assert!(timer::cancel(id));
+    executor.block_on(timer::sleep(4));
+    assert_eq!(calls.load(Ordering::SeqCst), 0);
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
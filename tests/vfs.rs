@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::devfs::{self, DevFs};
+use hivemind::initrd::InitrdFs;
+use hivemind::vfs::{self, FileType, OpenMode, SeekFrom, VfsError};
+use hivemind::{apic, ata, pci, systables, thread};
+use hivemind::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
+    apic::init().expect("APIC initialization failed");
+    pci::init();
+    ata::init();
+    thread::init();
+    devfs::init();
+    vfs::mount("/", Arc::new(InitrdFs::new().expect("initrd unusable"))).expect("mounting / failed");
+    vfs::mount("/dev", Arc::new(DevFs)).expect("mounting /dev failed");
+
+    test_main();
+    loop {}
+}
+
+const MOTD: &[u8] = include_bytes!("../initrd/etc/motd");
+
+#[test_case]
+fn files_are_read_and_sought() {
+    serial_print!("files_are_read_and_sought... ");
+    let mut file = vfs::open("/etc/motd", OpenMode::Read).expect("open failed");
+    assert_eq!(file.stat().size, MOTD.len() as u64);
+    assert_eq!(file.read_to_end(), Ok(MOTD.to_vec()));
+    assert_eq!(file.seek(SeekFrom::End(-4)), Ok(MOTD.len() as u64 - 4));
+    let mut tail = [0; 8];
+    assert_eq!(file.read(&mut tail), Ok(4));
+    assert_eq!(&tail[..4], &MOTD[MOTD.len() - 4..]);
+    assert_eq!(file.seek(SeekFrom::Current(-100)), Err(VfsError::InvalidArgument));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn initrd_is_read_only() {
+    serial_print!("initrd_is_read_only... ");
+    let mut file = vfs::open("/etc/motd", OpenMode::ReadWrite).expect("open failed");
+    assert_eq!(file.write(b"x"), Err(VfsError::ReadOnly));
+    let mut file = vfs::open("/etc/motd", OpenMode::Read).expect("open failed");
+    assert_eq!(file.write(b"x"), Err(VfsError::PermissionDenied));
+    assert_eq!(vfs::create("/etc/new", FileType::File), Err(VfsError::ReadOnly));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn paths_are_resolved() {
+    serial_print!("paths_are_resolved... ");
+    assert_eq!(vfs::stat("/etc").map(|metadata| metadata.file_type), Ok(FileType::Directory));
+    assert_eq!(vfs::stat("/dev/../etc/./motd").map(|metadata| metadata.size), Ok(MOTD.len() as u64));
+    assert_eq!(vfs::stat("/etc/missing").err(), Some(VfsError::NotFound));
+    assert_eq!(vfs::stat("/etc/motd/child").err(), Some(VfsError::NotADirectory));
+    assert_eq!(vfs::stat("etc").err(), Some(VfsError::InvalidPath));
+    assert_eq!(vfs::open("/etc", OpenMode::Read).err(), Some(VfsError::IsADirectory));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn mount_points_are_listed() {
+    serial_print!("mount_points_are_listed... ");
+    let root = vfs::readdir("/").expect("readdir failed");
+    let names: Vec<&str> = root.iter().map(|entry| entry.name.as_str()).collect();
+    assert!(names.contains(&"etc"));
+    assert!(names.contains(&"dev"));
+    assert_eq!(vfs::mount("/dev", Arc::new(DevFs)), Err(VfsError::AlreadyMounted));
+    let mounts = vfs::mounts();
+    assert!(mounts.contains(&(String::from("/dev"), String::from("devfs"))));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn devices_appear_as_files() {
+    serial_print!("devices_appear_as_files... ");
+    let dev = vfs::readdir("/dev").expect("readdir failed");
+    for name in ["null", "zero", "serial0", "console", "ata0.1"].iter() {
+        assert!(dev.iter().any(|entry| entry.name == *name), "/dev/{} missing", name);
+    }
+    let mut zero = vfs::open("/dev/zero", OpenMode::Read).expect("open failed");
+    let mut buffer = [1; 16];
+    assert_eq!(zero.read(&mut buffer), Ok(16));
+    assert_eq!(buffer, [0; 16]);
+    let mut null = vfs::open("/dev/null", OpenMode::ReadWrite).expect("open failed");
+    assert_eq!(null.write(b"discarded"), Ok(9));
+    assert_eq!(null.read(&mut buffer), Ok(0));
+    let mut serial = vfs::open("/dev/serial0", OpenMode::Write).expect("open failed");
+    assert_eq!(serial.write(b"(serial0) "), Ok(10));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn disks_are_read_at_any_offset() {
+    serial_print!("disks_are_read_at_any_offset... ");
+    // every sector of the ATA test disk starts with its number, the rest repeats its low byte
+    let mut disk = vfs::open("/dev/ata0.1", OpenMode::Read).expect("open failed");
+    assert_eq!(disk.stat().file_type, FileType::BlockDevice);
+    assert_eq!(disk.seek(SeekFrom::Start(3 * 512 - 4)), Ok(3 * 512 - 4));
+    let mut buffer = [0; 12];
+    assert_eq!(disk.read(&mut buffer), Ok(12));
+    assert_eq!(buffer, [2, 2, 2, 2, 3, 0, 0, 0, 0, 0, 0, 0]);
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
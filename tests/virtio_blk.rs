@@ -0,0 +1,105 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[macro_use]
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::block::{self, BlockDevice};
+use hivemind::{apic, pci, systables, thread, virtio_blk};
+use hivemind::{serial_print, serial_println};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+    systables::init();
+    apic::init().expect("APIC initialization failed");
+    pci::init();
+    virtio_blk::init();
+    thread::init();
+
+    test_main();
+    loop {}
+}
+
+/// The size of the images `build.rs` creates.
+const TEST_DISK_SECTORS: u64 = 2048;
+
+/// Checks the content `build.rs` wrote to a sector.
+fn assert_pattern(sector: u64, data: &[u8]) {
+    let mut number = [0; 8];
+    number.copy_from_slice(&data[..8]);
+    assert_eq!(u64::from_le_bytes(number), sector);
+    assert!(data[8..].iter().all(|&byte| byte == sector as u8));
+}
+
+#[test_case]
+fn both_transports_are_found() {
+    serial_print!("both_transports_are_found... ");
+    let disks = virtio_blk::disks();
+    assert_eq!(disks.len(), 2);
+    assert!(disks.iter().any(|disk| disk.is_modern()));
+    assert!(disks.iter().any(|disk| !disk.is_modern()));
+    for disk in disks.iter() {
+        assert_eq!(disk.sector_count(), TEST_DISK_SECTORS);
+        assert!(block::find(disk.name()).is_some());
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn read_sectors() {
+    serial_print!("read_sectors... ");
+    for disk in virtio_blk::disks() {
+        let mut data = [0; virtio_blk::SECTOR_SIZE];
+        disk.read(42, &mut data).expect("read failed");
+        assert_pattern(42, &data);
+
+        // more than fits in the DMA buffer at once
+        let mut data = vec![0; 200 * virtio_blk::SECTOR_SIZE];
+        disk.read(100, &mut data).expect("read failed");
+        for (i, sector) in data.chunks(virtio_blk::SECTOR_SIZE).enumerate() {
+            assert_pattern(100 + i as u64, sector);
+        }
+    }
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn write_and_read_back() {
+    serial_print!("write_and_read_back... ");
+    for disk in virtio_blk::disks() {
+        let mut original = vec![0; 4 * virtio_blk::SECTOR_SIZE];
+        disk.read(1500, &mut original).expect("read failed");
+
+        let written: Vec<u8> = (0..original.len()).map(|i| (i * 13) as u8).collect();
+        disk.write(1500, &written).expect("write failed");
+        disk.flush().expect("flush failed");
+        let mut read = vec![0; written.len()];
+        disk.read(1500, &mut read).expect("read failed");
+        assert_eq!(read, written);
+
+        disk.write(1500, &original).expect("write failed");
+    }
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}
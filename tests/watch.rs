@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(hivemind::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+#[macro_use]
+extern crate alloc;
+extern crate mech_core;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use hivemind::persist;
+use hivemind::sync::IrqMutex;
+use hivemind::watch::{self, ChangedCell};
+use hivemind::{serial_print, serial_println};
+use mech_core::{Change, Hasher, Index, Value};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use hivemind::allocator;
+    use hivemind::memory::{self, BootInfoFrameAllocator};
+
+    hivemind::init();
+    let mut mapper = unsafe { memory::init(boot_info.physical_memory_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    memory::remap_physical_memory(&boot_info.memory_map, &mut mapper, &mut frame_allocator)
+        .expect("remapping physical memory failed");
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    memory::install(mapper, frame_allocator);
+
+    test_main();
+    loop {}
+}
+
+fn set(table: u64, row: usize, column: usize, value: Value) -> Change {
+    Change::Set{table, row: Index::Index(row as _), column: Index::Index(column as _), value}
+}
+
+/// Records the cells of every call of a watcher.
+fn recorder() -> (Arc<IrqMutex<Vec<Vec<ChangedCell>>>>, impl Fn(&[ChangedCell]) + Send + Sync) {
+    let calls = Arc::new(IrqMutex::new("CALLS", Vec::new()));
+    let recorded = calls.clone();
+    (calls, move |cells: &[ChangedCell]| recorded.lock().push(cells.to_vec()))
+}
+
+#[test_case]
+fn watchers_get_the_changed_cells() {
+    serial_print!("watchers_get_the_changed_cells... ");
+    let (bees, wasps) = (Hasher::hash_str("watched-bees"), Hasher::hash_str("watched-wasps"));
+    let (calls, watcher) = recorder();
+    let id = watch::watch(bees, watcher);
+    persist::transact(vec![
+        Change::NewTable{ id: bees, rows: 2, columns: 2 },
+        Change::NewTable{ id: wasps, rows: 1, columns: 1 },
+        set(bees, 1, 2, Value::from_str("drone")),
+        set(wasps, 1, 1, Value::from_u64(3)),
+        set(bees, 2, 1, Value::from_u64(7)),
+    ]).expect("transaction failed");
+    // watchers run in the watcher task, not within the transaction
+    assert!(calls.lock().is_empty());
+    assert_eq!(watch::dispatch(), 1);
+    assert_eq!(*calls.lock(), vec![vec![
+        ChangedCell { row: 1, column: 2, value: Value::from_str("drone") },
+        ChangedCell { row: 2, column: 1, value: Value::from_u64(7) },
+    ]]);
+
+    persist::transact(vec![set(wasps, 1, 1, Value::from_u64(4))]).expect("transaction failed");
+    persist::transact(vec![set(bees, 1, 1, Value::Bool(true))]).expect("transaction failed");
+    persist::transact(vec![set(bees, 1, 1, Value::Bool(false))]).expect("transaction failed");
+    // one call per transaction
+    assert_eq!(watch::dispatch(), 2);
+    assert_eq!(calls.lock().len(), 3);
+    assert!(watch::unwatch(id));
+    serial_println!("[ok]");
+}
+
+#[test_case]
+fn watchers_may_transact() {
+    serial_print!("watchers_may_transact... ");
+    let (source, copy) = (Hasher::hash_str("watched-source"), Hasher::hash_str("watched-copy"));
+    persist::transact(vec![
+        Change::NewTable{ id: source, rows: 1, columns: 1 },
+        Change::NewTable{ id: copy, rows: 1, columns: 1 },
+    ]).expect("transaction failed");
+    let forward = watch::watch(source, move |cells| {
+        let changes = cells.iter().map(|cell| set(copy, 1, 1, cell.value.clone())).collect();
+        persist::transact(changes).expect("transaction failed");
+    });
+    let (calls, watcher) = recorder();
+    let recording = watch::watch(copy, watcher);
+    persist::transact(vec![set(source, 1, 1, Value::from_u64(42))]).expect("transaction failed");
+    // the copy's notification is queued by the first watcher and delivered by the same call
+    assert_eq!(watch::dispatch(), 2);
+    assert_eq!(*calls.lock(), vec![vec![ChangedCell { row: 1, column: 1, value: Value::from_u64(42) }]]);
+    assert!(watch::unwatch(forward));
+    assert!(watch::unwatch(recording));
+    assert!(!watch::unwatch(recording));
+    serial_println!("[ok]");
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hivemind::test_panic_handler(info)
+}